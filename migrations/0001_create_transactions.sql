@@ -0,0 +1,10 @@
+-- Initial schema: one row per payment attempt
+CREATE TABLE IF NOT EXISTS transactions (
+    id SERIAL PRIMARY KEY,
+    transaction_uuid UUID NOT NULL UNIQUE,
+    amount INTEGER NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    status VARCHAR(20) NOT NULL,
+    masked_card_number VARCHAR(32) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,6 @@
+-- Replace free-form status strings with a Postgres enum
+CREATE TYPE transaction_status AS ENUM ('pending', 'success', 'failed', 'refunded');
+
+ALTER TABLE transactions
+    ALTER COLUMN status TYPE transaction_status
+    USING LOWER(status)::transaction_status;
@@ -0,0 +1,3 @@
+-- Track how much of a charge has been refunded
+ALTER TABLE transactions
+    ADD COLUMN refunded_amount INTEGER NOT NULL DEFAULT 0;
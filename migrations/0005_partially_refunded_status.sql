@@ -0,0 +1,2 @@
+-- Partial refunds leave a transaction in an intermediate state
+ALTER TYPE transaction_status ADD VALUE IF NOT EXISTS 'partially_refunded';
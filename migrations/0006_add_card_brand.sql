@@ -0,0 +1,5 @@
+-- Card network detected from the BIN prefix
+CREATE TYPE card_brand AS ENUM ('visa', 'mastercard', 'amex', 'discover', 'unknown');
+
+ALTER TABLE transactions
+    ADD COLUMN card_brand card_brand NOT NULL DEFAULT 'unknown';
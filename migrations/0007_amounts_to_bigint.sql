@@ -0,0 +1,4 @@
+-- Widen amounts so large charges and future summations can't overflow
+ALTER TABLE transactions
+    ALTER COLUMN amount TYPE BIGINT,
+    ALTER COLUMN refunded_amount TYPE BIGINT;
@@ -0,0 +1,3 @@
+-- Which gateway produced the charge outcome; NULL until one answers
+ALTER TABLE transactions
+    ADD COLUMN gateway TEXT;
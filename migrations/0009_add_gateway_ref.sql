@@ -0,0 +1,3 @@
+-- Authorization/reference ID issued by the gateway; NULL when it returned none
+ALTER TABLE transactions
+    ADD COLUMN gateway_ref TEXT;
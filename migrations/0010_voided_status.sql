@@ -0,0 +1,2 @@
+-- Authorizations cancelled before settlement
+ALTER TYPE transaction_status ADD VALUE IF NOT EXISTS 'voided';
@@ -0,0 +1,6 @@
+-- Two-step payments: authorize now, capture (possibly less) at fulfilment
+ALTER TYPE transaction_status ADD VALUE IF NOT EXISTS 'authorized';
+
+-- Amount originally authorized; NULL for charges captured immediately
+ALTER TABLE transactions
+    ADD COLUMN authorized_amount BIGINT;
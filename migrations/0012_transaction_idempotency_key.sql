@@ -0,0 +1,10 @@
+-- DB-level idempotency: at most one transaction per Idempotency-Key, even when concurrent
+-- retries race past the idempotency_keys lookup. Existing rows keep NULL (no key), which the
+-- partial index ignores, so this applies without a backfill.
+ALTER TABLE transactions
+    ADD COLUMN idempotency_key VARCHAR(255),
+    ADD COLUMN request_hash CHAR(64);
+
+CREATE UNIQUE INDEX IF NOT EXISTS transactions_idempotency_key_idx
+    ON transactions (idempotency_key)
+    WHERE idempotency_key IS NOT NULL;
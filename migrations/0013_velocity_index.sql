@@ -0,0 +1,3 @@
+-- Velocity checks count recent charges per card
+CREATE INDEX IF NOT EXISTS transactions_card_created_at_idx
+    ON transactions (masked_card_number, card_brand, created_at);
@@ -0,0 +1,11 @@
+-- Keyed SHA-256 of the full PAN (HMAC with CARD_FINGERPRINT_SALT) to correlate the same card
+-- across transactions. It cannot be reversed to the PAN without brute-forcing with the salt,
+-- so the salt must stay secret. NULL for token charges and rows created before this migration.
+ALTER TABLE transactions
+    ADD COLUMN card_fingerprint CHAR(64);
+
+-- Velocity checks now count per fingerprint rather than per last four digits
+DROP INDEX IF EXISTS transactions_card_created_at_idx;
+CREATE INDEX IF NOT EXISTS transactions_card_fingerprint_created_at_idx
+    ON transactions (card_fingerprint, created_at)
+    WHERE card_fingerprint IS NOT NULL;
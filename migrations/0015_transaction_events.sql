@@ -0,0 +1,28 @@
+-- Append-only audit trail: one row per state change, written in the same SQL transaction
+-- as the change itself. Doubles as the outbox that reliable webhook delivery can read from.
+CREATE TYPE transaction_event_type AS ENUM (
+    'created', 'authorized', 'succeeded', 'failed', 'captured', 'refunded', 'partially_refunded', 'voided'
+);
+
+CREATE TABLE IF NOT EXISTS transaction_events (
+    id BIGSERIAL PRIMARY KEY,
+    transaction_id INTEGER NOT NULL REFERENCES transactions (id),
+    event_type transaction_event_type NOT NULL,
+    status transaction_status NOT NULL, -- Transaction status after the event
+    amount BIGINT NOT NULL, -- Minor units the event concerns, e.g. the refunded amount
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS transaction_events_transaction_id_idx
+    ON transaction_events (transaction_id, id);
+
+-- Rows are never rewritten or removed
+CREATE OR REPLACE FUNCTION reject_transaction_event_change() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'transaction_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER transaction_events_append_only
+    BEFORE UPDATE OR DELETE ON transaction_events
+    FOR EACH ROW EXECUTE FUNCTION reject_transaction_event_change();
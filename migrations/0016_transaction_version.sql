@@ -0,0 +1,3 @@
+-- Optimistic concurrency: bumped on every update, checked by refund/void/capture writes
+ALTER TABLE transactions
+    ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- Cross-currency charges: amount/currency hold what the gateway charged (the settlement
+-- side); these keep what the customer was quoted and the rate used. NULL when not converted.
+ALTER TABLE transactions
+    ADD COLUMN presentment_amount BIGINT,
+    ADD COLUMN presentment_currency VARCHAR(3),
+    ADD COLUMN fx_rate NUMERIC;
@@ -0,0 +1,5 @@
+-- Rounding applied to the converted amount, kept for audit and gateway reconciliation
+CREATE TYPE rounding_mode AS ENUM ('half_up', 'half_even', 'floor');
+
+ALTER TABLE transactions
+    ADD COLUMN fx_rounding rounding_mode;
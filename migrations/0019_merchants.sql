@@ -0,0 +1,33 @@
+-- Multi-tenancy: every transaction belongs to the merchant whose API key created it.
+-- Keys stay in MERCHANT_API_KEYS (name=key); names are registered here at startup.
+CREATE TABLE IF NOT EXISTS merchants (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Bare keys (no name=) authenticate as this merchant, which also owns all existing rows
+INSERT INTO merchants (name) VALUES ('default') ON CONFLICT (name) DO NOTHING;
+
+ALTER TABLE transactions
+    ADD COLUMN merchant_id INTEGER REFERENCES merchants (id);
+UPDATE transactions SET merchant_id = (SELECT id FROM merchants WHERE name = 'default');
+ALTER TABLE transactions
+    ALTER COLUMN merchant_id SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS transactions_merchant_created_at_idx
+    ON transactions (merchant_id, created_at);
+
+-- Idempotency keys are chosen by merchants, so they are only unique per merchant
+DROP INDEX IF EXISTS transactions_idempotency_key_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS transactions_merchant_idempotency_key_idx
+    ON transactions (merchant_id, idempotency_key)
+    WHERE idempotency_key IS NOT NULL;
+
+ALTER TABLE idempotency_keys
+    ADD COLUMN merchant_id INTEGER REFERENCES merchants (id);
+UPDATE idempotency_keys SET merchant_id = (SELECT id FROM merchants WHERE name = 'default');
+ALTER TABLE idempotency_keys
+    ALTER COLUMN merchant_id SET NOT NULL,
+    DROP CONSTRAINT idempotency_keys_pkey,
+    ADD PRIMARY KEY (merchant_id, idempotency_key);
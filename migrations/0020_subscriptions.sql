@@ -0,0 +1,27 @@
+-- Recurring charges against a stored gateway token. A background task bills due rows;
+-- each cycle is an ordinary transaction linked back here.
+CREATE TYPE billing_interval AS ENUM ('day', 'week', 'month', 'year');
+CREATE TYPE subscription_status AS ENUM ('active', 'past_due');
+
+CREATE TABLE IF NOT EXISTS subscriptions (
+    id SERIAL PRIMARY KEY,
+    subscription_uuid UUID NOT NULL UNIQUE,
+    merchant_id INTEGER NOT NULL REFERENCES merchants (id),
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    currency VARCHAR(3) NOT NULL,
+    payment_token VARCHAR(255) NOT NULL, -- Gateway-issued; PANs are never stored
+    billing_interval billing_interval NOT NULL,
+    status subscription_status NOT NULL DEFAULT 'active',
+    next_billing_at TIMESTAMP NOT NULL, -- Start of the cycle being billed
+    next_attempt_at TIMESTAMP NOT NULL, -- Later than next_billing_at while dunning
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- The billing scan only ever looks at active rows that are due
+CREATE INDEX IF NOT EXISTS subscriptions_due_idx
+    ON subscriptions (next_attempt_at)
+    WHERE status = 'active';
+
+ALTER TABLE transactions
+    ADD COLUMN subscription_id INTEGER REFERENCES subscriptions (id);
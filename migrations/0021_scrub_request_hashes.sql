@@ -0,0 +1,5 @@
+-- Request hashes used to cover the raw card number and CVV. They are now computed from the
+-- salted card fingerprint instead, so drop the old ones: stored responses expire within a day
+-- anyway, and a retry against a scrubbed key is refused rather than charged twice.
+DELETE FROM idempotency_keys;
+UPDATE transactions SET request_hash = NULL WHERE request_hash IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Card expiry, kept only when STORE_CARD_EXPIRY is enabled: stored alongside the masked PAN it
+-- is cardholder data in PCI DSS scope. NULL for token charges and when storage is off.
+ALTER TABLE transactions
+    ADD COLUMN card_expiry_month INTEGER CHECK (card_expiry_month BETWEEN 1 AND 12),
+    ADD COLUMN card_expiry_year INTEGER;
@@ -0,0 +1,4 @@
+-- Merchant-supplied key/value data (order IDs, customer refs, ...), returned as given.
+-- The API only accepts objects; the CHECK keeps other JSON out of the column too.
+ALTER TABLE transactions
+    ADD COLUMN metadata JSONB CHECK (metadata IS NULL OR jsonb_typeof(metadata) = 'object');
@@ -0,0 +1,7 @@
+-- Idempotency keys are reused beyond POST /payment: a refund key is only unique within its
+-- transaction, so the same key may guard one refund on each of several transactions.
+-- 'payment' for charges, 'refund:<transaction UUID>' for refunds.
+ALTER TABLE idempotency_keys
+    ADD COLUMN scope VARCHAR(64) NOT NULL DEFAULT 'payment',
+    DROP CONSTRAINT idempotency_keys_pkey,
+    ADD PRIMARY KEY (merchant_id, scope, idempotency_key);
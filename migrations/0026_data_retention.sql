@@ -0,0 +1,15 @@
+-- Retention: transactions past RETENTION_DAYS are either anonymized in place or deleted
+-- together with their audit trail. Anonymized rows are marked so the job skips them after.
+ALTER TABLE transactions
+    ADD COLUMN anonymized_at TIMESTAMPTZ;
+
+-- The audit trail stays append-only for everything but the retention purge, which opts in
+-- for its own SQL transaction with SET LOCAL payments.retention_purge = 'on'
+CREATE OR REPLACE FUNCTION reject_transaction_event_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND current_setting('payments.retention_purge', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'transaction_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,8 @@
+-- Why the gateway declined a charge, for clients to act on without parsing the message.
+-- NULL for anything but a gateway decline.
+CREATE TYPE decline_reason AS ENUM (
+    'insufficient_funds', 'card_expired', 'do_not_honor', 'suspected_fraud', 'processing_error'
+);
+
+ALTER TABLE transactions
+    ADD COLUMN decline_reason decline_reason;
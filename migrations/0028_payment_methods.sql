@@ -0,0 +1,12 @@
+-- Cards vaulted at the gateway via POST /tokens. Only the gateway's token and what is safe to
+-- show (masked number, brand) are kept; the PAN never reaches this table.
+CREATE TABLE payment_methods (
+    id SERIAL PRIMARY KEY,
+    token_id UUID NOT NULL UNIQUE,
+    merchant_id INTEGER NOT NULL REFERENCES merchants(id),
+    payment_token VARCHAR(255) NOT NULL,
+    masked_card_number VARCHAR(32) NOT NULL,
+    card_brand card_brand NOT NULL,
+    gateway VARCHAR(32) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,731 @@
+// --- CONFIGURATION AND STATE MANAGEMENT ---
+
+use axum::http::{HeaderName, HeaderValue};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use uuid::Uuid;
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::AtomicBool,
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use sqlx::PgPool;
+use anyhow::Result;
+use reqwest::Client;
+use metrics_exporter_prometheus::PrometheusHandle;
+use ipnet::IpNet;
+
+use crate::{errors::*, gateway::*, handlers::*, middleware::*, models::*, validation::*};
+
+pub(crate) const DEFAULT_BIND_ADDR: &str = "127.0.0.1:3000";
+pub(crate) const DEFAULT_MERCHANT: &str = "default";
+pub(crate) const DEFAULT_DB_MAX_CONNECTIONS: u32 = 5;
+pub(crate) const DEFAULT_DB_MIN_CONNECTIONS: u32 = 0;
+pub(crate) const DEFAULT_DB_ACQUIRE_TIMEOUT_SECS: u64 = 30;
+// Both under the few-minute idle cutoffs common on NAT gateways and load balancers, so the pool
+// retires a connection before a middlebox silently drops it
+pub(crate) const DEFAULT_DB_IDLE_TIMEOUT_SECS: u64 = 5 * 60;
+pub(crate) const DEFAULT_DB_MAX_LIFETIME_SECS: u64 = 30 * 60;
+pub(crate) const DEFAULT_DB_CONNECT_ATTEMPTS: u32 = 10;
+pub(crate) const DEFAULT_DB_CONNECT_RETRY_DELAY_MS: u64 = 500;
+pub(crate) const MAX_DB_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(10);
+pub(crate) const DEFAULT_GATEWAY_TIMEOUT_SECS: u64 = 10;
+pub(crate) const DEFAULT_GATEWAY_CHARGE_TIMEOUT_MS: u64 = 8_000;
+pub(crate) const DEFAULT_MAX_PAYMENT_AMOUNT: i64 = 100_000_000_000;
+pub(crate) const DEFAULT_RATE_LIMIT_PER_MINUTE: u32 = 60;
+pub(crate) const DEFAULT_GATEWAY_RETRY_ATTEMPTS: u32 = 3;
+pub(crate) const DEFAULT_GATEWAY_RETRY_BASE_DELAY_MS: u64 = 100;
+pub(crate) const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+pub(crate) const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECS: u64 = 30;
+pub(crate) const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 16 * 1024;
+pub(crate) const DEFAULT_WEBHOOK_RETRY_ATTEMPTS: u32 = 5;
+pub(crate) const DEFAULT_REPLAY_WINDOW_SECS: i64 = 300;
+pub(crate) const DEFAULT_VELOCITY_MAX_CHARGES: i64 = 10;
+pub(crate) const DEFAULT_VELOCITY_WINDOW_MINUTES: i64 = 60;
+pub(crate) const DEFAULT_DECLINE_RATE_WINDOW_MINUTES: u64 = 15;
+pub(crate) const DECLINE_RATE_BUCKETS: u32 = 60;
+pub(crate) const DEFAULT_FX_RATES_TTL_SECS: u64 = 300;
+pub(crate) const DEFAULT_SUBSCRIPTION_POLL_SECS: u64 = 60;
+pub(crate) const DEFAULT_SUBSCRIPTION_MAX_RETRIES: u32 = 3;
+pub(crate) const DEFAULT_SUBSCRIPTION_RETRY_DELAY_MINUTES: i64 = 24 * 60;
+pub(crate) const DEFAULT_PAYMENT_QUEUE_CAPACITY: usize = 1_000;
+pub(crate) const DEFAULT_PAYMENT_WORKERS: usize = 8;
+pub(crate) const DEFAULT_MAX_CONCURRENT_PAYMENTS: usize = 100;
+pub(crate) const DEFAULT_RECONCILIATION_POLL_SECS: u64 = 300;
+pub(crate) const DEFAULT_RECONCILIATION_PENDING_AFTER_SECS: i64 = 30 * 60;
+pub(crate) const RECONCILIATION_BATCH_SIZE: i64 = 100;
+pub(crate) const DEFAULT_RETENTION_POLL_SECS: u64 = 60 * 60;
+pub(crate) const DEFAULT_GATEWAY_SIGNATURE_HEADER: &str = "X-Gateway-Signature";
+pub(crate) const DEFAULT_TLS_RELOAD_POLL_SECS: u64 = 5 * 60;
+pub(crate) const RETENTION_BATCH_SIZE: i64 = 1_000;
+pub(crate) const STATUS_UPDATES_CAPACITY: usize = 1_024;
+pub(crate) const STATUS_STREAM_RECHECK: Duration = Duration::from_secs(5);
+pub(crate) const FX_RATES_TIMEOUT: Duration = Duration::from_secs(2);
+pub(crate) const WEBHOOK_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+pub(crate) const GATEWAY_HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+pub(crate) const GATEWAY_HEALTH_CACHE_TTL: Duration = Duration::from_secs(5);
+
+// Everything read from the environment, validated once at startup.
+// Deliberately not Debug: it holds secrets.
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub(crate) database_url: String,
+    pub(crate) api_key: String,
+    pub(crate) api_key_file: Option<PathBuf>, // Secret file the key is read from, and re-read on rotation
+    pub(crate) bind_addr: SocketAddr,
+    pub(crate) tls: Option<TlsPaths>, // Serve HTTPS directly; plain HTTP (behind a terminating proxy) when unset
+    pub(crate) tls_reload_poll_secs: u64, // How often the certificate files are checked for a renewal
+    pub(crate) metrics_bind_addr: Option<SocketAddr>,
+    pub(crate) max_connections: u32,
+    pub(crate) min_connections: u32,
+    pub(crate) acquire_timeout_secs: u64,
+    pub(crate) idle_timeout_secs: u64, // Idle connections are closed after this long
+    pub(crate) max_lifetime_secs: u64, // Connections are recycled after this long, busy or not
+    pub(crate) db_connect_attempts: u32,
+    pub(crate) db_connect_retry_delay_ms: u64,
+    pub(crate) gateway_timeout_secs: u64, // HTTP client timeout: the outer bound for every outbound call
+    pub(crate) gateway_charge_timeout_ms: u64, // Per-charge deadline, inside the client timeout
+    pub(crate) max_payment_amount: i64, // Upper bound for a single charge, in minor units
+    pub(crate) amount_limits: Vec<(&'static str, AmountRange)>, // Per-currency overrides of 1..=max_payment_amount
+    pub(crate) rate_limit_per_minute: u32,
+    pub(crate) gateway_retry_attempts: u32,
+    pub(crate) gateway_retry_base_delay_ms: u64,
+    pub(crate) circuit_breaker_threshold: u32,
+    pub(crate) circuit_breaker_cooldown_secs: u64,
+    pub(crate) merchant_api_keys: Vec<(String, String, Roles)>, // (merchant name, API key, roles)
+    pub(crate) default_gateways: Vec<GatewayMode>, // Failover chain for currencies without a route
+    pub(crate) gateway_routes: Vec<(&'static str, Vec<GatewayMode>)>, // Per-currency failover chains
+    pub(crate) test_cards: Vec<(String, SimulatedOutcome)>, // PAN prefix -> decline, for the simulated gateways
+    pub(crate) cors_allowed_origins: Vec<HeaderValue>, // Empty means no cross-origin access
+    pub(crate) max_request_body_bytes: usize,
+    pub(crate) http_logging: bool, // Redacted request/response logging at debug level, for troubleshooting
+    pub(crate) webhook: Option<(String, String)>, // (URL, signing secret); notifications are off when unset
+    pub(crate) webhook_retry_attempts: u32,
+    pub(crate) request_signing_secret: Option<String>, // When set, API requests must carry a valid X-Signature
+    pub(crate) replay_window_secs: i64, // Allowed clock skew for X-Timestamp on signed requests
+    pub(crate) gateway_url: Option<String>, // External gateway base URL; charges are simulated when unset
+    pub(crate) gateway_health_url: Option<String>, // Polled by GET /health?deep=true
+    pub(crate) gateway_signing: Option<GatewaySigning>, // Outbound charge signing; off unless GATEWAY_SIGNING_SECRET is set
+    pub(crate) velocity_max_charges: i64, // Successful charges allowed per card within the window
+    pub(crate) velocity_window_minutes: i64,
+    pub(crate) decline_rate_window_minutes: u64, // Span of the rolling decline rate in /metrics and deep health
+    pub(crate) card_fingerprint_salt: String, // Keys the card fingerprint HMAC; keep it secret and stable
+    pub(crate) store_card_expiry: bool, // Off by default: a stored expiry is cardholder data under PCI DSS
+    pub(crate) fx_rates_url: Option<String>, // Rate provider; cross-currency charges are refused when unset
+    pub(crate) fx_rates_ttl_secs: u64,
+    pub(crate) fx_rounding: RoundingMode, // Applied to fractional minor units after conversion
+    pub(crate) ip_allowlist: Vec<IpNet>, // Source networks allowed to call the API; empty allows any
+    pub(crate) trusted_proxies: Vec<IpNet>, // Peers whose X-Forwarded-For is believed
+    pub(crate) subscription_poll_secs: u64, // How often due subscriptions are billed
+    pub(crate) subscription_max_retries: u32, // Failed retries of a cycle before the subscription goes past due
+    pub(crate) subscription_retry_delay_minutes: i64, // First dunning retry; doubles after each failure
+    pub(crate) payment_queue_capacity: usize, // Accepted async payments waiting for a worker
+    pub(crate) payment_workers: usize, // Async payments charged concurrently
+    pub(crate) max_concurrent_payments: usize, // Synchronous charges in flight; more are shed with 503
+    pub(crate) reconciliation_poll_secs: u64,
+    pub(crate) reconciliation_pending_after_secs: i64, // Age at which a Pending transaction is looked up
+    pub(crate) retention: Option<RetentionPolicy>, // None unless RETENTION_ENABLED is set
+    pub(crate) retention_poll_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GatewayMode {
+    Real,
+    Mock,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Result<Config, AppError> {
+        // Comma-separated `merchant=key:roles` entries; a bare key belongs to the "default" merchant.
+        // A merchant may list several keys, so keys can be rotated by deploying old and new side by side.
+        // Roles are `+`-separated (charge, refund, read, admin); a key without any gets all of them.
+        let merchant_api_keys: Vec<(String, String, Roles)> = required_env_var("MERCHANT_API_KEYS")?
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (merchant, key) = match entry.split_once('=') {
+                    Some((merchant, key)) if !merchant.trim().is_empty() && !key.trim().is_empty() => {
+                        (merchant.trim(), key.trim())
+                    }
+                    Some(_) => {
+                        return Err(AppError::EnvironmentError(format!(
+                            "MERCHANT_API_KEYS entries must look like merchant=key, got {:?}.", entry
+                        )))
+                    }
+                    None => (DEFAULT_MERCHANT, entry),
+                };
+                let (key, roles) = match key.split_once(':') {
+                    Some((key, roles)) => (key.trim(), Roles::parse(roles).map_err(|e| {
+                        AppError::EnvironmentError(format!("MERCHANT_API_KEYS entry {:?}: {}.", entry, e))
+                    })?),
+                    None => (key, Roles::ALL),
+                };
+                Ok((merchant.to_string(), key.to_string(), roles))
+            })
+            .collect::<Result<_, _>>()?;
+
+        if merchant_api_keys.is_empty() {
+            return Err(AppError::EnvironmentError("MERCHANT_API_KEYS must contain at least one key.".to_string()));
+        }
+
+        let max_connections = positive_env_var("DB_MAX_CONNECTIONS", DEFAULT_DB_MAX_CONNECTIONS)?;
+        let min_connections = non_negative_env_var("DB_MIN_CONNECTIONS", DEFAULT_DB_MIN_CONNECTIONS)?;
+        if min_connections > max_connections {
+            return Err(AppError::EnvironmentError(format!(
+                "DB_MIN_CONNECTIONS ({}) cannot exceed DB_MAX_CONNECTIONS ({}).", min_connections, max_connections
+            )));
+        }
+
+        // An ordered failover chain, e.g. GATEWAY_MODE=real|mock
+        let default_gateways = match env::var("GATEWAY_MODE") {
+            Ok(value) => parse_gateway_chain("GATEWAY_MODE", &value)?,
+            Err(_) => vec![GatewayMode::Real],
+        };
+
+        // e.g. GATEWAY_ROUTES=EUR=mock,JPY=real|mock
+        let gateway_routes = match env::var("GATEWAY_ROUTES") {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|route| !route.is_empty())
+                .map(|route| {
+                    let (currency, mode) = route.split_once('=').ok_or_else(|| {
+                        AppError::EnvironmentError(format!("GATEWAY_ROUTES entries must look like CUR=mode, got {:?}.", route))
+                    })?;
+                    let currency = Currency::from_code(&currency.trim().to_uppercase()).map_err(|_| {
+                        AppError::EnvironmentError(format!("GATEWAY_ROUTES contains an unsupported currency: {:?}.", currency))
+                    })?;
+                    Ok((currency.code(), parse_gateway_chain("GATEWAY_ROUTES", mode)?))
+                })
+                .collect::<Result<Vec<_>, AppError>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        // Inclusive minor-unit bounds, e.g. AMOUNT_LIMITS=USD=50:99999999,JPY=50:10000000
+        let amount_limits = match env::var("AMOUNT_LIMITS") {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|limit| !limit.is_empty())
+                .map(|limit| {
+                    let invalid = || AppError::EnvironmentError(format!(
+                        "AMOUNT_LIMITS entries must look like CUR=min:max with 0 < min <= max, got {:?}.", limit
+                    ));
+                    let (currency, range) = limit.split_once('=').ok_or_else(invalid)?;
+                    let currency = Currency::from_code(&currency.trim().to_uppercase()).map_err(|_| {
+                        AppError::EnvironmentError(format!("AMOUNT_LIMITS contains an unsupported currency: {:?}.", currency))
+                    })?;
+                    let (min, max) = range.split_once(':').ok_or_else(invalid)?;
+                    let min = min.trim().parse::<i64>().map_err(|_| invalid())?;
+                    let max = max.trim().parse::<i64>().map_err(|_| invalid())?;
+                    if min <= 0 || min > max {
+                        return Err(invalid());
+                    }
+                    Ok((currency.code(), AmountRange { min, max }))
+                })
+                .collect::<Result<Vec<_>, AppError>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        // e.g. TEST_CARD_OUTCOMES=4000=insufficient_funds,4000000000000069=expired. Replaces the
+        // default 4000 rule, so list it again to keep it.
+        let test_cards = match env::var("TEST_CARD_OUTCOMES") {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| {
+                    let (prefix, outcome) = entry
+                        .split_once('=')
+                        .filter(|(prefix, _)| !prefix.trim().is_empty() && prefix.trim().chars().all(|c| c.is_ascii_digit()))
+                        .ok_or_else(|| AppError::EnvironmentError(format!(
+                            "TEST_CARD_OUTCOMES entries must look like PAN_PREFIX=outcome, got {:?}.", entry
+                        )))?;
+                    let outcome = SimulatedOutcome::parse(outcome.trim()).ok_or_else(|| AppError::EnvironmentError(format!(
+                        "TEST_CARD_OUTCOMES outcomes must be one of {}, got {:?}.", SimulatedOutcome::NAMES.join(", "), outcome
+                    )))?;
+                    Ok((prefix.trim().to_string(), outcome))
+                })
+                .collect::<Result<Vec<_>, AppError>>()?,
+            Err(_) => TestCards::default_rules(),
+        };
+
+        let cors_allowed_origins = match env::var("CORS_ALLOWED_ORIGINS") {
+            Ok(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(|origin| {
+                    HeaderValue::from_str(origin).map_err(|_| {
+                        AppError::EnvironmentError(format!("CORS_ALLOWED_ORIGINS contains an invalid origin: {:?}.", origin))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        // A webhook without a secret would send unverifiable events, so both or neither
+        let webhook = match (env::var("WEBHOOK_URL"), env::var("WEBHOOK_SECRET")) {
+            (Ok(url), Ok(secret)) if !url.trim().is_empty() && !secret.is_empty() => {
+                reqwest::Url::parse(url.trim()).map_err(|e| {
+                    AppError::EnvironmentError(format!("WEBHOOK_URL must be an absolute URL: {}", e))
+                })?;
+                Some((url.trim().to_string(), secret))
+            }
+            (Ok(url), _) if !url.trim().is_empty() => {
+                return Err(AppError::EnvironmentError("WEBHOOK_SECRET must be set when WEBHOOK_URL is.".to_string()));
+            }
+            _ => None,
+        };
+
+        let non_empty_path = |name: &str| env::var(name).ok().filter(|path| !path.trim().is_empty()).map(|path| PathBuf::from(path.trim()));
+        let tls = match (non_empty_path("TLS_CERT_PATH"), non_empty_path("TLS_KEY_PATH")) {
+            (Some(cert_path), Some(key_path)) => Some(TlsPaths { cert_path, key_path }),
+            (None, None) => None,
+            _ => return Err(AppError::EnvironmentError("TLS_CERT_PATH and TLS_KEY_PATH must be set together.".to_string())),
+        };
+
+        // A mounted secret takes precedence over the plain variable, since only it can be rotated
+        let api_key_file = non_empty_path("PAYMENT_GATEWAY_API_KEY_FILE");
+
+        let gateway_url = match env::var("GATEWAY_URL") {
+            Ok(url) if !url.trim().is_empty() => {
+                reqwest::Url::parse(url.trim()).map_err(|e| {
+                    AppError::EnvironmentError(format!("GATEWAY_URL must be an absolute URL: {}", e))
+                })?;
+                Some(url.trim().to_string())
+            }
+            _ => None,
+        };
+
+        let gateway_health_url = match env::var("GATEWAY_HEALTH_URL") {
+            Ok(url) if !url.trim().is_empty() => {
+                reqwest::Url::parse(url.trim()).map_err(|e| {
+                    AppError::EnvironmentError(format!("GATEWAY_HEALTH_URL must be an absolute URL: {}", e))
+                })?;
+                Some(url.trim().to_string())
+            }
+            _ => None,
+        };
+
+        let fx_rates_url = match env::var("FX_RATES_URL") {
+            Ok(url) if !url.trim().is_empty() => {
+                reqwest::Url::parse(url.trim()).map_err(|e| {
+                    AppError::EnvironmentError(format!("FX_RATES_URL must be an absolute URL: {}", e))
+                })?;
+                Some(url.trim().to_string())
+            }
+            _ => None,
+        };
+
+        let gateway_signing = match env::var("GATEWAY_SIGNING_SECRET") {
+            Ok(secret) if !secret.is_empty() => {
+                let algorithm = match env::var("GATEWAY_SIGNING_ALGORITHM") {
+                    Ok(value) => GatewaySigningAlgorithm::parse(value.trim()).ok_or_else(|| AppError::EnvironmentError(format!(
+                        "GATEWAY_SIGNING_ALGORITHM must be \"hmac-sha256\" or \"hmac-sha512\", got {:?}.", value
+                    )))?,
+                    Err(_) => GatewaySigningAlgorithm::HmacSha256,
+                };
+                let header = env::var("GATEWAY_SIGNATURE_HEADER").unwrap_or_else(|_| DEFAULT_GATEWAY_SIGNATURE_HEADER.to_string());
+                let header = HeaderName::from_bytes(header.trim().as_bytes()).map_err(|_| {
+                    AppError::EnvironmentError(format!("GATEWAY_SIGNATURE_HEADER is not a valid header name: {:?}.", header))
+                })?;
+                Some(GatewaySigning { algorithm, secret: secret.into_bytes().into(), header })
+            }
+            _ => None,
+        };
+
+        let gateway_timeout_secs = positive_env_var("GATEWAY_TIMEOUT_SECS", DEFAULT_GATEWAY_TIMEOUT_SECS)?;
+        let gateway_charge_timeout_ms = positive_env_var("GATEWAY_CHARGE_TIMEOUT_MS", DEFAULT_GATEWAY_CHARGE_TIMEOUT_MS)?;
+        if gateway_charge_timeout_ms > gateway_timeout_secs * 1000 {
+            return Err(AppError::EnvironmentError(format!(
+                "GATEWAY_CHARGE_TIMEOUT_MS ({}) cannot exceed GATEWAY_TIMEOUT_SECS ({}s).",
+                gateway_charge_timeout_ms, gateway_timeout_secs
+            )));
+        }
+
+        // Destructive, so it never runs on a default or half-finished configuration
+        let retention = if bool_env_var("RETENTION_ENABLED", false)? {
+            let after_days = match env::var("RETENTION_DAYS") {
+                Ok(_) => positive_env_var("RETENTION_DAYS", 0)?,
+                Err(_) => {
+                    return Err(AppError::EnvironmentError("RETENTION_DAYS must be set when RETENTION_ENABLED is.".to_string()));
+                }
+            };
+            let mode = match env::var("RETENTION_MODE") {
+                Ok(value) => RetentionMode::parse(value.trim()).ok_or_else(|| AppError::EnvironmentError(format!(
+                    "RETENTION_MODE must be \"anonymize\" or \"delete\", got {:?}.", value
+                )))?,
+                Err(_) => RetentionMode::Anonymize,
+            };
+            Some(RetentionPolicy { after_days, mode })
+        } else {
+            None
+        };
+
+        Ok(Config {
+            database_url: required_env_var("DATABASE_URL")?,
+            api_key: match &api_key_file {
+                Some(path) => read_gateway_api_key(path).map_err(|err| match err {
+                    AppError::BadRequest(_, msg) => AppError::EnvironmentError(msg),
+                    err => err,
+                })?,
+                None => required_env_var("PAYMENT_GATEWAY_API_KEY")?,
+            },
+            api_key_file,
+            bind_addr: socket_addr_env_var("BIND_ADDR")?
+                .unwrap_or_else(|| DEFAULT_BIND_ADDR.parse().expect("default bind address is valid")),
+            tls,
+            tls_reload_poll_secs: positive_env_var("TLS_RELOAD_POLL_SECS", DEFAULT_TLS_RELOAD_POLL_SECS)?,
+            metrics_bind_addr: socket_addr_env_var("METRICS_BIND_ADDR")?,
+            max_connections,
+            min_connections,
+            acquire_timeout_secs: positive_env_var("DB_ACQUIRE_TIMEOUT", DEFAULT_DB_ACQUIRE_TIMEOUT_SECS)?,
+            idle_timeout_secs: positive_env_var("DB_IDLE_TIMEOUT_SECS", DEFAULT_DB_IDLE_TIMEOUT_SECS)?,
+            max_lifetime_secs: positive_env_var("DB_MAX_LIFETIME_SECS", DEFAULT_DB_MAX_LIFETIME_SECS)?,
+            db_connect_attempts: positive_env_var("DB_CONNECT_ATTEMPTS", DEFAULT_DB_CONNECT_ATTEMPTS)?,
+            db_connect_retry_delay_ms: positive_env_var("DB_CONNECT_RETRY_DELAY_MS", DEFAULT_DB_CONNECT_RETRY_DELAY_MS)?,
+            gateway_timeout_secs,
+            gateway_charge_timeout_ms,
+            max_payment_amount: positive_env_var("MAX_PAYMENT_AMOUNT", DEFAULT_MAX_PAYMENT_AMOUNT)?,
+            amount_limits,
+            rate_limit_per_minute: positive_env_var("RATE_LIMIT_PER_MINUTE", DEFAULT_RATE_LIMIT_PER_MINUTE)?,
+            gateway_retry_attempts: positive_env_var("GATEWAY_RETRY_ATTEMPTS", DEFAULT_GATEWAY_RETRY_ATTEMPTS)?,
+            gateway_retry_base_delay_ms: positive_env_var("GATEWAY_RETRY_BASE_DELAY_MS", DEFAULT_GATEWAY_RETRY_BASE_DELAY_MS)?,
+            circuit_breaker_threshold: positive_env_var("CIRCUIT_BREAKER_THRESHOLD", DEFAULT_CIRCUIT_BREAKER_THRESHOLD)?,
+            circuit_breaker_cooldown_secs: positive_env_var("CIRCUIT_BREAKER_COOLDOWN_SECS", DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECS)?,
+            merchant_api_keys,
+            default_gateways,
+            gateway_routes,
+            test_cards,
+            cors_allowed_origins,
+            max_request_body_bytes: positive_env_var("MAX_REQUEST_BODY_BYTES", DEFAULT_MAX_REQUEST_BODY_BYTES)?,
+            http_logging: bool_env_var("HTTP_LOGGING", false)?,
+            webhook,
+            webhook_retry_attempts: positive_env_var("WEBHOOK_RETRY_ATTEMPTS", DEFAULT_WEBHOOK_RETRY_ATTEMPTS)?,
+            request_signing_secret: env::var("REQUEST_SIGNING_SECRET").ok().filter(|secret| !secret.is_empty()),
+            replay_window_secs: positive_env_var("REPLAY_WINDOW_SECS", DEFAULT_REPLAY_WINDOW_SECS)?,
+            gateway_url,
+            gateway_health_url,
+            gateway_signing,
+            velocity_max_charges: positive_env_var("VELOCITY_MAX_CHARGES", DEFAULT_VELOCITY_MAX_CHARGES)?,
+            velocity_window_minutes: positive_env_var("VELOCITY_WINDOW_MINUTES", DEFAULT_VELOCITY_WINDOW_MINUTES)?,
+            decline_rate_window_minutes: positive_env_var("DECLINE_RATE_WINDOW_MINUTES", DEFAULT_DECLINE_RATE_WINDOW_MINUTES)?,
+            card_fingerprint_salt: Some(required_env_var("CARD_FINGERPRINT_SALT")?)
+                .filter(|salt| !salt.is_empty())
+                .ok_or_else(|| AppError::EnvironmentError("CARD_FINGERPRINT_SALT must not be empty.".to_string()))?,
+            store_card_expiry: bool_env_var("STORE_CARD_EXPIRY", false)?,
+            fx_rates_url,
+            fx_rates_ttl_secs: positive_env_var("FX_RATES_TTL_SECS", DEFAULT_FX_RATES_TTL_SECS)?,
+            fx_rounding: match env::var("FX_ROUNDING_MODE") {
+                Ok(value) => RoundingMode::parse(value.trim()).ok_or_else(|| AppError::EnvironmentError(format!(
+                    "FX_ROUNDING_MODE must be \"half_up\", \"half_even\" or \"floor\", got {:?}.", value
+                )))?,
+                Err(_) => RoundingMode::HalfUp,
+            },
+            ip_allowlist: ip_networks_env_var("IP_ALLOWLIST")?,
+            trusted_proxies: ip_networks_env_var("TRUSTED_PROXIES")?,
+            subscription_poll_secs: positive_env_var("SUBSCRIPTION_POLL_SECS", DEFAULT_SUBSCRIPTION_POLL_SECS)?,
+            subscription_max_retries: non_negative_env_var("SUBSCRIPTION_MAX_RETRIES", DEFAULT_SUBSCRIPTION_MAX_RETRIES)?,
+            subscription_retry_delay_minutes: positive_env_var(
+                "SUBSCRIPTION_RETRY_DELAY_MINUTES",
+                DEFAULT_SUBSCRIPTION_RETRY_DELAY_MINUTES,
+            )?,
+            payment_queue_capacity: positive_env_var("PAYMENT_QUEUE_CAPACITY", DEFAULT_PAYMENT_QUEUE_CAPACITY)?,
+            payment_workers: positive_env_var("PAYMENT_WORKERS", DEFAULT_PAYMENT_WORKERS)?,
+            max_concurrent_payments: positive_env_var("MAX_CONCURRENT_PAYMENTS", DEFAULT_MAX_CONCURRENT_PAYMENTS)?,
+            reconciliation_poll_secs: positive_env_var("RECONCILIATION_POLL_SECS", DEFAULT_RECONCILIATION_POLL_SECS)?,
+            reconciliation_pending_after_secs: positive_env_var(
+                "RECONCILIATION_PENDING_AFTER_SECS",
+                DEFAULT_RECONCILIATION_PENDING_AFTER_SECS,
+            )?,
+            retention,
+            retention_poll_secs: positive_env_var("RETENTION_POLL_SECS", DEFAULT_RETENTION_POLL_SECS)?,
+        })
+    }
+}
+
+// '|'-separated gateways in priority order; at least one is required
+pub(crate) fn parse_gateway_chain(name: &str, value: &str) -> Result<Vec<GatewayMode>, AppError> {
+    let chain = value
+        .split('|')
+        .map(|mode| match mode.trim() {
+            "real" => Ok(GatewayMode::Real),
+            "mock" => Ok(GatewayMode::Mock),
+            other => Err(AppError::EnvironmentError(format!(
+                "{} must use \"real\" or \"mock\", got {:?}.", name, other
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if chain.is_empty() {
+        return Err(AppError::EnvironmentError(format!("{} must name at least one gateway.", name)));
+    }
+
+    Ok(chain)
+}
+
+pub(crate) fn required_env_var(name: &str) -> Result<String, AppError> {
+    env::var(name).map_err(|_| AppError::EnvironmentError(format!("{} must be set in the .env file.", name)))
+}
+
+// Optional positive numeric setting; a present but malformed value is an error, not a fallback
+pub(crate) fn positive_env_var<T>(name: &str, default: T) -> Result<T, AppError>
+where
+    T: std::str::FromStr + PartialOrd + Default,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<T>()
+            .ok()
+            .filter(|v| *v > T::default())
+            .ok_or_else(|| AppError::EnvironmentError(format!("{} must be a positive integer, got {:?}.", name, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+pub(crate) fn non_negative_env_var(name: &str, default: u32) -> Result<u32, AppError> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| AppError::EnvironmentError(format!("{} must be a non-negative integer, got {:?}.", name, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+pub(crate) fn bool_env_var(name: &str, default: bool) -> Result<bool, AppError> {
+    match env::var(name) {
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err(AppError::EnvironmentError(format!("{} must be \"true\" or \"false\", got {:?}.", name, value))),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+pub(crate) fn socket_addr_env_var(name: &str) -> Result<Option<SocketAddr>, AppError> {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().map(Some).map_err(|e| {
+            AppError::EnvironmentError(format!("{} must be a socket address like 0.0.0.0:3000: {}", name, e))
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+// Comma-separated CIDRs; a bare address is a single-host network
+pub(crate) fn ip_networks_env_var(name: &str) -> Result<Vec<IpNet>, AppError> {
+    let Ok(value) = env::var(name) else {
+        return Ok(Vec::new());
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| AppError::EnvironmentError(format!(
+                    "{} entries must be IP addresses or CIDRs like 10.0.0.0/8, got {:?}.", name, entry
+                )))
+        })
+        .collect()
+}
+
+// State struct holding DB pool, API key, the gateway routes and shared runtime state
+#[derive(Clone)]
+pub struct AppState {
+    pub(crate) db: PgPool,
+    pub(crate) api_key: GatewayApiKey,
+    pub(crate) amount_limits: AmountLimits,
+    pub(crate) ready: Arc<AtomicBool>, // Flipped once startup has finished
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) merchant_keys: Arc<Vec<([u8; 32], MerchantId, Roles)>>, // SHA-256 of each accepted API key, its owner and roles
+    pub(crate) gateway_retry: RetryPolicy,
+    pub(crate) metrics: PrometheusHandle,
+    pub(crate) gateways: GatewayRouter,
+    pub(crate) cors_allowed_origins: Arc<Vec<HeaderValue>>,
+    pub(crate) max_request_body_bytes: usize, // Larger bodies are rejected with 413
+    pub(crate) http_logging: bool,
+    pub(crate) webhooks: Option<WebhookNotifier>,
+    pub(crate) request_signing_secret: Option<Arc<[u8]>>,
+    pub(crate) nonces: NonceCache,
+    pub(crate) gateway_probe: Option<GatewayHealthProbe>, // Only used by deep health checks
+    pub(crate) velocity: VelocityLimit,
+    pub(crate) decline_rate: DeclineRate,
+    pub(crate) card_fingerprint_salt: Arc<[u8]>,
+    pub(crate) store_card_expiry: bool,
+    pub(crate) fx: Option<FxConverter>,
+    pub(crate) ip_allowlist: IpAllowlist,
+    pub(crate) dunning: DunningPolicy,
+    pub(crate) payment_queue: mpsc::Sender<PendingCharge>, // Bounded; drained by `run_payment_worker`
+    pub(crate) payment_slots: Arc<Semaphore>, // One permit per POST /payment being charged
+    pub(crate) status_updates: broadcast::Sender<StatusUpdate>, // Charge outcomes, for status streams
+}
+
+// A charge leaving Pending, as seen by this instance
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StatusUpdate {
+    pub(crate) transaction_uuid: Uuid,
+    pub(crate) status: TransactionStatus,
+}
+
+// What happens to transactions once they are older than the retention period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RetentionMode {
+    Anonymize, // Card data, fingerprint and metadata scrubbed; amounts and statuses kept for reporting
+    Delete,    // The row and its audit events are removed
+}
+
+impl RetentionMode {
+    pub(crate) fn parse(value: &str) -> Option<RetentionMode> {
+        match value {
+            "anonymize" => Some(RetentionMode::Anonymize),
+            "delete" => Some(RetentionMode::Delete),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            RetentionMode::Anonymize => "anonymize",
+            RetentionMode::Delete => "delete",
+        }
+    }
+}
+
+// PEM certificate chain and private key for serving HTTPS
+#[derive(Debug, Clone)]
+pub(crate) struct TlsPaths {
+    pub(crate) cert_path: PathBuf,
+    pub(crate) key_path: PathBuf,
+}
+
+// The external gateway's API key, shared by every holder so a rotation reaches them all at
+// once. `source` is the secret file it is reloaded from; without one it can't be rotated.
+#[derive(Clone)]
+pub(crate) struct GatewayApiKey {
+    pub(crate) current: Arc<RwLock<Arc<str>>>,
+    pub(crate) source: Option<Arc<Path>>,
+}
+
+// MACs the external gateway may require over a charge's body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GatewaySigningAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl GatewaySigningAlgorithm {
+    pub(crate) fn parse(value: &str) -> Option<GatewaySigningAlgorithm> {
+        match value {
+            "hmac-sha256" => Some(GatewaySigningAlgorithm::HmacSha256),
+            "hmac-sha512" => Some(GatewaySigningAlgorithm::HmacSha512),
+            _ => None,
+        }
+    }
+}
+
+// Credentials and placement for signing outbound charges; gateways differ in both
+#[derive(Debug, Clone)]
+pub(crate) struct GatewaySigning {
+    pub(crate) algorithm: GatewaySigningAlgorithm,
+    pub(crate) secret: Arc<[u8]>,
+    pub(crate) header: HeaderName,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetentionPolicy {
+    pub(crate) after_days: i64,
+    pub(crate) mode: RetentionMode,
+}
+
+// Retry schedule for subscription cycles that fail to charge
+#[derive(Clone, Copy)]
+pub(crate) struct DunningPolicy {
+    pub(crate) max_retries: i32,
+    pub(crate) retry_delay: chrono::Duration, // Before the first retry, doubled for each one after
+}
+
+// Source networks allowed to reach the API, and the proxies trusted to report the client address
+#[derive(Clone)]
+pub(crate) struct IpAllowlist {
+    pub(crate) allowed: Arc<[IpNet]>, // Empty lets every source address through
+    pub(crate) trusted_proxies: Arc<[IpNet]>,
+}
+
+// The client address resolved past trusted proxies; set by `check_ip_allowlist`
+#[derive(Clone, Copy)]
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+// Anti-fraud cap on how often one card may be charged successfully
+#[derive(Clone, Copy)]
+pub(crate) struct VelocityLimit {
+    pub(crate) max_charges: i64,
+    pub(crate) window: chrono::Duration,
+}
+
+// Exponential backoff for transient gateway failures
+#[derive(Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) base_delay: Duration, // Doubled after every failed attempt
+}
+
+// Trips open after `failure_threshold` consecutive gateway failures; one per gateway
+#[derive(Clone)]
+pub(crate) struct CircuitBreaker {
+    pub(crate) failure_threshold: u32,
+    pub(crate) cooldown: Duration,
+    pub(crate) state: Arc<Mutex<BreakerState>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum BreakerState {
+    Closed { consecutive_failures: u32 },
+    Open { until: Instant },
+    // A single probe request is in flight. If it never reports back (the caller was
+    // cancelled), another probe is let through once the deadline passes.
+    HalfOpen { probe_deadline: Instant },
+}
+
+// Fixed one-minute window per client IP
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    pub(crate) requests_per_minute: u32,
+    pub(crate) windows: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+// Nonces seen on signed requests, kept only as long as their timestamp is still acceptable.
+// In-memory, so replay protection is per instance.
+#[derive(Clone)]
+pub(crate) struct NonceCache {
+    pub(crate) window_secs: i64,
+    pub(crate) seen: Arc<Mutex<HashMap<String, i64>>>, // nonce -> request timestamp (unix seconds)
+}
+
+// Gateway outcomes over a rolling window, for alerting on decline spikes (card testing, or a
+// gateway declining everything). Counted in fixed buckets so memory stays bounded under load.
+#[derive(Clone)]
+pub(crate) struct DeclineRate {
+    pub(crate) window: Duration,
+    pub(crate) buckets: Arc<Mutex<VecDeque<(Instant, u64, u64)>>>, // Bucket start, outcomes, declines
+}
+
+// Gateway reachability for deep health checks, cached so probes don't hammer the gateway
+#[derive(Clone)]
+pub(crate) struct GatewayHealthProbe {
+    pub(crate) client: Client,
+    pub(crate) url: Arc<str>,
+    pub(crate) last: Arc<Mutex<Option<(Instant, bool)>>>, // When it was checked, and whether it was up
+}
@@ -0,0 +1,385 @@
+// --- DATABASE ---
+//
+// Idempotency records, transaction rows and their audit events, and startup connection handling.
+
+use axum::http::HeaderMap;
+use chrono::Utc;
+use uuid::Uuid;
+use std::time::Duration;
+use sqlx::{PgPool, postgres::PgPoolOptions};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{config::*, errors::*, middleware::*, models::*};
+
+// --- IDEMPOTENCY ---
+
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+// Stored responses expire after this. The key stays on its transaction row for good, so a
+// retry after expiry still finds the original charge (see `existing_idempotent_response`)
+// rather than charging again; reusing a key for a new payment is never possible.
+pub(crate) const IDEMPOTENCY_KEY_TTL_HOURS: i64 = 24;
+pub(crate) const PAYMENT_IN_PROGRESS: &str = "PAYMENT_IN_PROGRESS";
+pub(crate) const DUPLICATE_REQUEST: &str = "DUPLICATE_REQUEST"; // Message key of every replayed response
+pub(crate) const PAYMENT_SCOPE: &str = "payment"; // Scope of POST /payment keys in idempotency_keys
+
+// Refund keys are scoped to their transaction, so one key can't replay another transaction's refund
+pub(crate) fn refund_scope(transaction_uuid: Uuid) -> String {
+    format!("refund:{}", transaction_uuid)
+}
+
+// Read and sanity-check the Idempotency-Key header, if present
+pub(crate) fn idempotency_key_from_headers(headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+
+    let key = value
+        .to_str()
+        .map_err(|_| AppError::BadRequest("INVALID_IDEMPOTENCY_KEY", "Idempotency key must be valid ASCII.".to_string()))?
+        .trim();
+
+    if key.is_empty() || key.len() > 255 {
+        return Err(AppError::BadRequest("INVALID_IDEMPOTENCY_KEY", "Idempotency key must be 1-255 characters.".to_string()));
+    }
+
+    Ok(Some(key.to_string()))
+}
+
+// SHA-256 of the request, used to detect a key reused with a different payload. The hash is
+// stored, so the card only enters it as its salted fingerprint; the CVV not at all.
+pub(crate) fn request_fingerprint(card_fingerprint_salt: &[u8], data: &PaymentRequest) -> String {
+    let instrument = match &data.instrument {
+        PaymentInstrument::Card(card) => serde_json::json!({
+            "card_fingerprint": card_fingerprint(card_fingerprint_salt, &card.card_number),
+            "expiry_month": card.expiry_month,
+            "expiry_year": card.expiry_year,
+        }),
+        PaymentInstrument::Token { payment_token } => serde_json::json!({ "payment_token": payment_token }),
+        PaymentInstrument::SavedMethod { token_id } => serde_json::json!({ "token_id": token_id }),
+    };
+    let mut canonical = serde_json::json!({
+        "amount": data.amount,
+        "currency": data.currency,
+        "instrument": instrument,
+        "capture": data.capture,
+        "settlement_currency": data.settlement_currency,
+    });
+    // Only when present, so hashes of requests without metadata are unchanged
+    if let Some(metadata) = &data.metadata {
+        canonical["metadata"] = metadata.clone();
+    }
+
+    format!("{:x}", Sha256::digest(canonical.to_string().as_bytes()))
+}
+
+// As `request_fingerprint`, for a refund: a key reused for a different amount is rejected
+pub(crate) fn refund_fingerprint(request: &RefundRequest) -> String {
+    let canonical = serde_json::json!({ "amount": request.amount });
+
+    format!("{:x}", Sha256::digest(canonical.to_string().as_bytes()))
+}
+
+// Stored response for a live (non-expired) key
+pub(crate) async fn find_idempotent_response(
+    db: impl sqlx::PgExecutor<'_>,
+    merchant: MerchantId,
+    scope: &str,
+    key: &str,
+    request_hash: &str,
+) -> Result<Option<PaymentResponse>, AppError> {
+    let cutoff = Utc::now() - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS);
+
+    let row = sqlx::query!(
+        r#"
+        SELECT request_hash, response
+        FROM idempotency_keys
+        WHERE merchant_id = $1 AND scope = $2 AND idempotency_key = $3 AND created_at > $4
+        "#,
+        merchant.0,
+        scope,
+        key,
+        cutoff
+    )
+    .fetch_optional(db)
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    if row.request_hash != request_hash {
+        return Err(AppError::BadRequest("IDEMPOTENCY_KEY_REUSED", "Idempotency key reused with different payload.".to_string()));
+    }
+
+    let response = serde_json::from_value(row.response)
+        .map_err(|e| AppError::InternalServerError(format!("Corrupt idempotency record: {}", e)))?;
+
+    Ok(Some(response))
+}
+
+// Remember the response for a key; an expired record under the same key is replaced
+pub(crate) async fn store_idempotent_response(
+    db: impl sqlx::PgExecutor<'_>,
+    merchant: MerchantId,
+    scope: &str,
+    key: &str,
+    request_hash: &str,
+    response: &PaymentResponse,
+) -> Result<(), AppError> {
+    let cutoff = Utc::now() - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS);
+    let response = serde_json::to_value(response)
+        .map_err(|e| AppError::InternalServerError(format!("Failed to serialize response: {}", e)))?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO idempotency_keys (merchant_id, scope, idempotency_key, request_hash, response)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (merchant_id, scope, idempotency_key) DO UPDATE
+        SET request_hash = EXCLUDED.request_hash, response = EXCLUDED.response, created_at = NOW()
+        WHERE idempotency_keys.created_at <= $6
+        "#,
+        merchant.0,
+        scope,
+        key,
+        request_hash,
+        response,
+        cutoff
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+// Response for a retry that lost the insert race (or whose stored response expired):
+// report the transaction the key already produced instead of charging again. A row still
+// Pending has no outcome to report yet, so the retry is told to come back later.
+pub(crate) async fn existing_idempotent_response(
+    db: &PgPool,
+    merchant: MerchantId,
+    key: &str,
+    request_hash: &str,
+) -> Result<PaymentResponse, AppError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT transaction_uuid, request_hash, amount, currency, status AS "status: TransactionStatus", masked_card_number, card_brand AS "card_brand: CardBrand", gateway_ref, decline_reason AS "decline_reason: DeclineReason"
+        FROM transactions
+        WHERE merchant_id = $1 AND idempotency_key = $2
+        "#,
+        merchant.0,
+        key
+    )
+    .fetch_one(db)
+    .await?;
+
+    if row.request_hash.as_deref() != Some(request_hash) {
+        return Err(AppError::BadRequest("IDEMPOTENCY_KEY_REUSED", "Idempotency key reused with different payload.".to_string()));
+    }
+
+    let transaction_id = row.transaction_uuid.to_string();
+    let message = format!("Duplicate request; transaction is {}.", row.status.as_str());
+    let response = match row.status {
+        TransactionStatus::Pending => {
+            return Err(AppError::Conflict(
+                PAYMENT_IN_PROGRESS,
+                format!("Transaction {} for this idempotency key has no outcome yet; retry later.", transaction_id),
+            ));
+        }
+        TransactionStatus::Failed => {
+            PaymentResponse::new_failure(transaction_id, DUPLICATE_REQUEST, message).with_decline_reason(row.decline_reason)
+        }
+        TransactionStatus::Authorized => {
+            PaymentResponse::new_success(transaction_id, DUPLICATE_REQUEST, message).with_operation(PaymentOperation::Authorize)
+        }
+        TransactionStatus::Success => {
+            PaymentResponse::new_success(transaction_id, DUPLICATE_REQUEST, message).with_operation(PaymentOperation::Charge)
+        }
+        // Charged (and since refunded or voided): the original request succeeded
+        _ => PaymentResponse::new_success(transaction_id, DUPLICATE_REQUEST, message),
+    };
+
+    Ok(response
+        .with_card_brand(row.card_brand)
+        .with_payment_details(row.amount, &row.currency, &row.masked_card_number)
+        .with_gateway_ref(row.gateway_ref))
+}
+
+// --- TRANSACTION ROWS ---
+
+// A status change and its audit event, inside the caller's SQL transaction. Only applied while
+// the row is still `from`; false, with no event, if something else changed it first.
+pub(crate) async fn update_transaction_status(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    transaction_id: i32,
+    from: TransactionStatus,
+    status: TransactionStatus,
+    event: TransactionEventType,
+    amount: i64,
+) -> Result<bool, AppError> {
+    let updated = sqlx::query!(
+        "UPDATE transactions SET status = $1, version = version + 1 WHERE id = $2 AND status = $3",
+        status as _,
+        transaction_id,
+        from as _
+    )
+    .execute(&mut **tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    record_event(tx, transaction_id, event, status, amount).await?;
+    Ok(true)
+}
+
+// Follow-up writes are guarded by the version read with the row. The FOR UPDATE lock already
+// serializes the handlers that take it, so a mismatch means a writer that skipped the lock;
+// refuse rather than overwrite its change.
+pub(crate) fn ensure_unmodified(result: sqlx::postgres::PgQueryResult) -> Result<(), AppError> {
+    if result.rows_affected() == 0 {
+        return Err(AppError::Conflict(
+            "CONCURRENT_MODIFICATION",
+            "Transaction was modified concurrently; retry the request.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Append to the audit log; always called inside the SQL transaction that made the change
+pub(crate) async fn record_event(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    transaction_id: i32,
+    event: TransactionEventType,
+    status: TransactionStatus,
+    amount: i64,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO transaction_events (transaction_id, event_type, status, amount)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        transaction_id,
+        event as _,
+        status as _,
+        amount
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+// Every query that loads a Transaction; only the clauses after FROM differ. A macro because
+// sqlx checks the query at compile time and so needs it as literals.
+macro_rules! query_transactions {
+    ($clauses:literal $(, $arg:expr)* $(,)?) => {
+        sqlx::query_as!(
+            Transaction,
+            r#"
+            SELECT id, transaction_uuid, amount, currency, status AS "status: TransactionStatus", masked_card_number, card_brand AS "card_brand: CardBrand", refunded_amount, authorized_amount, gateway, gateway_ref, version, presentment_amount, presentment_currency, fx_rate, fx_rounding AS "fx_rounding: RoundingMode", card_expiry_month, card_expiry_year, decline_reason AS "decline_reason: DeclineReason", metadata, created_at
+            FROM transactions
+            "# + $clauses
+            $(, $arg)*
+        )
+    };
+}
+pub(crate) use query_transactions;
+
+// Load a transaction row, mapping a miss to 404
+pub(crate) async fn fetch_transaction(db: &PgPool, merchant: MerchantId, transaction_uuid: Uuid) -> Result<Transaction, AppError> {
+    query_transactions!("WHERE transaction_uuid = $1 AND merchant_id = $2", transaction_uuid, merchant.0)
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Transaction not found.".to_string()))
+}
+
+// As `fetch_transaction`, but the row stays locked until `tx` ends
+pub(crate) async fn lock_transaction(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    merchant: MerchantId,
+    transaction_uuid: Uuid,
+) -> Result<Transaction, AppError> {
+    query_transactions!("WHERE transaction_uuid = $1 AND merchant_id = $2 FOR UPDATE", transaction_uuid, merchant.0)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Transaction not found.".to_string()))
+}
+
+// --- CONNECTION AND STARTUP ---
+
+// Migrations under migrations/, embedded at compile time. Postgres advisory locks keep
+// replicas starting together from applying them twice.
+pub(crate) static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
+
+// Bring a fresh or older database up to the schema this build expects
+pub(crate) async fn run_migrations(db: &PgPool) -> Result<(), AppError> {
+    MIGRATOR.run(db).await.map_err(|e| {
+        AppError::EnvironmentError(format!("Failed to apply database migrations: {}", e))
+    })?;
+    info!(migrations = MIGRATOR.iter().count(), "database schema is up to date");
+
+    Ok(())
+}
+
+// Make sure every merchant named in MERCHANT_API_KEYS has a row, and pair each key's hash
+// with its merchant's ID for authentication
+pub(crate) async fn register_merchants(
+    db: &PgPool,
+    merchant_api_keys: &[(String, String, Roles)],
+) -> Result<Vec<([u8; 32], MerchantId, Roles)>, AppError> {
+    let mut merchant_keys = Vec::with_capacity(merchant_api_keys.len());
+
+    for (name, key, roles) in merchant_api_keys {
+        let merchant_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO merchants (name) VALUES ($1)
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING id
+            "#,
+            name
+        )
+        .fetch_one(db)
+        .await?;
+
+        merchant_keys.push((Sha256::digest(key.as_bytes()).into(), MerchantId(merchant_id), *roles));
+    }
+    info!(keys = merchant_keys.len(), "merchant API keys registered");
+
+    Ok(merchant_keys)
+}
+
+// The database may still be starting (compose/k8s ordering), so back off and retry
+pub(crate) async fn connect_with_retry(config: &Config) -> Result<PgPool, AppError> {
+    let mut delay = Duration::from_millis(config.db_connect_retry_delay_ms);
+    let mut attempt = 1;
+
+    loop {
+        let result = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .min_connections(config.min_connections)
+            .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+            .idle_timeout(Duration::from_secs(config.idle_timeout_secs))
+            .max_lifetime(Duration::from_secs(config.max_lifetime_secs))
+            .connect(&config.database_url)
+            .await;
+
+        match result {
+            Ok(pool) => return Ok(pool),
+            Err(err) if attempt < config.db_connect_attempts => {
+                warn!(attempt, max_attempts = config.db_connect_attempts, error = %err, retry_in = ?delay,
+                    "database connection failed, retrying");
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_DB_CONNECT_RETRY_DELAY);
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(AppError::EnvironmentError(format!(
+                    "Failed to connect to PostgreSQL after {} attempts: {}", attempt, err
+                )));
+            }
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// --- ERROR HANDLING (ADVANCED) ---
+
+use axum::{
+    extract::Json,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::middleware::*;
+
+// JSON body of every error response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    pub code: &'static str, // Stable and machine-readable; messages may change
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    // Every offending field, for VALIDATION_FAILED
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+// Advanced error handling: AppError
+#[derive(Debug)]
+pub(crate) enum AppError {
+    InternalServerError(String),
+    BadRequest(&'static str, String), // Code and message, e.g. ("INVALID_CARD", msg)
+    NotFound(String),
+    Conflict(&'static str, String), // Code and message, like BadRequest
+    Unauthorized(String),
+    Forbidden(String), // Authenticated, but the API key lacks the route's role
+    DatabaseError(sqlx::Error),
+    EnvironmentError(String),
+    GatewayError(String),
+    GatewayTimeout, // No answer in time; unlike GatewayError the charge may have landed
+    GatewayAmbiguous(String), // Accepted (2xx) but unreadable: the charge may have landed too
+    GatewayRejected(String), // Refused with a 4xx: nothing was charged, and resending won't change that
+    ServiceUnavailable(String), // Temporarily unable to take the request; safe to retry
+    RateLimited(u64), // Seconds until the client may retry
+    PayloadTooLarge(String),
+    Validation(Vec<FieldError>), // 400 listing every failed field
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            // No connection within the acquire timeout: the database is down or the pool is
+            // exhausted. Both pass, so the client is told to retry rather than given a 500.
+            sqlx::Error::PoolTimedOut => {
+                warn!("timed out acquiring a database connection");
+                AppError::ServiceUnavailable("Database is temporarily unavailable; retry shortly.".to_string())
+            }
+            err => AppError::DatabaseError(err),
+        }
+    }
+}
+
+// Field errors from the validation module become one VALIDATION_FAILED response
+impl From<Vec<FieldError>> for AppError {
+    fn from(errors: Vec<FieldError>) -> Self {
+        AppError::Validation(errors)
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(err: reqwest::Error) -> Self {
+        AppError::GatewayError(format!("External gateway call failed: {}", err))
+    }
+}
+
+// Retry-After sent with a 503: shedding is momentary, so clients are told to come back soon
+pub(crate) const SERVICE_UNAVAILABLE_RETRY_AFTER_SECS: u64 = 1;
+
+impl AppError {
+    // The gateway may have charged the card, so the transaction must stay Pending for
+    // reconciliation rather than be recorded as failed
+    pub(crate) fn is_outcome_unknown(&self) -> bool {
+        matches!(self, AppError::GatewayTimeout | AppError::GatewayAmbiguous(_))
+    }
+
+    // Status and localized body, as sent by `into_response` and embedded in batch results
+    pub(crate) fn into_status_and_detail(self) -> (StatusCode, ErrorDetail) {
+        let mut fields = Vec::new();
+        let (status, code, error_message) = match self {
+            AppError::InternalServerError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR", msg),
+            AppError::BadRequest(code, msg) => (StatusCode::BAD_REQUEST, code, msg),
+            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, "NOT_FOUND", msg),
+            AppError::Conflict(code, msg) => (StatusCode::CONFLICT, code, msg),
+            AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, "UNAUTHORIZED", msg),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, "FORBIDDEN", msg),
+            AppError::DatabaseError(err) => {
+                error!(error = ?err, "database operation failed");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR, 
+                    "DATABASE_ERROR",
+                    "Database operation failed.".to_string()
+                )
+            },
+            AppError::EnvironmentError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, "CONFIGURATION_ERROR", msg),
+            AppError::GatewayError(msg) => (StatusCode::BAD_GATEWAY, "GATEWAY_ERROR", msg),
+            AppError::GatewayTimeout => (StatusCode::GATEWAY_TIMEOUT, "GATEWAY_TIMEOUT", "Gateway timed out.".to_string()),
+            AppError::GatewayAmbiguous(_) => (
+                StatusCode::BAD_GATEWAY,
+                "GATEWAY_OUTCOME_UNKNOWN",
+                "The gateway's answer could not be read; the payment stays pending until it is confirmed.".to_string(),
+            ),
+            AppError::GatewayRejected(msg) => (StatusCode::BAD_GATEWAY, "GATEWAY_REJECTED", msg),
+            AppError::ServiceUnavailable(msg) => (StatusCode::SERVICE_UNAVAILABLE, "SERVICE_UNAVAILABLE", msg),
+            AppError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, "RATE_LIMITED", "Too many requests.".to_string()),
+            AppError::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, "PAYLOAD_TOO_LARGE", msg),
+            AppError::Validation(errors) => {
+                fields = errors;
+                (StatusCode::BAD_REQUEST, "VALIDATION_FAILED", "Request validation failed.".to_string())
+            }
+        };
+
+        let locale = Locale::current();
+        for field in &mut fields {
+            field.message = locale.message(field.code, std::mem::take(&mut field.message));
+        }
+        let error = ErrorDetail {
+            code,
+            message: locale.message(code, error_message),
+            request_id: CURRENT_REQUEST_ID.try_with(Clone::clone).ok(),
+            fields,
+        };
+
+        (status, error)
+    }
+}
+
+// Handle error conversion
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let retry_after = match &self {
+            AppError::RateLimited(secs) => Some(*secs),
+            AppError::ServiceUnavailable(_) => Some(SERVICE_UNAVAILABLE_RETRY_AFTER_SECS),
+            _ => None,
+        };
+
+        let (status, error) = self.into_status_and_detail();
+        let mut response = (status, Json(ErrorBody { error })).into_response();
+        if let Some(secs) = retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, secs.into());
+        }
+
+        response
+    }
+}
+
+// Languages human-readable messages are offered in, picked per request from Accept-Language.
+// Codes and message keys never change with the locale; only the text alongside them does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Locale {
+    En,
+    Tr,
+}
+
+impl Locale {
+    pub(crate) fn tag(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Tr => "tr",
+        }
+    }
+
+    // Highest-weighted supported language, e.g. "de-DE, tr;q=0.8, en;q=0.5" -> Tr. English
+    // when nothing listed is supported.
+    pub(crate) fn negotiate(accept_language: &str) -> Locale {
+        let mut best: Option<(Locale, f32)> = None;
+        for entry in accept_language.split(',') {
+            let mut parts = entry.split(';').map(str::trim);
+            let primary = parts.next().unwrap_or_default().split('-').next().unwrap_or_default().to_ascii_lowercase();
+            let weight = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            let locale = match primary.as_str() {
+                "en" => Locale::En,
+                "tr" => Locale::Tr,
+                _ => continue,
+            };
+            if weight > 0.0 && best.is_none_or(|(_, best_weight)| weight > best_weight) {
+                best = Some((locale, weight));
+            }
+        }
+
+        best.map_or(Locale::En, |(locale, _)| locale)
+    }
+
+    // The request's locale; English outside a request (workers, webhooks)
+    pub(crate) fn current() -> Locale {
+        CURRENT_LOCALE.try_with(|locale| *locale).unwrap_or(Locale::En)
+    }
+
+    // `english` is the detailed message; other locales replace it with the catalog entry for
+    // `key` when there is one
+    pub(crate) fn message(&self, key: &str, english: String) -> String {
+        match self {
+            Locale::En => english,
+            Locale::Tr => turkish_message(key).map_or(english, str::to_string),
+        }
+    }
+}
+
+// Turkish catalog, keyed by error code or payment message key
+pub(crate) fn turkish_message(key: &str) -> Option<&'static str> {
+    let message = match key {
+        "PAYMENT_APPROVED" => "Ödeme onaylandı.",
+        "PAYMENT_AUTHORIZED" => "Ödeme için provizyon alındı.",
+        "PAYMENT_DECLINED" => "Ödeme reddedildi.",
+        "DUPLICATE_REQUEST" => "Yinelenen istek; ilk isteğin sonucu döndürüldü.",
+        "REFUND_APPROVED" => "İade onaylandı.",
+        "CAPTURE_APPROVED" => "Tahsilat onaylandı.",
+        "AUTHORIZATION_VOIDED" => "Provizyon iptal edildi.",
+        "VALIDATION_FAILED" => "İstek doğrulanamadı.",
+        "UNSUPPORTED_CURRENCY" => "Desteklenmeyen para birimi.",
+        "INVALID_AMOUNT" => "Ödeme tutarı sıfırdan büyük olmalıdır.",
+        "AMOUNT_TOO_SMALL" => "Ödeme tutarı izin verilen alt sınırın altında.",
+        "AMOUNT_TOO_LARGE" => "Ödeme tutarı izin verilen üst sınırı aşıyor.",
+        "AMOUNT_TOO_PRECISE" => "Ödeme tutarı para biriminin izin verdiğinden fazla ondalık basamak içeriyor.",
+        "INVALID_CVV" => "Kart türü için geçersiz CVV.",
+        "INVALID_CARD" => "Geçersiz kart numarası.",
+        "INVALID_PAYMENT_TOKEN" => "Geçersiz ödeme belirteci.",
+        "INVALID_GATEWAY_KEY" => "Geçersiz ödeme sağlayıcısı API anahtarı.",
+        "GATEWAY_KEY_SOURCE_NOT_CONFIGURED" => "Ödeme sağlayıcısı anahtar dosyası yapılandırılmamış.",
+        "INVALID_BATCH" => "Toplu ödeme isteği 1 ile izin verilen üst sınır arasında ödeme içermelidir.",
+        "INVALID_PAYMENT_REQUEST" => "Geçersiz ödeme isteği.",
+        "UNKNOWN_PAYMENT_METHOD" => "Bilinmeyen ödeme yöntemi.",
+        "INVALID_EXPIRY" => "Geçersiz son kullanma tarihi.",
+        "CARD_EXPIRED" => "Kartın süresi dolmuş.",
+        "INVALID_METADATA" => "Metadata bir JSON nesnesi olmalıdır.",
+        "METADATA_TOO_LARGE" => "Metadata izin verilen boyutu aşıyor.",
+        "INVALID_IDEMPOTENCY_KEY" => "Geçersiz idempotency anahtarı.",
+        "IDEMPOTENCY_KEY_REUSED" => "Idempotency anahtarı farklı bir istekle yeniden kullanıldı.",
+        "PAYMENT_IN_PROGRESS" => "Bu idempotency anahtarına ait ödeme henüz sonuçlanmadı; daha sonra tekrar deneyin.",
+        "FX_NOT_CONFIGURED" => "Döviz çevirisi etkin değil.",
+        "INVALID_TRANSACTION_ID" => "Geçersiz işlem UUID'si.",
+        "INVALID_SUBSCRIPTION_ID" => "Geçersiz abonelik UUID'si.",
+        "INVALID_REFUND_REQUEST" => "Geçersiz iade isteği.",
+        "INVALID_REFUND_AMOUNT" => "Geçersiz iade tutarı.",
+        "ALREADY_REFUNDED" => "İşlem zaten iade edilmiş.",
+        "NOT_REFUNDABLE" => "Yalnızca başarılı işlemler iade edilebilir.",
+        "INVALID_CAPTURE_REQUEST" => "Geçersiz tahsilat isteği.",
+        "INVALID_CAPTURE_AMOUNT" => "Geçersiz tahsilat tutarı.",
+        "NOT_CAPTURABLE" => "Yalnızca provizyonlu işlemler tahsil edilebilir.",
+        "ALREADY_SETTLED" => "İşlem kesinleşmiş; bunun yerine iade edin.",
+        "ALREADY_VOIDED" => "İşlem zaten iptal edilmiş.",
+        "NOT_VOIDABLE" => "Bu işlem iptal edilemez.",
+        "CONCURRENT_MODIFICATION" => "İşlem eşzamanlı olarak değiştirildi; isteği yeniden deneyin.",
+        "INVALID_QUERY" => "Geçersiz sorgu parametreleri.",
+        "INVALID_CURSOR" => "Geçersiz sayfalama imleci.",
+        "NOT_FOUND" => "Kayıt bulunamadı.",
+        "UNAUTHORIZED" => "Kimlik doğrulanamadı.",
+        "FORBIDDEN" => "Bu API anahtarının bu işlem için yetkisi yok.",
+        "RATE_LIMITED" => "Çok fazla istek.",
+        "PAYLOAD_TOO_LARGE" => "İstek gövdesi çok büyük.",
+        "GATEWAY_ERROR" => "Ödeme sağlayıcısına ulaşılamadı.",
+        "GATEWAY_TIMEOUT" => "Ödeme sağlayıcısı zamanında yanıt vermedi.",
+        "GATEWAY_OUTCOME_UNKNOWN" => "Ödeme sağlayıcısının yanıtı okunamadı; ödeme doğrulanana kadar beklemede kalır.",
+        "GATEWAY_REJECTED" => "Ödeme sağlayıcısı isteği reddetti.",
+        "SERVICE_UNAVAILABLE" => "Hizmet geçici olarak kullanılamıyor; daha sonra tekrar deneyin.",
+        "INTERNAL_ERROR" | "DATABASE_ERROR" => "Sunucu hatası.",
+        _ => return None,
+    };
+
+    Some(message)
+}
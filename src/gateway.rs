@@ -0,0 +1,1043 @@
+// --- EXTERNAL GATEWAY SIMULATION ---
+
+use axum::http::header;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::Client;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+use tracing::{debug, error, info, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use opentelemetry::propagation::Injector;
+
+use crate::{config::*, errors::*, middleware::{CURRENT_REQUEST_ID, REQUEST_ID_HEADER}, models::*};
+
+// Gateway decision for a charge. Card details are only reported back for tokenized charges.
+#[derive(Debug)]
+pub struct GatewayCharge {
+    pub status: TransactionStatus,
+    pub message: String,
+    pub masked_card_number: Option<String>,
+    pub card_brand: Option<CardBrand>,
+    pub gateway_ref: Option<String>, // Not every gateway (or outcome) returns one
+    pub decline_reason: Option<DeclineReason>, // Set when the gateway declined
+}
+
+impl GatewayCharge {
+    pub(crate) fn new(status: TransactionStatus, message: &str) -> Self {
+        GatewayCharge {
+            status,
+            message: message.to_string(),
+            masked_card_number: None,
+            card_brand: None,
+            gateway_ref: None,
+            decline_reason: None,
+        }
+    }
+
+    pub(crate) fn declined(reason: DeclineReason, message: &str) -> Self {
+        GatewayCharge { decline_reason: Some(reason), ..GatewayCharge::new(TransactionStatus::Failed, message) }
+    }
+
+    pub(crate) fn with_gateway_ref(mut self, gateway_ref: String) -> Self {
+        self.gateway_ref = Some(gateway_ref);
+        self
+    }
+}
+
+// Anything that can charge and refund. Chosen per currency by the GatewayRouter.
+#[async_trait]
+pub(crate) trait PaymentGateway: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn charge(
+        &self,
+        data: &PaymentRequest,
+        transaction_uuid: &Uuid,
+        request_id: &str,
+    ) -> Result<GatewayCharge, AppError>;
+
+    async fn refund(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError>;
+
+    // Cancels an authorization that has not settled yet
+    async fn void(&self, transaction: &Transaction) -> Result<String, AppError>;
+
+    // Takes `amount` (at most the authorized amount) from a held authorization
+    async fn capture(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError>;
+
+    // What the gateway recorded for a charge whose outcome we never saw. None means the
+    // charge never reached it, and fails the row, so only a gateway that knows every charge
+    // it received may answer None; one that can't tell returns an error instead.
+    async fn lookup_charge(&self, transaction: &Transaction) -> Result<Option<GatewayCharge>, AppError>;
+
+    // Vaults a card and returns the gateway's token for charging it later
+    async fn tokenize(&self, card: &CardDetails) -> Result<String, AppError>;
+}
+
+// What an approval means for this request: captured now, or only held
+pub(crate) fn approved_status(data: &PaymentRequest) -> TransactionStatus {
+    if data.capture { TransactionStatus::Success } else { TransactionStatus::Authorized }
+}
+
+// Declines the simulated gateways return for test cards. Configured through
+// TEST_CARD_OUTCOMES by the names in `NAMES`:
+//   decline            -> "Card declined: Do not honor"       (do_not_honor)
+//   insufficient_funds -> "Card declined: Insufficient funds" (insufficient_funds)
+//   expired            -> "Card declined: Expired card"       (card_expired)
+//   suspected_fraud    -> "Card declined: Suspected fraud"    (suspected_fraud)
+//   processing_error   -> "Card declined: Processing error"   (processing_error)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SimulatedOutcome {
+    Decline,
+    InsufficientFunds,
+    Expired,
+    SuspectedFraud,
+    ProcessingError,
+}
+
+impl SimulatedOutcome {
+    pub(crate) const NAMES: [&'static str; 5] = ["decline", "insufficient_funds", "expired", "suspected_fraud", "processing_error"];
+
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "decline" => Some(SimulatedOutcome::Decline),
+            "insufficient_funds" => Some(SimulatedOutcome::InsufficientFunds),
+            "expired" => Some(SimulatedOutcome::Expired),
+            "suspected_fraud" => Some(SimulatedOutcome::SuspectedFraud),
+            "processing_error" => Some(SimulatedOutcome::ProcessingError),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            SimulatedOutcome::Decline => "Do not honor",
+            SimulatedOutcome::InsufficientFunds => "Insufficient funds",
+            SimulatedOutcome::Expired => "Expired card",
+            SimulatedOutcome::SuspectedFraud => "Suspected fraud",
+            SimulatedOutcome::ProcessingError => "Processing error",
+        }
+    }
+
+    pub(crate) fn decline_reason(&self) -> DeclineReason {
+        match self {
+            SimulatedOutcome::Decline => DeclineReason::DoNotHonor,
+            SimulatedOutcome::InsufficientFunds => DeclineReason::InsufficientFunds,
+            SimulatedOutcome::Expired => DeclineReason::CardExpired,
+            SimulatedOutcome::SuspectedFraud => DeclineReason::SuspectedFraud,
+            SimulatedOutcome::ProcessingError => DeclineReason::ProcessingError,
+        }
+    }
+}
+
+// PAN prefix -> simulated decline; the longest matching prefix wins
+#[derive(Clone)]
+pub(crate) struct TestCards(pub(crate) Arc<[(String, SimulatedOutcome)]>);
+
+impl TestCards {
+    pub(crate) fn new(mut rules: Vec<(String, SimulatedOutcome)>) -> Self {
+        rules.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        TestCards(rules.into())
+    }
+
+    // Cards starting with 4000 have always been declined for insufficient funds
+    pub(crate) fn default_rules() -> Vec<(String, SimulatedOutcome)> {
+        vec![("4000".to_string(), SimulatedOutcome::InsufficientFunds)]
+    }
+
+    pub(crate) fn outcome(&self, card_number: &str) -> Option<SimulatedOutcome> {
+        self.0
+            .iter()
+            .find(|(prefix, _)| card_number.starts_with(prefix.as_str()))
+            .map(|(_, outcome)| *outcome)
+    }
+
+    // The gateway's answer for a card charge, or None when the card is approved
+    pub(crate) fn decline(&self, card_number: &str, source: &str) -> Option<GatewayCharge> {
+        self.outcome(card_number).map(|outcome| {
+            GatewayCharge::declined(outcome.decline_reason(), &format!("Card declined: {} ({}).", outcome.reason(), source))
+        })
+    }
+}
+
+impl Default for TestCards {
+    fn default() -> Self {
+        TestCards::new(TestCards::default_rules())
+    }
+}
+
+// Talks to the external gateway using the configured API key
+pub(crate) struct RealGateway {
+    pub(crate) client: Client,
+    pub(crate) api_key: GatewayApiKey,
+    pub(crate) url: Option<String>, // GATEWAY_URL; every call is simulated when unset
+    pub(crate) charge_timeout: Duration,
+    pub(crate) test_cards: TestCards, // Drives the simulated charges
+    pub(crate) signing: Option<GatewaySigning>,
+}
+
+#[async_trait]
+impl PaymentGateway for RealGateway {
+    fn name(&self) -> &'static str {
+        "real"
+    }
+
+    async fn charge(
+        &self,
+        data: &PaymentRequest,
+        transaction_uuid: &Uuid,
+        request_id: &str,
+    ) -> Result<GatewayCharge, AppError> {
+        tokio::time::timeout(
+            self.charge_timeout,
+            call_external_payment_gateway(self, data, transaction_uuid, request_id),
+        )
+        .await
+        .unwrap_or(Err(AppError::GatewayTimeout))
+    }
+
+    async fn refund(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        call_external_refund_gateway(self, transaction, amount).await
+    }
+
+    async fn void(&self, transaction: &Transaction) -> Result<String, AppError> {
+        call_external_void_gateway(self, transaction).await
+    }
+
+    async fn capture(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        call_external_capture_gateway(self, transaction, amount).await
+    }
+
+    async fn lookup_charge(&self, transaction: &Transaction) -> Result<Option<GatewayCharge>, AppError> {
+        call_external_lookup_gateway(self, transaction).await
+    }
+
+    async fn tokenize(&self, card: &CardDetails) -> Result<String, AppError> {
+        call_external_tokenize_gateway(self, card).await
+    }
+}
+
+// Fully in-process sandbox: no network, no API key. Cards matching a test card rule and
+// tokens starting with tok_decline are declined.
+#[derive(Default)]
+pub(crate) struct MockGateway {
+    pub(crate) test_cards: TestCards,
+}
+
+#[async_trait]
+impl PaymentGateway for MockGateway {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn charge(
+        &self,
+        data: &PaymentRequest,
+        transaction_uuid: &Uuid,
+        _request_id: &str,
+    ) -> Result<GatewayCharge, AppError> {
+        match &data.instrument {
+            PaymentInstrument::Card(card) if let Some(declined) = self.test_cards.decline(&card.card_number, "Mock") => Ok(declined),
+            // The mock only issues references for approvals, so declines exercise the NULL case
+            PaymentInstrument::Card(_) => Ok(GatewayCharge::new(approved_status(data), "Payment approved (Mock).")
+                .with_gateway_ref(format!("mock_{}", transaction_uuid.simple()))),
+            PaymentInstrument::Token { payment_token } => Ok(simulate_token_charge(payment_token, approved_status(data), "Mock")),
+            PaymentInstrument::SavedMethod { .. } => Err(unresolved_payment_method()),
+        }
+    }
+
+    async fn refund(&self, _transaction: &Transaction, _amount: i64) -> Result<String, AppError> {
+        Ok("Refund approved (Mock).".to_string())
+    }
+
+    async fn void(&self, _transaction: &Transaction) -> Result<String, AppError> {
+        Ok("Authorization voided (Mock).".to_string())
+    }
+
+    async fn capture(&self, _transaction: &Transaction, _amount: i64) -> Result<String, AppError> {
+        Ok("Authorization captured (Mock).".to_string())
+    }
+
+    // The mock answers every charge it receives, so one left pending never reached it
+    async fn lookup_charge(&self, _transaction: &Transaction) -> Result<Option<GatewayCharge>, AppError> {
+        Ok(None)
+    }
+
+    async fn tokenize(&self, _card: &CardDetails) -> Result<String, AppError> {
+        Ok(format!("tok_mock_{}", Uuid::new_v4().simple()))
+    }
+}
+
+// A gateway with its own breaker, so an outage on one doesn't block failover to another
+#[derive(Clone)]
+pub(crate) struct RoutedGateway {
+    pub(crate) gateway: Arc<dyn PaymentGateway>,
+    pub(crate) breaker: CircuitBreaker,
+}
+
+// Currency -> ordered failover chain, with a default chain for everything unrouted
+#[derive(Clone)]
+pub(crate) struct GatewayRouter {
+    pub(crate) default: Vec<RoutedGateway>,
+    pub(crate) by_currency: Arc<HashMap<&'static str, Vec<RoutedGateway>>>,
+}
+
+impl GatewayRouter {
+    // Chains are never empty; Config rejects an empty list
+    pub(crate) fn new(default: Vec<RoutedGateway>) -> Self {
+        GatewayRouter { default, by_currency: Arc::new(HashMap::new()) }
+    }
+
+    pub(crate) fn with_route(mut self, currency: &'static str, chain: Vec<RoutedGateway>) -> Self {
+        Arc::make_mut(&mut self.by_currency).insert(currency, chain);
+        self
+    }
+
+    // Expects a normalized (uppercase) currency code
+    pub(crate) fn route(&self, currency: &str) -> &[RoutedGateway] {
+        self.by_currency.get(currency).unwrap_or(&self.default)
+    }
+
+    // Looks a gateway up by the name recorded on a transaction
+    pub(crate) fn by_name(&self, name: &str) -> Option<&RoutedGateway> {
+        self.all().find(|routed| routed.gateway.name() == name)
+    }
+
+    pub(crate) fn all(&self) -> impl Iterator<Item = &RoutedGateway> {
+        self.default.iter().chain(self.by_currency.values().flatten())
+    }
+
+    // Breaker state per distinct gateway, for the health report
+    pub(crate) fn breaker_states(&self) -> BTreeMap<&'static str, &'static str> {
+        self.all()
+            .map(|routed| (routed.gateway.name(), routed.breaker.state_name()))
+            .collect()
+    }
+}
+
+// Body of a charge sent to the external gateway. Carries the card, so it is never logged.
+#[derive(Debug, Serialize)]
+pub(crate) struct GatewayChargeRequest<'a> {
+    pub(crate) reference: &'a Uuid,
+    pub(crate) amount: i64,
+    pub(crate) currency: &'a str,
+    pub(crate) capture: bool,
+    pub(crate) instrument: &'a PaymentInstrument,
+}
+
+impl GatewaySigning {
+    // Hex MAC of the exact bytes sent, prefixed with the hash so the gateway can tell them apart
+    pub(crate) fn sign(&self, body: &[u8]) -> String {
+        match self.algorithm {
+            GatewaySigningAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+                mac.update(body);
+                format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+            }
+            GatewaySigningAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+                mac.update(body);
+                format!("sha512={}", hex::encode(mac.finalize().into_bytes()))
+            }
+        }
+    }
+
+    // Adds the signature header for `body`
+    pub(crate) fn attach(&self, headers: &mut reqwest::header::HeaderMap, body: &[u8]) {
+        let signature = reqwest::header::HeaderValue::from_str(&self.sign(body)).expect("hex signatures are valid header values");
+        headers.insert(self.header.clone(), signature);
+    }
+}
+
+// Refuses anything that couldn't be sent as a bearer token, so a botched secret never replaces
+// a working key
+pub(crate) const MAX_GATEWAY_API_KEY_LEN: usize = 512;
+
+pub(crate) fn validate_gateway_api_key(key: &str) -> Result<(), AppError> {
+    if key.is_empty() || key.len() > MAX_GATEWAY_API_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(AppError::BadRequest(
+            "INVALID_GATEWAY_KEY",
+            format!("Gateway API key must be 1-{} printable ASCII characters.", MAX_GATEWAY_API_KEY_LEN),
+        ));
+    }
+    Ok(())
+}
+
+// Reads and validates the key in a secret file; surrounding whitespace (a trailing newline) is dropped
+pub(crate) fn read_gateway_api_key(path: &Path) -> Result<String, AppError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        AppError::EnvironmentError(format!("Failed to read gateway API key from {}: {}", path.display(), e))
+    })?;
+    let key = contents.trim();
+    validate_gateway_api_key(key)?;
+    Ok(key.to_string())
+}
+
+impl GatewayApiKey {
+    pub(crate) fn new(key: &str) -> Self {
+        GatewayApiKey { current: Arc::new(RwLock::new(key.into())), source: None }
+    }
+
+    pub(crate) fn with_source(mut self, source: Option<&Path>) -> Self {
+        self.source = source.map(Into::into);
+        self
+    }
+
+    pub(crate) fn current(&self) -> Arc<str> {
+        self.current.read().expect("gateway key lock poisoned").clone()
+    }
+
+    // Re-reads the secret file and swaps the key in once it validates; calls already under way
+    // finish with the key they started with. Returns whether the key changed.
+    pub(crate) fn reload(&self) -> Result<bool, AppError> {
+        let source = self.source.as_deref().ok_or_else(|| {
+            AppError::BadRequest(
+                "GATEWAY_KEY_SOURCE_NOT_CONFIGURED",
+                "Set PAYMENT_GATEWAY_API_KEY_FILE to rotate the gateway key at runtime.".to_string(),
+            )
+        })?;
+        let key = read_gateway_api_key(source)?;
+
+        let mut current = self.current.write().expect("gateway key lock poisoned");
+        let changed = **current != *key;
+        *current = key.into();
+        Ok(changed)
+    }
+}
+
+// The external gateway's answer to a charge
+#[derive(Debug, Deserialize)]
+pub(crate) struct GatewayChargeReply {
+    pub(crate) status: GatewayChargeStatus,
+    pub(crate) message: String,
+    #[serde(default)]
+    pub(crate) gateway_ref: Option<String>,
+    #[serde(default)]
+    pub(crate) decline_reason: Option<DeclineReason>,
+    #[serde(default)]
+    pub(crate) masked_card_number: Option<String>, // Token charges only
+    #[serde(default)]
+    pub(crate) card_brand: Option<CardBrand>,
+    #[serde(default)]
+    pub(crate) captured: Option<bool>, // Lookups only; an approval without it was captured
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GatewayChargeStatus {
+    Approved,
+    Declined,
+}
+
+impl GatewayChargeReply {
+    // `approved` is what an approval means: for a charge, what we asked for; for a lookup,
+    // what the gateway reports
+    pub(crate) fn into_charge(self, approved: TransactionStatus) -> GatewayCharge {
+        let charge = match self.status {
+            GatewayChargeStatus::Approved => GatewayCharge::new(approved, &self.message),
+            GatewayChargeStatus::Declined => {
+                GatewayCharge::declined(self.decline_reason.unwrap_or(DeclineReason::DoNotHonor), &self.message)
+            }
+        };
+
+        GatewayCharge {
+            gateway_ref: self.gateway_ref,
+            masked_card_number: self.masked_card_number,
+            card_brand: self.card_brand,
+            ..charge
+        }
+    }
+}
+
+pub(crate) const GATEWAY_CHARGES_PATH: &str = "charges";
+pub(crate) const GATEWAY_TOKENS_PATH: &str = "tokens";
+
+// POSTs `body` to `<GATEWAY_URL>/<path>`. Errors before any answer and 5xx answers are
+// GatewayErrors; a 4xx means the gateway refused the request itself, so it is rejected outright
+// rather than resent. Reading the answer is left to the caller.
+pub(crate) async fn post_to_gateway(
+    client: &Client,
+    url: &str,
+    path: &str,
+    api_key: &str,
+    headers: reqwest::header::HeaderMap,
+    body: Vec<u8>,
+) -> Result<reqwest::Response, AppError> {
+    let response = client
+        .post(format!("{}/{}", url.trim_end_matches('/'), path))
+        .bearer_auth(api_key)
+        .header(header::CONTENT_TYPE, "application/json")
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| if e.is_timeout() { AppError::GatewayTimeout } else { AppError::from(e) })?;
+
+    let status = response.status();
+    if status.is_client_error() {
+        return Err(AppError::GatewayRejected(format!("External gateway refused the request with {}.", status)));
+    }
+    if !status.is_success() {
+        return Err(AppError::GatewayError(format!("External gateway answered {}.", status)));
+    }
+    Ok(response)
+}
+
+// POSTs a charge to `<GATEWAY_URL>/charges`. Our transaction UUID is the Idempotency-Key, so a
+// retried attempt is deduplicated by the gateway. A timeout or a 2xx whose body can't be read
+// may have charged the card, so those are reported as outcome unknown.
+pub(crate) async fn post_gateway_charge(
+    client: &Client,
+    url: &str,
+    api_key: &str,
+    mut headers: reqwest::header::HeaderMap,
+    body: Vec<u8>,
+    transaction_uuid: &Uuid,
+) -> Result<GatewayChargeReply, AppError> {
+    let idempotency_key = reqwest::header::HeaderValue::from_str(&transaction_uuid.to_string())
+        .expect("UUIDs are valid header values");
+    headers.insert("Idempotency-Key", idempotency_key);
+    let response = post_to_gateway(client, url, GATEWAY_CHARGES_PATH, api_key, headers, body).await?;
+
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| AppError::GatewayAmbiguous(format!("External gateway response was cut off: {}", e)))?;
+    serde_json::from_slice(&bytes)
+        .map_err(|e| AppError::GatewayAmbiguous(format!("Malformed external gateway response: {}", e)))
+}
+
+// Body of a refund or capture on an existing charge
+#[derive(Debug, Serialize)]
+pub(crate) struct GatewayAmountRequest<'a> {
+    pub(crate) amount: i64,
+    pub(crate) currency: &'a str,
+}
+
+// The gateway's answer to a refund, void or capture
+#[derive(Debug, Deserialize)]
+pub(crate) struct GatewayOperationReply {
+    pub(crate) message: String,
+}
+
+// The gateway's answer to tokenizing a card
+#[derive(Debug, Deserialize)]
+pub(crate) struct GatewayTokenReply {
+    pub(crate) token: String,
+}
+
+// Signed like charges, and sent with the request's trace context and X-Request-Id
+pub(crate) fn gateway_request_headers(gateway: &RealGateway, body: &[u8], request_id: Option<&str>) -> reqwest::header::HeaderMap {
+    let mut headers = trace_context_headers();
+    if let Some(signing) = &gateway.signing {
+        signing.attach(&mut headers, body);
+    }
+    if let Some(value) = request_id.and_then(|id| reqwest::header::HeaderValue::from_str(id).ok()) {
+        headers.insert(REQUEST_ID_HEADER, value);
+    }
+    headers
+}
+
+// POSTs a refund, void or capture on `transaction` to `<GATEWAY_URL>/charges/<reference>/<operation>`.
+// The 2xx is the gateway's decision, so a body that can't be read only loses its message.
+pub(crate) async fn post_gateway_operation(
+    gateway: &RealGateway,
+    url: &str,
+    api_key: &str,
+    transaction: &Transaction,
+    operation: &str,
+    body: Vec<u8>,
+    accepted: &str,
+) -> Result<String, AppError> {
+    let path = format!("{}/{}/{}", GATEWAY_CHARGES_PATH, charge_reference(transaction), operation);
+    let request_id = CURRENT_REQUEST_ID.try_with(Clone::clone).ok();
+    let headers = gateway_request_headers(gateway, &body, request_id.as_deref());
+    let response = post_to_gateway(&gateway.client, url, &path, api_key, headers, body).await?;
+
+    Ok(response
+        .json::<GatewayOperationReply>()
+        .await
+        .map_or_else(|_| accepted.to_string(), |reply| reply.message))
+}
+
+pub(crate) async fn call_external_payment_gateway(
+    gateway: &RealGateway,
+    data: &PaymentRequest, 
+    transaction_uuid: &Uuid,
+    request_id: &str,
+) -> Result<GatewayCharge, AppError> { 
+    
+    let api_key = gateway.api_key.current();
+    if api_key.is_empty() {
+        return Err(AppError::EnvironmentError("API Key is missing.".to_string()));
+    }
+
+    let body = gateway_request_body(&GatewayChargeRequest {
+        reference: transaction_uuid,
+        amount: data.amount,
+        currency: &data.currency,
+        capture: data.capture,
+        instrument: &data.instrument,
+    })?;
+    let headers = gateway_request_headers(gateway, &body, Some(request_id));
+    debug!(
+        request_id,
+        traceparent = ?headers.get("traceparent"),
+        signed = gateway.signing.is_some(),
+        simulated = gateway.url.is_none(),
+        "calling external gateway"
+    );
+
+    if let Some(url) = &gateway.url {
+        let reply = post_gateway_charge(&gateway.client, url, &api_key, headers, body, transaction_uuid).await?;
+        info!(status = ?reply.status, gateway_ref = ?reply.gateway_ref, "external gateway call successful");
+        return Ok(reply.into_charge(approved_status(data)));
+    }
+
+    let card = match &data.instrument {
+        PaymentInstrument::Card(card) => card,
+        PaymentInstrument::Token { payment_token } => {
+            return Ok(simulate_token_charge(payment_token, approved_status(data), "Simulation"));
+        }
+        PaymentInstrument::SavedMethod { .. } => return Err(unresolved_payment_method()),
+    };
+    
+    // Simulation Rule: test cards are declined as configured
+    if let Some(declined) = gateway.test_cards.decline(&card.card_number, "Simulation") {
+        return Ok(declined);
+    }
+    
+    info!("external gateway call successful");
+
+    // Simulated authorization ID, as a real gateway would return in its response body
+    Ok(GatewayCharge::new(approved_status(data), "Payment successfully processed by external gateway.")
+        .with_gateway_ref(format!("ch_{}", transaction_uuid.simple())))
+}
+
+pub(crate) fn gateway_request_body(body: &impl Serialize) -> Result<Vec<u8>, AppError> {
+    serde_json::to_vec(body)
+        .map_err(|e| AppError::InternalServerError(format!("Failed to serialize gateway request: {}", e)))
+}
+
+// W3C trace context for the current span, so the callee's spans join our trace.
+// Empty unless OpenTelemetry export is enabled.
+pub(crate) fn trace_context_headers() -> reqwest::header::HeaderMap {
+    struct HeaderInjector<'a>(&'a mut reqwest::header::HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(name), Ok(value)) = (
+                reqwest::header::HeaderName::from_bytes(key.as_bytes()),
+                reqwest::header::HeaderValue::from_str(&value),
+            ) {
+                self.0.insert(name, value);
+            }
+        }
+    }
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers));
+    });
+    headers
+}
+
+// Saved methods are swapped for their gateway token before any gateway sees the request
+pub(crate) fn unresolved_payment_method() -> AppError {
+    AppError::InternalServerError("Saved payment method was not resolved before charging.".to_string())
+}
+
+// Token charges: the gateway resolves the token and reports the masked card it maps to
+pub(crate) fn simulate_token_charge(payment_token: &str, approved: TransactionStatus, source: &str) -> GatewayCharge {
+    let charge = if payment_token.starts_with("tok_decline") {
+        GatewayCharge::declined(DeclineReason::DoNotHonor, &format!("Card declined: Do not honor ({}).", source))
+    } else {
+        GatewayCharge::new(approved, &format!("Token payment approved ({}).", source))
+    };
+
+    GatewayCharge {
+        masked_card_number: Some("XXXX-XXXX-XXXX-4242".to_string()),
+        card_brand: Some(CardBrand::Visa),
+        ..charge
+    }
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            state: Arc::new(Mutex::new(BreakerState::Closed { consecutive_failures: 0 })),
+        }
+    }
+
+    // Whether a call may go through; moves Open -> HalfOpen once the cooldown has passed
+    pub(crate) fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().expect("circuit breaker mutex poisoned");
+        let now = Instant::now();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } | BreakerState::HalfOpen { probe_deadline: until } if now >= until => {
+                *state = BreakerState::HalfOpen { probe_deadline: now + self.cooldown };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub(crate) fn record_success(&self) {
+        *self.state.lock().expect("circuit breaker mutex poisoned") =
+            BreakerState::Closed { consecutive_failures: 0 };
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let mut state = self.state.lock().expect("circuit breaker mutex poisoned");
+        let failures = match *state {
+            BreakerState::Closed { consecutive_failures } => consecutive_failures + 1,
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => self.failure_threshold,
+        };
+
+        *state = if failures >= self.failure_threshold {
+            warn!(cooldown = ?self.cooldown, "gateway circuit breaker opened");
+            BreakerState::Open { until: Instant::now() + self.cooldown }
+        } else {
+            BreakerState::Closed { consecutive_failures: failures }
+        };
+    }
+
+    pub(crate) fn state_name(&self) -> &'static str {
+        match *self.state.lock().expect("circuit breaker mutex poisoned") {
+            BreakerState::Closed { .. } => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+// Failures that say the gateway is unhealthy, and so count against its breaker
+pub(crate) fn is_gateway_failure(err: &AppError) -> bool {
+    matches!(err, AppError::GatewayError(_) | AppError::GatewayTimeout | AppError::GatewayAmbiguous(_))
+}
+
+// Only failures where the charge cannot have landed are retried or failed over. A decline
+// is an Ok result, a timeout is left for reconciliation rather than sent again, and a rejected
+// request is final: resent, it is refused again or charged by the next gateway.
+pub(crate) fn is_retryable(err: &AppError) -> bool {
+    matches!(err, AppError::GatewayError(_))
+}
+
+// Charge with retries. The same transaction UUID is sent on every attempt so the
+// gateway can deduplicate.
+pub(crate) async fn call_gateway_with_retry(
+    state: &AppState,
+    routed: &RoutedGateway,
+    data: &PaymentRequest,
+    transaction_uuid: &Uuid,
+    request_id: &str,
+) -> Result<GatewayCharge, AppError> {
+    let gateway = routed.gateway.as_ref();
+    if !routed.breaker.try_acquire() {
+        return Err(AppError::GatewayError("Gateway temporarily unavailable".to_string()));
+    }
+
+    let policy = state.gateway_retry;
+    let mut delay = policy.base_delay;
+    let mut attempt = 1;
+
+    let result = loop {
+        let started = Instant::now();
+        let attempt_result = gateway.charge(data, transaction_uuid, request_id).await;
+        metrics::histogram!("gateway_call_duration_seconds", "gateway" => gateway.name())
+            .record(started.elapsed().as_secs_f64());
+
+        match attempt_result {
+            Err(err) if is_retryable(&err) && attempt < policy.max_attempts => {
+                warn!(attempt, max_attempts = policy.max_attempts, error = %err, retry_in = ?delay,
+                    "gateway attempt failed, retrying");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => break result,
+        }
+    };
+
+    match &result {
+        Err(err) if is_gateway_failure(err) => routed.breaker.record_failure(),
+        _ => routed.breaker.record_success(),
+    }
+
+    result
+}
+
+// Walk the failover chain. Only availability errors move on to the next gateway; a
+// decline is final, and so is a timeout, since the charge may have landed on the
+// gateway that timed out. Returns the name of the gateway that produced the result.
+pub(crate) async fn charge_with_failover(
+    state: &AppState,
+    chain: &[RoutedGateway],
+    data: &PaymentRequest,
+    transaction_uuid: &Uuid,
+    request_id: &str,
+) -> (&'static str, Result<GatewayCharge, AppError>) {
+    let mut last = None;
+
+    for (position, routed) in chain.iter().enumerate() {
+        let name = routed.gateway.name();
+        let result = call_gateway_with_retry(state, routed, data, transaction_uuid, request_id).await;
+
+        match result {
+            Err(err) if is_retryable(&err) && position + 1 < chain.len() => {
+                warn!(gateway = name, error = %err, "gateway unavailable, failing over");
+                metrics::counter!("gateway_failovers_total", "gateway" => name).increment(1);
+                last = Some((name, Err(err)));
+            }
+            result => return (name, result),
+        }
+    }
+
+    last.expect("gateway chains are never empty")
+}
+
+// Follow-up calls are keyed on the gateway's charge ID; rows from before it was stored fall back to our UUID
+pub(crate) fn charge_reference(transaction: &Transaction) -> String {
+    transaction
+        .gateway_ref
+        .clone()
+        .unwrap_or_else(|| transaction.transaction_uuid.to_string())
+}
+
+pub(crate) async fn call_external_refund_gateway(
+    gateway: &RealGateway,
+    transaction: &Transaction,
+    amount: i64,
+) -> Result<String, AppError> {
+    let api_key = gateway.api_key.current();
+    if api_key.is_empty() {
+        return Err(AppError::EnvironmentError("API Key is missing.".to_string()));
+    }
+
+    let charge_reference = charge_reference(transaction);
+    let accepted = "Refund successfully processed by external gateway.";
+    let message = match &gateway.url {
+        Some(url) => {
+            let body = gateway_request_body(&GatewayAmountRequest { amount, currency: &transaction.currency })?;
+            post_gateway_operation(gateway, url, &api_key, transaction, "refunds", body, accepted).await?
+        }
+        None => accepted.to_string(),
+    };
+
+    info!(amount, currency = %transaction.currency, charge_reference, "external refund call successful");
+
+    Ok(message)
+}
+
+pub(crate) async fn call_external_tokenize_gateway(
+    gateway: &RealGateway,
+    card: &CardDetails,
+) -> Result<String, AppError> {
+    let api_key = gateway.api_key.current();
+    if api_key.is_empty() {
+        return Err(AppError::EnvironmentError("API Key is missing.".to_string()));
+    }
+
+    let Some(url) = &gateway.url else {
+        info!("external tokenize call successful");
+        return Ok(format!("tok_{}", Uuid::new_v4().simple()));
+    };
+
+    // Carries the card, so like a charge it is never logged
+    let body = gateway_request_body(card)?;
+    let request_id = CURRENT_REQUEST_ID.try_with(Clone::clone).ok();
+    let headers = gateway_request_headers(gateway, &body, request_id.as_deref());
+    let reply: GatewayTokenReply = post_to_gateway(&gateway.client, url, GATEWAY_TOKENS_PATH, &api_key, headers, body)
+        .await?
+        .json()
+        .await
+        .map_err(|e| AppError::GatewayError(format!("Malformed external gateway response: {}", e)))?;
+
+    info!("external tokenize call successful");
+
+    Ok(reply.token)
+}
+
+pub(crate) async fn call_external_void_gateway(
+    gateway: &RealGateway,
+    transaction: &Transaction,
+) -> Result<String, AppError> {
+    let api_key = gateway.api_key.current();
+    if api_key.is_empty() {
+        return Err(AppError::EnvironmentError("API Key is missing.".to_string()));
+    }
+
+    let charge_reference = charge_reference(transaction);
+    let accepted = "Authorization voided by external gateway; no funds were captured.";
+    let message = match &gateway.url {
+        Some(url) => post_gateway_operation(gateway, url, &api_key, transaction, "void", b"{}".to_vec(), accepted).await?,
+        None => accepted.to_string(),
+    };
+
+    info!(charge_reference, "external void call successful");
+
+    Ok(message)
+}
+
+pub(crate) async fn call_external_capture_gateway(
+    gateway: &RealGateway,
+    transaction: &Transaction,
+    amount: i64,
+) -> Result<String, AppError> {
+    let api_key = gateway.api_key.current();
+    if api_key.is_empty() {
+        return Err(AppError::EnvironmentError("API Key is missing.".to_string()));
+    }
+
+    let charge_reference = charge_reference(transaction);
+    let accepted = "Authorization captured by external gateway.";
+    let message = match &gateway.url {
+        Some(url) => {
+            let body = gateway_request_body(&GatewayAmountRequest { amount, currency: &transaction.currency })?;
+            post_gateway_operation(gateway, url, &api_key, transaction, "capture", body, accepted).await?
+        }
+        None => accepted.to_string(),
+    };
+
+    info!(amount, currency = %transaction.currency, charge_reference, "external capture call successful");
+
+    Ok(message)
+}
+
+// GETs `<GATEWAY_URL>/charges/<reference>`. Only the gateway's 404 says the charge never
+// reached it; any other failure is an error, so the row is left for the next scan. Simulated
+// calls keep no record to ask, so they can't look charges up at all.
+pub(crate) async fn call_external_lookup_gateway(
+    gateway: &RealGateway,
+    transaction: &Transaction,
+) -> Result<Option<GatewayCharge>, AppError> {
+    let api_key = gateway.api_key.current();
+    if api_key.is_empty() {
+        return Err(AppError::EnvironmentError("API Key is missing.".to_string()));
+    }
+
+    // A charge that landed has a gateway reference to look up by; without one, our UUID
+    let charge_reference = charge_reference(transaction);
+    let Some(url) = &gateway.url else {
+        return Err(AppError::GatewayError(format!(
+            "Simulated gateway can't look up charge {}; set GATEWAY_URL.",
+            charge_reference
+        )));
+    };
+
+    let response = gateway
+        .client
+        .get(format!("{}/{}/{}", url.trim_end_matches('/'), GATEWAY_CHARGES_PATH, charge_reference))
+        .bearer_auth(&*api_key)
+        .headers(gateway_request_headers(gateway, b"", None))
+        .send()
+        .await?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        info!(charge_reference, "external charge lookup found no charge");
+        return Ok(None);
+    }
+    if !status.is_success() {
+        return Err(AppError::GatewayError(format!("External gateway answered {} to a charge lookup.", status)));
+    }
+    let reply: GatewayChargeReply = response
+        .json()
+        .await
+        .map_err(|e| AppError::GatewayError(format!("Malformed external gateway response: {}", e)))?;
+
+    info!(charge_reference, status = ?reply.status, "external charge lookup found the charge");
+    let approved = if reply.captured == Some(false) { TransactionStatus::Authorized } else { TransactionStatus::Success };
+    Ok(Some(reply.into_charge(approved)))
+}
+
+// Merchant webhooks: fire-and-forget, signed, retried with backoff
+
+pub(crate) const WEBHOOK_SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+// Body of every webhook POST
+#[derive(Debug, Serialize)]
+pub(crate) struct PaymentEvent {
+    pub(crate) transaction_uuid: Uuid,
+    pub(crate) status: TransactionStatus,
+    pub(crate) amount: i64,
+    pub(crate) currency: String,
+    pub(crate) timestamp: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub(crate) struct WebhookNotifier {
+    pub(crate) client: Client,
+    pub(crate) url: Arc<str>,
+    pub(crate) secret: Arc<[u8]>,
+    pub(crate) max_attempts: u32,
+}
+
+// Hex HMAC-SHA256 of the exact bytes sent, so receivers can verify before parsing
+pub(crate) fn webhook_signature(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl WebhookNotifier {
+    // Delivery runs in its own task so the payment response never waits on the merchant
+    pub(crate) fn notify(&self, event: PaymentEvent) {
+        let notifier = self.clone();
+        tokio::spawn(async move { notifier.deliver(event).await }.in_current_span());
+    }
+
+    pub(crate) async fn deliver(&self, event: PaymentEvent) {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(error = %e, "failed to serialize webhook event");
+                return;
+            }
+        };
+        let signature = webhook_signature(&self.secret, &body);
+        let mut delay = WEBHOOK_RETRY_BASE_DELAY;
+
+        for attempt in 1..=self.max_attempts {
+            let result = self
+                .client
+                .post(&*self.url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(WEBHOOK_SIGNATURE_HEADER, &signature)
+                .headers(trace_context_headers())
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => {
+                    debug!(attempt, transaction_uuid = %event.transaction_uuid, "webhook delivered");
+                    return;
+                }
+                Err(e) if attempt < self.max_attempts => {
+                    warn!(attempt, error = %e, retry_in = ?delay, "webhook delivery failed, retrying");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    error!(attempts = attempt, error = %e, transaction_uuid = %event.transaction_uuid,
+                        "webhook delivery gave up");
+                }
+            }
+        }
+    }
+}
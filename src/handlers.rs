@@ -0,0 +1,2362 @@
+// --- HANDLER FUNCTIONS ---
+
+use axum::{
+    body::Bytes,
+    extract::{rejection::{PathRejection, QueryRejection}, Extension, Json, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response},
+};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, Semaphore};
+use futures_util::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::Ordering,
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use sqlx::PgPool;
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::Client;
+use tracing::{error, info, warn, Instrument};
+use rust_decimal::Decimal;
+
+use crate::{config::*, db::*, errors::*, gateway::*, middleware::*, models::*, validation::*};
+
+// Source of FX rates: every currency's rate against `base`
+#[async_trait]
+pub(crate) trait FxRateProvider: Send + Sync {
+    async fn rates(&self, base: &str) -> Result<HashMap<String, Decimal>, AppError>;
+}
+
+// GET {FX_RATES_URL}?base=USD answering {"rates": {"EUR": 0.92, ...}}
+pub(crate) struct HttpFxRateProvider {
+    pub(crate) client: Client,
+    pub(crate) url: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct FxRatesBody {
+    pub(crate) rates: HashMap<String, Decimal>,
+}
+
+#[async_trait]
+impl FxRateProvider for HttpFxRateProvider {
+    async fn rates(&self, base: &str) -> Result<HashMap<String, Decimal>, AppError> {
+        let unavailable = |e: reqwest::Error| AppError::GatewayError(format!("FX rate provider unavailable: {}", e));
+
+        let body: FxRatesBody = self
+            .client
+            .get(&self.url)
+            .query(&[("base", base)])
+            .headers(trace_context_headers())
+            .timeout(FX_RATES_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+
+        Ok(body.rates)
+    }
+}
+
+pub(crate) type FxRates = Arc<HashMap<String, Decimal>>;
+
+// Rates cached per base currency for `ttl`, so charges don't each call the provider
+#[derive(Clone)]
+pub(crate) struct FxConverter {
+    pub(crate) provider: Arc<dyn FxRateProvider>,
+    pub(crate) ttl: Duration,
+    pub(crate) rounding: RoundingMode,
+    pub(crate) cache: Arc<Mutex<HashMap<&'static str, (Instant, FxRates)>>>, // base -> (fetched at, rates)
+}
+
+impl FxConverter {
+    pub(crate) fn new(provider: impl FxRateProvider + 'static, ttl: Duration, rounding: RoundingMode) -> Self {
+        FxConverter { provider: Arc::new(provider), ttl, rounding, cache: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // A failure is a GatewayError: charging the unconverted amount is never an option
+    pub(crate) async fn rate(&self, from: Currency, to: Currency) -> Result<Decimal, AppError> {
+        let cached = self
+            .cache
+            .lock()
+            .expect("FX rate cache mutex poisoned")
+            .get(from.code())
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < self.ttl)
+            .map(|(_, rates)| rates.clone());
+
+        let rates = match cached {
+            Some(rates) => rates,
+            None => {
+                let rates = Arc::new(self.provider.rates(from.code()).await?);
+                self.cache.lock().expect("FX rate cache mutex poisoned").insert(from.code(), (Instant::now(), rates.clone()));
+                rates
+            }
+        };
+
+        rates
+            .get(to.code())
+            .copied()
+            .filter(|rate| rate.is_sign_positive() && !rate.is_zero())
+            .ok_or_else(|| AppError::GatewayError(format!(
+                "FX rate provider has no rate for {}/{}.", from.code(), to.code()
+            )))
+    }
+}
+
+pub(crate) fn record_payment_metric(currency: &str, gateway: &'static str, status: &'static str) {
+    metrics::counter!(
+        "payments_total",
+        "currency" => currency.to_string(),
+        "gateway" => gateway,
+        "status" => status
+    )
+    .increment(1);
+}
+
+impl DeclineRate {
+    pub(crate) fn new(window: Duration) -> Self {
+        DeclineRate { window, buckets: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    // One gateway answer: a decline, or an approval (charge or authorization)
+    pub(crate) fn record(&self, declined: bool) {
+        self.record_at(Instant::now(), declined);
+    }
+
+    pub(crate) fn record_at(&self, now: Instant, declined: bool) {
+        let width = self.window / DECLINE_RATE_BUCKETS;
+        let mut buckets = self.buckets.lock().expect("decline rate mutex poisoned");
+        match buckets.back_mut() {
+            Some((start, total, declines)) if now.duration_since(*start) < width => {
+                *total += 1;
+                *declines += u64::from(declined);
+            }
+            _ => buckets.push_back((now, 1, u64::from(declined))),
+        }
+        Self::prune(&mut buckets, now, self.window);
+    }
+
+    // (outcomes, declines) within the window ending at `now`
+    pub(crate) fn counts_at(&self, now: Instant) -> (u64, u64) {
+        let mut buckets = self.buckets.lock().expect("decline rate mutex poisoned");
+        Self::prune(&mut buckets, now, self.window);
+        buckets.iter().fold((0, 0), |(total, declines), (_, t, d)| (total + t, declines + d))
+    }
+
+    // Declines / outcomes; None when nothing was charged in the window
+    pub(crate) fn rate(total: u64, declines: u64) -> Option<f64> {
+        (total > 0).then(|| declines as f64 / total as f64)
+    }
+
+    fn prune(buckets: &mut VecDeque<(Instant, u64, u64)>, now: Instant, window: Duration) {
+        while buckets.front().is_some_and(|(start, _, _)| now.duration_since(*start) >= window) {
+            buckets.pop_front();
+        }
+    }
+}
+
+// Only called once the outcome is persisted, so the merchant can fetch what we report
+pub(crate) fn notify_payment(state: &AppState, transaction_uuid: Uuid, status: TransactionStatus, data: &PaymentRequest) {
+    if let Some(webhooks) = &state.webhooks {
+        webhooks.notify(PaymentEvent {
+            transaction_uuid,
+            status,
+            amount: data.amount,
+            currency: data.currency.clone(),
+            timestamp: Utc::now(),
+        });
+    }
+}
+
+// Tell open status streams; nobody listening is not an error
+pub(crate) fn publish_status(state: &AppState, transaction_uuid: Uuid, status: TransactionStatus) {
+    let _ = state.status_updates.send(StatusUpdate { transaction_uuid, status });
+}
+
+// Placeholder for token charges until the gateway tells us which card the token maps to
+pub(crate) const TOKEN_PENDING_MASK: &str = "XXXX-XXXX-XXXX-XXXX";
+// Written over the masked card of anonymized transactions; even the last four digits go
+pub(crate) const ANONYMIZED_CARD_MASK: &str = "XXXX-XXXX-XXXX-XXXX";
+
+// Too many recent charges on one card is a classic card-testing signal. Only charges that
+// went through count; declines and failures do not.
+pub(crate) async fn check_velocity(db: &PgPool, limit: VelocityLimit, fingerprint: &str) -> Result<(), AppError> {
+    let since = Utc::now() - limit.window;
+
+    let recent_charges = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM transactions
+        WHERE card_fingerprint = $1
+          AND created_at > $2
+          AND status IN ('success', 'authorized', 'refunded', 'partially_refunded')
+        "#,
+        fingerprint,
+        since
+    )
+    .fetch_one(db)
+    .await?;
+
+    if recent_charges >= limit.max_charges {
+        warn!(recent_charges, "velocity limit exceeded");
+        return Err(AppError::BadRequest(
+            "VELOCITY_LIMIT_EXCEEDED",
+            "Velocity limit exceeded for this card; try again later.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// The span carries only masked card data; the PAN and CVV are never recorded
+#[utoipa::path(
+    post,
+    path = "/api/v1/payment",
+    request_body = PaymentRequest,
+    params(("Idempotency-Key" = Option<String>, Header, description = "Replays the stored response for a retried request")),
+    responses(
+        (status = 200, description = "Charge attempted; `success` reports the gateway outcome", body = PaymentResponse),
+        (status = 400, description = "Validation failed, or the idempotency key was reused with a different request", body = ErrorBody),
+        (status = 409, description = "The first request with this idempotency key has no outcome yet", body = ErrorBody),
+        (status = 502, description = "Gateway unavailable", body = ErrorBody),
+        (status = 503, description = "Too many payments in flight; retry after `Retry-After` seconds", body = ErrorBody),
+        (status = 504, description = "Gateway timed out; the transaction stays pending", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn process_payment(
+    State(state): State<AppState>,
+    _role: ChargeRole,
+    Extension(request_id): Extension<RequestId>,
+    Extension(merchant): Extension<MerchantId>,
+    headers: HeaderMap,
+    Json(payment_data): Json<PaymentRequest>,
+) -> Result<Json<PaymentResponse>, AppError> {
+    // Shed load rather than queue it: a waiting request would only pile onto the gateway and DB
+    let _slot = state.payment_slots.try_acquire().map_err(|_| {
+        metrics::counter!("payments_shed_total").increment(1);
+        AppError::ServiceUnavailable("Too many payments in progress; retry shortly.".to_string())
+    })?;
+    let idempotency_key = idempotency_key_from_headers(&headers)?;
+    let origin = ChargeOrigin { request_id: &request_id.0, merchant, idempotency_key, subscription_id: None };
+
+    charge_payment(&state, origin, payment_data).await.map(|response| Json(response.localized()))
+}
+
+// A batch is capped so one request can't hold the gateway for long; items run a few at a time
+pub(crate) const MAX_BATCH_SIZE: usize = 100;
+pub(crate) const BATCH_CONCURRENCY: usize = 8;
+
+// Handler for POST /api/v1/payments/batch: charges each item as POST /payment would. Up to
+// BATCH_CONCURRENCY items are in flight at once, results keep request order, and partial
+// failure is allowed: an item that is malformed, declined or fails only affects its own result.
+// With an Idempotency-Key, item `i` is keyed `<key>:<i>` so a retried batch replays each item.
+#[utoipa::path(
+    post,
+    path = "/api/v1/payments/batch",
+    request_body = Vec<PaymentRequest>,
+    params(("Idempotency-Key" = Option<String>, Header, description = "Applied per item as `<key>:<index>`")),
+    responses(
+        (status = 200, description = "Every item was attempted; each result reports its own outcome", body = BatchPaymentResponse),
+        (status = 400, description = "Empty batch, or more than 100 items", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn process_payment_batch(
+    State(state): State<AppState>,
+    _role: ChargeRole,
+    Extension(request_id): Extension<RequestId>,
+    Extension(merchant): Extension<MerchantId>,
+    headers: HeaderMap,
+    Json(items): Json<Vec<serde_json::Value>>,
+) -> Result<Json<BatchPaymentResponse>, AppError> {
+    if items.is_empty() || items.len() > MAX_BATCH_SIZE {
+        return Err(AppError::BadRequest(
+            "INVALID_BATCH",
+            format!("A batch must contain between 1 and {} payments.", MAX_BATCH_SIZE),
+        ));
+    }
+    let idempotency_key = idempotency_key_from_headers(&headers)?;
+
+    let charge_item = |(index, item): (usize, serde_json::Value)| {
+        let (state, request_id) = (&state, &request_id.0);
+        let idempotency_key = idempotency_key.as_ref().map(|key| format!("{}:{}", key, index));
+        async move {
+            let outcome = async {
+                let payment_data = serde_json::from_value::<PaymentRequest>(item)
+                    .map_err(|err| AppError::BadRequest("INVALID_PAYMENT_REQUEST", format!("Invalid payment request: {}", err)))?;
+                let _slot = state.payment_slots.try_acquire().map_err(|_| {
+                    metrics::counter!("payments_shed_total").increment(1);
+                    AppError::ServiceUnavailable("Too many payments in progress; retry shortly.".to_string())
+                })?;
+                let origin = ChargeOrigin { request_id, merchant, idempotency_key, subscription_id: None };
+                charge_payment(state, origin, payment_data).await
+            }
+            .await;
+
+            match outcome {
+                Ok(response) => BatchPaymentResult { index, status: StatusCode::OK.as_u16(), payment: Some(response.localized()), error: None },
+                Err(err) => {
+                    let (status, error) = err.into_status_and_detail();
+                    BatchPaymentResult { index, status: status.as_u16(), payment: None, error: Some(error) }
+                }
+            }
+        }
+    };
+
+    let results = stream::iter(items.into_iter().enumerate())
+        .map(charge_item)
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    Ok(Json(BatchPaymentResponse { results }))
+}
+
+// Handler for POST /api/v1/payment/validate: a dry run of POST /payment's field checks. Nothing
+// is written and no gateway is called, so velocity limits and FX conversion aren't exercised.
+#[utoipa::path(
+    post,
+    path = "/api/v1/payment/validate",
+    request_body = PaymentRequest,
+    responses(
+        (status = 200, description = "The request would pass validation", body = PaymentValidation),
+        (status = 400, description = "Validation failed; every failed field is listed", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn validate_payment(
+    State(state): State<AppState>,
+    _role: ChargeRole,
+    Json(mut payment_data): Json<PaymentRequest>,
+) -> Result<Json<PaymentValidation>, AppError> {
+    validate_payment_request(&state.amount_limits, &mut payment_data)?;
+
+    Ok(Json(PaymentValidation { valid: true }))
+}
+
+// Handler for POST /api/v1/payment/async: records the payment as Pending, queues it for a
+// worker and answers straight away
+#[utoipa::path(
+    post,
+    path = "/api/v1/payment/async",
+    request_body = PaymentRequest,
+    params(("Idempotency-Key" = Option<String>, Header, description = "Replays the stored response for a retried request")),
+    responses(
+        (status = 202, description = "Queued; poll the transaction for its outcome", body = AcceptedPayment),
+        (status = 200, description = "Retried request; the stored outcome is replayed", body = PaymentResponse),
+        (status = 400, description = "Validation failed, or the idempotency key was reused with a different request", body = ErrorBody),
+        (status = 409, description = "The first request with this idempotency key has no outcome yet", body = ErrorBody),
+        (status = 503, description = "Queue full; retry shortly", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn process_payment_async(
+    State(state): State<AppState>,
+    _role: ChargeRole,
+    Extension(request_id): Extension<RequestId>,
+    Extension(merchant): Extension<MerchantId>,
+    headers: HeaderMap,
+    Json(payment_data): Json<PaymentRequest>,
+) -> Result<Response, AppError> {
+    // Claimed before anything is recorded, so a full queue turns the payment away untouched
+    let queue_slot = state
+        .payment_queue
+        .clone()
+        .try_reserve_owned()
+        .map_err(|_| AppError::ServiceUnavailable("Payment queue is full; retry shortly.".to_string()))?;
+
+    let idempotency_key = idempotency_key_from_headers(&headers)?;
+    let origin = ChargeOrigin { request_id: &request_id.0, merchant, idempotency_key, subscription_id: None };
+    let span = tracing::info_span!(
+        "payment",
+        transaction_uuid = tracing::field::Empty,
+        masked_card = tracing::field::Empty,
+        gateway = tracing::field::Empty,
+    );
+
+    match start_charge(&state, origin, payment_data).instrument(span).await? {
+        ChargeStart::Replayed(response) => Ok(Json(response.localized()).into_response()),
+        ChargeStart::Pending(pending) => {
+            let accepted = AcceptedPayment {
+                transaction_id: pending.transaction_uuid.to_string(),
+                status: TransactionStatus::Pending,
+            };
+            queue_slot.send(pending);
+            Ok((StatusCode::ACCEPTED, Json(accepted)).into_response())
+        }
+    }
+}
+
+// Completes queued payments, up to `concurrency` at a time. With every worker busy the queue
+// stops draining, which is what pushes back on POST /payment/async. Payments still queued at
+// shutdown were already accepted, so they are charged before the worker exits.
+pub(crate) async fn run_payment_worker(
+    state: AppState,
+    mut queue: mpsc::Receiver<PendingCharge>,
+    concurrency: usize,
+    mut stop: tokio::sync::watch::Receiver<bool>,
+) {
+    let workers = Arc::new(Semaphore::new(concurrency));
+    let mut running = tokio::task::JoinSet::new();
+
+    loop {
+        let worker = workers.clone().acquire_owned().await.expect("payment worker semaphore is never closed");
+        let pending = tokio::select! {
+            pending = queue.recv() => pending,
+            _ = stop.changed() => None,
+        };
+        let Some(pending) = pending else { break };
+        running.spawn(complete_queued_payment(state.clone(), pending, worker));
+        while running.try_join_next().is_some() {}
+    }
+
+    queue.close();
+    let mut drained = 0;
+    while let Some(pending) = queue.recv().await {
+        let worker = workers.clone().acquire_owned().await.expect("payment worker semaphore is never closed");
+        running.spawn(complete_queued_payment(state.clone(), pending, worker));
+        drained += 1;
+    }
+    while running.join_next().await.is_some() {}
+    info!(drained, "payment worker stopped");
+}
+
+// The outcome is persisted by `complete_charge`; nobody is waiting for the response
+pub(crate) async fn complete_queued_payment(state: AppState, pending: PendingCharge, _worker: OwnedSemaphorePermit) {
+    let transaction_uuid = pending.transaction_uuid;
+    let request_id = pending.request_id.clone();
+    let span = tracing::info_span!(
+        "payment",
+        request_id = %request_id,
+        transaction_uuid = %transaction_uuid,
+        masked_card = %pending.masked_card,
+        gateway = tracing::field::Empty,
+    );
+
+    let outcome = CURRENT_REQUEST_ID
+        .scope(request_id, complete_charge(&state, pending))
+        .instrument(span)
+        .await;
+    match outcome {
+        Ok(response) => info!(%transaction_uuid, success = response.success, "queued payment processed"),
+        Err(err) => warn!(%transaction_uuid, error = %err, "queued payment failed"),
+    }
+}
+
+// Who asked for a charge: an API request, or a subscription cycle billed in the background
+pub(crate) struct ChargeOrigin<'a> {
+    pub(crate) request_id: &'a str,
+    pub(crate) merchant: MerchantId,
+    pub(crate) idempotency_key: Option<String>,
+    pub(crate) subscription_id: Option<i32>, // Links the transaction to the subscription it bills
+}
+
+// A charge recorded as Pending and not yet sent to the gateway. Queued payments carry the
+// card details in memory only, as far as the worker that completes them.
+pub(crate) struct PendingCharge {
+    pub(crate) request_id: String,
+    pub(crate) merchant: MerchantId,
+    pub(crate) payment_data: PaymentRequest,
+    pub(crate) transaction_id: i32,
+    pub(crate) transaction_uuid: Uuid,
+    pub(crate) masked_card: String,
+    pub(crate) card_brand: CardBrand,
+    pub(crate) conversion: Option<FxConversion>,
+    pub(crate) idempotency: Option<(String, String)>, // Key and request hash
+    pub(crate) issuer: Option<RoutedGateway>, // Saved methods: the gateway whose token is sent
+}
+
+pub(crate) enum ChargeStart {
+    Replayed(PaymentResponse), // The idempotency key already has an outcome
+    Pending(PendingCharge),
+}
+
+// The payment path shared by POST /payment and subscription billing
+#[tracing::instrument(
+    name = "payment",
+    skip_all,
+    fields(transaction_uuid = tracing::field::Empty, masked_card = tracing::field::Empty, gateway = tracing::field::Empty)
+)]
+pub(crate) async fn charge_payment(
+    state: &AppState,
+    origin: ChargeOrigin<'_>,
+    payment_data: PaymentRequest,
+) -> Result<PaymentResponse, AppError> {
+    match start_charge(state, origin, payment_data).await? {
+        ChargeStart::Replayed(response) => Ok(response),
+        ChargeStart::Pending(pending) => complete_charge(state, pending).await,
+    }
+}
+
+// Validate the request and record it as Pending; nothing has been sent to a gateway yet
+pub(crate) async fn start_charge(
+    state: &AppState,
+    origin: ChargeOrigin<'_>,
+    mut payment_data: PaymentRequest,
+) -> Result<ChargeStart, AppError> {
+    let merchant = origin.merchant;
+
+    // 1. Basic Validation
+    let (masked_card, card_brand) = validate_payment_request(&state.amount_limits, &mut payment_data)?;
+    let (masked_card, card_brand, issuer) = match resolve_saved_payment_method(state, merchant, &mut payment_data).await? {
+        Some((masked_card, card_brand, issuer)) => (masked_card, card_brand, Some(issuer)),
+        None => (masked_card, card_brand, None),
+    };
+
+    // Replay the original response for a retried request instead of charging again
+    let idempotency = match origin.idempotency_key {
+        Some(key) => {
+            let request_hash = request_fingerprint(&state.card_fingerprint_salt, &payment_data);
+            if let Some(response) = find_idempotent_response(&state.db, merchant, PAYMENT_SCOPE, &key, &request_hash).await? {
+                return Ok(ChargeStart::Replayed(response));
+            }
+            Some((key, request_hash))
+        }
+        None => None,
+    };
+
+    // Cross-currency: from here on the request carries what the gateway will charge. Done after
+    // the idempotency lookup so a retry matches even though the rate may have moved.
+    let conversion = match payment_data.settlement_currency.clone() {
+        Some(settlement_currency) if settlement_currency != payment_data.currency => {
+            let fx = state.fx.as_ref().ok_or_else(|| {
+                AppError::BadRequest("FX_NOT_CONFIGURED", "Currency conversion is not enabled.".to_string())
+            })?;
+            let quoted = Money::from_minor(payment_data.amount, &payment_data.currency)?;
+            let settlement = Currency::from_code(&settlement_currency)?;
+            let rate = fx.rate(quoted.currency(), settlement).await?;
+            let converted = quoted.convert(settlement, rate, fx.rounding)?;
+            validate_amount(converted, &state.amount_limits)?;
+
+            info!(from = %quoted, to = %converted, %rate, rounding = ?fx.rounding, "converted payment amount");
+            payment_data.amount = converted.amount();
+            payment_data.currency = settlement_currency;
+            Some(FxConversion {
+                original_amount: quoted.amount(),
+                original_currency: quoted.currency().code().to_string(),
+                rate,
+                rounding: fx.rounding,
+            })
+        }
+        _ => None,
+    };
+    let (presentment_amount, presentment_currency, fx_rate, fx_rounding) = match &conversion {
+        Some(c) => (Some(c.original_amount), Some(c.original_currency.clone()), Some(c.rate), Some(c.rounding)),
+        None => (None, None, None, None),
+    };
+
+    // Token charges don't know their card yet, so only raw card payments are fingerprinted
+    let fingerprint = match &payment_data.instrument {
+        PaymentInstrument::Card(card) => Some(card_fingerprint(&state.card_fingerprint_salt, &card.card_number)),
+        PaymentInstrument::Token { .. } | PaymentInstrument::SavedMethod { .. } => None,
+    };
+    if let Some(fingerprint) = &fingerprint {
+        check_velocity(&state.db, state.velocity, fingerprint).await?;
+    }
+    let (card_expiry_month, card_expiry_year) = match &payment_data.instrument {
+        PaymentInstrument::Card(card) if state.store_card_expiry => (Some(card.expiry_month), Some(card.expiry_year)),
+        _ => (None, None),
+    };
+    
+    let transaction_uuid = Uuid::new_v4();
+
+    let span = tracing::Span::current();
+    span.record("transaction_uuid", tracing::field::display(&transaction_uuid));
+    span.record("masked_card", tracing::field::display(&masked_card));
+
+    // 2. RECORD THE ATTEMPT AS PENDING, committed before the gateway is called so the attempt
+    // survives whatever happens during the call, and no connection is held while it runs.
+    // A concurrent retry with the same key conflicts on the unique index and finds this row.
+    let (idempotency_key, request_hash) = idempotency.clone().unzip();
+    let mut tx = state.db.begin().await?;
+    let inserted = sqlx::query_scalar!(
+        r#"
+        INSERT INTO transactions (
+            transaction_uuid, amount, currency, status, masked_card_number, card_brand, card_fingerprint,
+            idempotency_key, request_hash, presentment_amount, presentment_currency, fx_rate, fx_rounding, merchant_id,
+            subscription_id, card_expiry_month, card_expiry_year, metadata
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+        ON CONFLICT (merchant_id, idempotency_key) WHERE idempotency_key IS NOT NULL DO NOTHING
+        RETURNING id
+        "#,
+        transaction_uuid,
+        payment_data.amount,
+        payment_data.currency,
+        TransactionStatus::Pending as _,
+        masked_card,
+        card_brand as _,
+        fingerprint,
+        idempotency_key,
+        request_hash,
+        presentment_amount,
+        presentment_currency,
+        fx_rate,
+        fx_rounding as _,
+        merchant.0,
+        origin.subscription_id,
+        card_expiry_month,
+        card_expiry_year,
+        payment_data.metadata
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(transaction_id) = inserted else {
+        tx.rollback().await?;
+        let (key, request_hash) = idempotency
+            .ok_or_else(|| AppError::InternalServerError("Insert conflict without an idempotency key.".to_string()))?;
+        info!(idempotency_key = %key, "duplicate request; returning existing transaction");
+        return existing_idempotent_response(&state.db, merchant, &key, &request_hash).await.map(ChargeStart::Replayed);
+    };
+    record_event(&mut tx, transaction_id, TransactionEventType::Created, TransactionStatus::Pending, payment_data.amount).await?;
+    tx.commit().await?;
+
+    Ok(ChargeStart::Pending(PendingCharge {
+        request_id: origin.request_id.to_string(),
+        merchant,
+        payment_data,
+        transaction_id,
+        transaction_uuid,
+        masked_card,
+        card_brand,
+        conversion,
+        idempotency,
+        issuer,
+    }))
+}
+
+// Send a pending charge to the gateway and persist its outcome
+pub(crate) async fn complete_charge(state: &AppState, pending: PendingCharge) -> Result<PaymentResponse, AppError> {
+    let PendingCharge {
+        request_id,
+        merchant,
+        payment_data,
+        transaction_id,
+        transaction_uuid,
+        masked_card,
+        card_brand,
+        conversion,
+        idempotency,
+        issuer,
+    } = pending;
+
+    // 3. EXTERNAL GATEWAY CALL. A token is only valid at the gateway that issued it, so
+    // saved-method charges skip currency routing and have nowhere to fail over to.
+    let chain = match &issuer {
+        Some(issuer) => std::slice::from_ref(issuer),
+        None => state.gateways.route(&payment_data.currency),
+    };
+    let (gateway, gateway_result) = charge_with_failover(
+        state,
+        chain,
+        &payment_data,
+        &transaction_uuid,
+        &request_id
+    ).await;
+    tracing::Span::current().record("gateway", gateway);
+
+    let charge = match gateway_result {
+        Ok(charge) => charge,
+        Err(err) if err.is_outcome_unknown() => {
+            // Outcome unknown: leave the row Pending (with the gateway asked) for reconciliation
+            match &err {
+                AppError::GatewayAmbiguous(detail) => {
+                    warn!(gateway, request_id, detail, "ambiguous gateway response; transaction left pending");
+                    record_payment_metric(&payment_data.currency, gateway, "ambiguous");
+                }
+                _ => {
+                    warn!(gateway, request_id, "gateway timed out; transaction left pending");
+                    record_payment_metric(&payment_data.currency, gateway, "timeout");
+                }
+            }
+            sqlx::query!(
+                "UPDATE transactions SET gateway = $1, version = version + 1 WHERE id = $2 AND status = 'pending'",
+                gateway,
+                transaction_id
+            )
+            .execute(&state.db)
+            .await?;
+            return Err(err);
+        }
+        Err(err) => {
+            // No charge was confirmed; close the pending row before surfacing the error
+            record_payment_metric(&payment_data.currency, gateway, "error");
+            let mut tx = state.db.begin().await?;
+            let closed = update_transaction_status(
+                &mut tx,
+                transaction_id,
+                TransactionStatus::Pending,
+                TransactionStatus::Failed,
+                TransactionEventType::Failed,
+                payment_data.amount,
+            ).await?;
+            tx.commit().await?;
+            if closed {
+                notify_payment(state, transaction_uuid, TransactionStatus::Failed, &payment_data);
+                publish_status(state, transaction_uuid, TransactionStatus::Failed);
+            } else {
+                warn!(gateway, request_id, "transaction settled while its charge was in flight; left as it is");
+            }
+            return Err(err);
+        }
+    };
+    let status = charge.status;
+    let response_message = charge.message;
+    let gateway_ref = charge.gateway_ref;
+    let decline_reason = charge.decline_reason.filter(|_| status == TransactionStatus::Failed);
+    record_payment_metric(&payment_data.currency, gateway, status.as_str());
+    state.decline_rate.record(status == TransactionStatus::Failed);
+
+    // 4. PERSIST THE GATEWAY OUTCOME (and which gateway produced it, for reconciliation),
+    // together with its event. If this fails the row stays Pending for reconciliation.
+    // Only a row still Pending takes the outcome: a void or reconciliation that got there
+    // first has already told someone what became of it.
+    let authorized_amount = (status == TransactionStatus::Authorized).then_some(payment_data.amount);
+    let operation = if payment_data.capture { PaymentOperation::Charge } else { PaymentOperation::Authorize };
+    let mut tx = state.db.begin().await?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE transactions
+        SET status = $1, gateway = $2, gateway_ref = $3, authorized_amount = $4, decline_reason = $5, version = version + 1
+        WHERE id = $6 AND status = 'pending'
+        "#,
+        status as _,
+        gateway,
+        gateway_ref,
+        authorized_amount,
+        decline_reason as _,
+        transaction_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        tx.rollback().await?;
+        let stored = query_transactions!("WHERE id = $1", transaction_id).fetch_one(&state.db).await?;
+        warn!(
+            gateway,
+            request_id,
+            outcome = ?status,
+            gateway_ref,
+            stored = ?stored.status,
+            "transaction settled while its charge was in flight; gateway outcome not applied"
+        );
+        let response = settled_charge_response(stored, operation, conversion);
+        if let Some((key, request_hash)) = idempotency {
+            store_idempotent_response(&state.db, merchant, PAYMENT_SCOPE, &key, &request_hash, &response).await?;
+        }
+        return Ok(response);
+    }
+
+    let (masked_card, card_brand) = match (charge.masked_card_number, charge.card_brand) {
+        (Some(gateway_mask), gateway_brand) => {
+            let gateway_brand = gateway_brand.unwrap_or(CardBrand::Unknown);
+            sqlx::query!(
+                "UPDATE transactions SET masked_card_number = $1, card_brand = $2, version = version + 1 WHERE id = $3",
+                gateway_mask,
+                gateway_brand as _,
+                transaction_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            (gateway_mask, gateway_brand)
+        }
+        (None, _) => (masked_card, card_brand),
+    };
+
+    record_event(&mut tx, transaction_id, TransactionEventType::for_charge(status), status, payment_data.amount).await?;
+    tx.commit().await?;
+
+    notify_payment(state, transaction_uuid, status, &payment_data);
+    publish_status(state, transaction_uuid, status);
+
+    // 5. Send Response to Customer
+    
+    let response = if matches!(status, TransactionStatus::Success | TransactionStatus::Authorized) {
+        info!(amount = payment_data.amount, currency = %payment_data.currency, status = ?status, "payment succeeded");
+        
+        PaymentResponse::new_success(
+            transaction_uuid.to_string(),
+            if status == TransactionStatus::Authorized { "PAYMENT_AUTHORIZED" } else { "PAYMENT_APPROVED" },
+            response_message
+        )
+        .with_card_brand(card_brand)
+        .with_payment_details(payment_data.amount, &payment_data.currency, &masked_card)
+        .with_gateway_ref(gateway_ref.clone())
+        .with_operation(operation)
+        .with_conversion(conversion.clone())
+    } else {
+        warn!(amount = payment_data.amount, currency = %payment_data.currency, status = ?status, ?decline_reason, "payment failed");
+
+        PaymentResponse::new_failure(
+            transaction_uuid.to_string(),
+            "PAYMENT_DECLINED",
+            response_message
+        )
+        .with_card_brand(card_brand)
+        .with_payment_details(payment_data.amount, &payment_data.currency, &masked_card)
+        .with_gateway_ref(gateway_ref.clone())
+        .with_operation(operation)
+        .with_conversion(conversion.clone())
+        .with_decline_reason(decline_reason)
+    };
+
+    if let Some((key, request_hash)) = idempotency {
+        store_idempotent_response(&state.db, merchant, PAYMENT_SCOPE, &key, &request_hash, &response).await?;
+    }
+
+    Ok(response)
+}
+
+// Response for a charge whose row was settled by someone else (a void, or reconciliation) while
+// the gateway call ran: what the row says, not what the gateway answered
+pub(crate) fn settled_charge_response(
+    transaction: Transaction,
+    operation: PaymentOperation,
+    conversion: Option<FxConversion>,
+) -> PaymentResponse {
+    let transaction_id = transaction.transaction_uuid.to_string();
+    let message = format!("Transaction was {} while the charge was in flight.", transaction.status.as_str());
+    let response = match transaction.status {
+        TransactionStatus::Voided => PaymentResponse::new_failure(transaction_id, "AUTHORIZATION_VOIDED", message),
+        TransactionStatus::Failed => PaymentResponse::new_failure(transaction_id, "PAYMENT_DECLINED", message)
+            .with_decline_reason(transaction.decline_reason),
+        TransactionStatus::Authorized => PaymentResponse::new_success(transaction_id, "PAYMENT_AUTHORIZED", message),
+        _ => PaymentResponse::new_success(transaction_id, "PAYMENT_APPROVED", message),
+    };
+
+    response
+        .with_card_brand(transaction.card_brand)
+        .with_payment_details(transaction.amount, &transaction.currency, &transaction.masked_card_number)
+        .with_gateway_ref(transaction.gateway_ref)
+        .with_operation(operation)
+        .with_conversion(conversion)
+}
+
+// Fetch a single transaction by its public UUID
+#[utoipa::path(
+    get,
+    path = "/api/v1/payment/{uuid}",
+    params(("uuid" = Uuid, Path, description = "Transaction UUID")),
+    responses(
+        (status = 200, body = Transaction),
+        (status = 400, description = "Malformed UUID", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn get_transaction(
+    State(state): State<AppState>,
+    _role: ReadRole,
+    Extension(merchant): Extension<MerchantId>,
+    transaction_uuid: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<Transaction>, AppError> {
+
+    let Path(transaction_uuid) = transaction_uuid
+        .map_err(|_| AppError::BadRequest("INVALID_TRANSACTION_ID", "Invalid transaction UUID.".to_string()))?;
+
+    let transaction = fetch_transaction(&state.db, merchant, transaction_uuid).await?;
+
+    Ok(Json(transaction))
+}
+
+// Refund a successful payment, fully or partially. The body is optional: `{ "amount": 500 }`
+#[utoipa::path(
+    post,
+    path = "/api/v1/payment/{uuid}/refund",
+    params(
+        ("uuid" = Uuid, Path, description = "Transaction UUID"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Replays the stored result of a retried refund on this transaction"),
+    ),
+    request_body(content = Option<RefundRequest>, description = "Omit to refund the remaining amount"),
+    responses(
+        (status = 200, body = PaymentResponse),
+        (status = 400, description = "Invalid refund amount, or the idempotency key was reused with a different amount", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Transaction is not refundable", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+#[tracing::instrument(name = "refund", skip_all, fields(transaction_uuid = tracing::field::Empty))]
+pub(crate) async fn refund_payment(
+    State(state): State<AppState>,
+    _role: RefundRole,
+    Extension(merchant): Extension<MerchantId>,
+    transaction_uuid: Result<Path<Uuid>, PathRejection>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<PaymentResponse>, AppError> {
+
+    let Path(transaction_uuid) = transaction_uuid
+        .map_err(|_| AppError::BadRequest("INVALID_TRANSACTION_ID", "Invalid transaction UUID.".to_string()))?;
+    tracing::Span::current().record("transaction_uuid", tracing::field::display(&transaction_uuid));
+
+    let refund_request: RefundRequest = if body.is_empty() {
+        RefundRequest { amount: None }
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| AppError::BadRequest("INVALID_REFUND_REQUEST", format!("Invalid refund request: {}", e)))?
+    };
+    let idempotency_key = idempotency_key_from_headers(&headers)?;
+    let scope = refund_scope(transaction_uuid);
+    let request_hash = refund_fingerprint(&refund_request);
+
+    // The row stays locked until commit, so concurrent refunds on the same
+    // transaction are serialized and can never over-refund.
+    let mut tx = state.db.begin().await?;
+
+    let transaction = lock_transaction(&mut tx, merchant, transaction_uuid).await?;
+
+    // Checked under the lock: a concurrent retry waits for the first refund to commit its
+    // stored result, then replays it instead of reversing again
+    if let Some(key) = &idempotency_key
+        && let Some(response) = find_idempotent_response(&mut *tx, merchant, &scope, key, &request_hash).await?
+    {
+        return Ok(Json(response.localized()));
+    }
+
+    match transaction.status {
+        TransactionStatus::Success | TransactionStatus::PartiallyRefunded => {}
+        TransactionStatus::Refunded => {
+            return Err(AppError::Conflict("ALREADY_REFUNDED", "Transaction has already been refunded.".to_string()));
+        }
+        _ => {
+            return Err(AppError::Conflict("NOT_REFUNDABLE", "Only successful transactions can be refunded.".to_string()));
+        }
+    }
+
+    let charged = Money::from_minor(transaction.amount, &transaction.currency)?;
+    let remaining = charged.checked_sub(Money::new(transaction.refunded_amount, charged.currency()))?;
+    let refund_amount = refund_request.amount.unwrap_or(remaining.amount());
+    if refund_amount <= 0 {
+        return Err(AppError::BadRequest("INVALID_REFUND_AMOUNT", "Refund amount must be greater than zero.".to_string()));
+    }
+    if refund_amount > remaining.amount() {
+        return Err(AppError::BadRequest("INVALID_REFUND_AMOUNT", format!(
+            "Refund amount exceeds the remaining refundable amount of {}.", remaining
+        )));
+    }
+
+    let response_message = gateway_for_transaction(&state, &transaction)
+        .refund(&transaction, refund_amount)
+        .await?;
+
+    let new_status = if refund_amount == remaining.amount() {
+        TransactionStatus::Refunded
+    } else {
+        TransactionStatus::PartiallyRefunded
+    };
+
+    let updated = sqlx::query!(
+        r#"
+        UPDATE transactions
+        SET status = $1, refunded_amount = refunded_amount + $2, version = version + 1
+        WHERE id = $3 AND version = $4
+        "#,
+        new_status as _,
+        refund_amount,
+        transaction.id,
+        transaction.version
+    )
+    .execute(&mut *tx)
+    .await?;
+    ensure_unmodified(updated)?;
+
+    let event = match new_status {
+        TransactionStatus::Refunded => TransactionEventType::Refunded,
+        _ => TransactionEventType::PartiallyRefunded,
+    };
+    record_event(&mut tx, transaction.id, event, new_status, refund_amount).await?;
+
+    let response = PaymentResponse::new_success(transaction_uuid.to_string(), "REFUND_APPROVED", response_message)
+        .with_card_brand(transaction.card_brand)
+        .with_payment_details(refund_amount, &transaction.currency, &transaction.masked_card_number)
+        .with_gateway_ref(transaction.gateway_ref.clone())
+        .with_operation(PaymentOperation::Refund);
+    // Stored with the refund itself, so a retry never sees the refund without its result
+    if let Some(key) = &idempotency_key {
+        store_idempotent_response(&mut *tx, merchant, &scope, key, &request_hash, &response).await?;
+    }
+
+    tx.commit().await?;
+
+    info!(
+        masked_card = %transaction.masked_card_number,
+        amount = refund_amount,
+        currency = %transaction.currency,
+        status = ?new_status,
+        "payment refunded"
+    );
+
+    Ok(Json(response.localized()))
+}
+
+// Follow-up operations go back through the gateway that took the charge
+pub(crate) fn gateway_for_transaction<'a>(state: &'a AppState, transaction: &Transaction) -> &'a dyn PaymentGateway {
+    let routed = transaction
+        .gateway
+        .as_deref()
+        .and_then(|name| state.gateways.by_name(name))
+        .unwrap_or(&state.gateways.route(&transaction.currency)[0]);
+
+    routed.gateway.as_ref()
+}
+
+// Capture a held authorization, fully or for less. The body is optional: `{ "amount": 800 }`
+#[utoipa::path(
+    post,
+    path = "/api/v1/payment/{uuid}/capture",
+    params(("uuid" = Uuid, Path, description = "Transaction UUID")),
+    request_body(content = Option<CaptureRequest>, description = "Omit to capture the full authorized amount"),
+    responses(
+        (status = 200, body = PaymentResponse),
+        (status = 400, description = "Invalid capture amount", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Transaction is not an open authorization", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+#[tracing::instrument(name = "capture", skip_all, fields(transaction_uuid = tracing::field::Empty))]
+pub(crate) async fn capture_payment(
+    State(state): State<AppState>,
+    _role: ChargeRole,
+    Extension(merchant): Extension<MerchantId>,
+    transaction_uuid: Result<Path<Uuid>, PathRejection>,
+    body: Bytes,
+) -> Result<Json<PaymentResponse>, AppError> {
+
+    let Path(transaction_uuid) = transaction_uuid
+        .map_err(|_| AppError::BadRequest("INVALID_TRANSACTION_ID", "Invalid transaction UUID.".to_string()))?;
+    tracing::Span::current().record("transaction_uuid", tracing::field::display(&transaction_uuid));
+
+    let capture_request: CaptureRequest = if body.is_empty() {
+        CaptureRequest { amount: None }
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| AppError::BadRequest("INVALID_CAPTURE_REQUEST", format!("Invalid capture request: {}", e)))?
+    };
+
+    // Locked so a capture can't race a void on the same authorization
+    let mut tx = state.db.begin().await?;
+
+    let transaction = lock_transaction(&mut tx, merchant, transaction_uuid).await?;
+
+    if transaction.status != TransactionStatus::Authorized {
+        return Err(AppError::Conflict("NOT_CAPTURABLE", "Only authorized transactions can be captured.".to_string()));
+    }
+
+    let authorized = Money::from_minor(transaction.authorized_amount.unwrap_or(transaction.amount), &transaction.currency)?;
+    let capture_amount = capture_request.amount.unwrap_or(authorized.amount());
+    if capture_amount <= 0 {
+        return Err(AppError::BadRequest("INVALID_CAPTURE_AMOUNT", "Capture amount must be greater than zero.".to_string()));
+    }
+    if capture_amount > authorized.amount() {
+        return Err(AppError::BadRequest("INVALID_CAPTURE_AMOUNT", format!(
+            "Capture amount exceeds the authorized amount of {}.", authorized
+        )));
+    }
+
+    let response_message = gateway_for_transaction(&state, &transaction)
+        .capture(&transaction, capture_amount)
+        .await?;
+
+    // `amount` becomes what was actually taken, so refunds are bounded by it
+    let updated = sqlx::query!(
+        "UPDATE transactions SET status = $1, amount = $2, version = version + 1 WHERE id = $3 AND version = $4",
+        TransactionStatus::Success as _,
+        capture_amount,
+        transaction.id,
+        transaction.version
+    )
+    .execute(&mut *tx)
+    .await?;
+    ensure_unmodified(updated)?;
+    record_event(&mut tx, transaction.id, TransactionEventType::Captured, TransactionStatus::Success, capture_amount).await?;
+
+    tx.commit().await?;
+
+    info!(
+        masked_card = %transaction.masked_card_number,
+        amount = capture_amount,
+        authorized = authorized.amount(),
+        currency = %transaction.currency,
+        "payment captured"
+    );
+
+    Ok(Json(
+        PaymentResponse::new_success(transaction_uuid.to_string(), "CAPTURE_APPROVED", response_message)
+            .with_card_brand(transaction.card_brand)
+            .with_payment_details(capture_amount, &transaction.currency, &transaction.masked_card_number)
+            .with_gateway_ref(transaction.gateway_ref)
+            .with_operation(PaymentOperation::Capture)
+            .localized()
+    ))
+}
+
+// Captured charges settle within this window; after it only a refund can reverse them
+pub(crate) const VOID_WINDOW_HOURS: i64 = 24;
+
+// Cancel an unsettled authorization. Unlike a refund, no money has moved, so the whole
+// authorization is released and the transaction ends as Voided.
+#[utoipa::path(
+    post,
+    path = "/api/v1/payment/{uuid}/void",
+    params(("uuid" = Uuid, Path, description = "Transaction UUID")),
+    responses(
+        (status = 200, body = PaymentResponse),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Transaction has settled, been refunded, or failed", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+#[tracing::instrument(name = "void", skip_all, fields(transaction_uuid = tracing::field::Empty))]
+pub(crate) async fn void_payment(
+    State(state): State<AppState>,
+    _role: RefundRole,
+    Extension(merchant): Extension<MerchantId>,
+    transaction_uuid: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<PaymentResponse>, AppError> {
+
+    let Path(transaction_uuid) = transaction_uuid
+        .map_err(|_| AppError::BadRequest("INVALID_TRANSACTION_ID", "Invalid transaction UUID.".to_string()))?;
+    tracing::Span::current().record("transaction_uuid", tracing::field::display(&transaction_uuid));
+
+    // Locked like refunds, so a void can't race a refund on the same row
+    let mut tx = state.db.begin().await?;
+
+    let transaction = lock_transaction(&mut tx, merchant, transaction_uuid).await?;
+
+    match transaction.status {
+        TransactionStatus::Pending | TransactionStatus::Authorized => {}
+        TransactionStatus::Success => {
+            let settled_at = transaction.created_at + chrono::Duration::hours(VOID_WINDOW_HOURS);
+            if Utc::now() >= settled_at {
+                return Err(AppError::Conflict("ALREADY_SETTLED", "Transaction has settled; refund it instead.".to_string()));
+            }
+        }
+        TransactionStatus::Voided => {
+            return Err(AppError::Conflict("ALREADY_VOIDED", "Transaction has already been voided.".to_string()));
+        }
+        TransactionStatus::Refunded | TransactionStatus::PartiallyRefunded => {
+            return Err(AppError::Conflict("NOT_VOIDABLE", "Refunded transactions cannot be voided.".to_string()));
+        }
+        TransactionStatus::Failed => {
+            return Err(AppError::Conflict("NOT_VOIDABLE", "Failed transactions cannot be voided.".to_string()));
+        }
+    }
+
+    let response_message = gateway_for_transaction(&state, &transaction).void(&transaction).await?;
+
+    let updated = sqlx::query!(
+        "UPDATE transactions SET status = $1, version = version + 1 WHERE id = $2 AND version = $3",
+        TransactionStatus::Voided as _,
+        transaction.id,
+        transaction.version
+    )
+    .execute(&mut *tx)
+    .await?;
+    ensure_unmodified(updated)?;
+    record_event(&mut tx, transaction.id, TransactionEventType::Voided, TransactionStatus::Voided, transaction.amount).await?;
+
+    tx.commit().await?;
+
+    info!(masked_card = %transaction.masked_card_number, previous_status = ?transaction.status, "payment voided");
+
+    Ok(Json(
+        PaymentResponse::new_success(transaction_uuid.to_string(), "AUTHORIZATION_VOIDED", response_message)
+            .with_card_brand(transaction.card_brand)
+            .with_payment_details(transaction.amount, &transaction.currency, &transaction.masked_card_number)
+            .with_gateway_ref(transaction.gateway_ref)
+            .with_operation(PaymentOperation::Void)
+            .localized()
+    ))
+}
+
+// Audit history of one transaction, oldest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/payment/{uuid}/events",
+    params(("uuid" = Uuid, Path, description = "Transaction UUID")),
+    responses((status = 200, body = Vec<TransactionEvent>), (status = 404, body = ErrorBody)),
+    security(("api_key" = []))
+)]
+pub(crate) async fn transaction_events(
+    State(state): State<AppState>,
+    _role: ReadRole,
+    Extension(merchant): Extension<MerchantId>,
+    transaction_uuid: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<Vec<TransactionEvent>>, AppError> {
+
+    let Path(transaction_uuid) = transaction_uuid
+        .map_err(|_| AppError::BadRequest("INVALID_TRANSACTION_ID", "Invalid transaction UUID.".to_string()))?;
+
+    let transaction = fetch_transaction(&state.db, merchant, transaction_uuid).await?;
+
+    let events = sqlx::query_as!(
+        TransactionEvent,
+        r#"
+        SELECT event_type AS "event_type: TransactionEventType", status AS "status: TransactionStatus", amount, created_at
+        FROM transaction_events
+        WHERE transaction_id = $1
+        ORDER BY id
+        "#,
+        transaction.id
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(events))
+}
+
+// Server-sent `status` events for one transaction: its current status, then the outcome once
+// it leaves Pending, after which the stream ends
+#[utoipa::path(
+    get,
+    path = "/api/v1/payment/{uuid}/stream",
+    params(("uuid" = Uuid, Path, description = "Transaction UUID")),
+    responses(
+        (status = 200, description = "`text/event-stream` of `status` events", content_type = "text/event-stream", body = String),
+        (status = 404, body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn transaction_status_stream(
+    State(state): State<AppState>,
+    _role: ReadRole,
+    Extension(merchant): Extension<MerchantId>,
+    transaction_uuid: Result<Path<Uuid>, PathRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, AppError> {
+    let Path(transaction_uuid) = transaction_uuid
+        .map_err(|_| AppError::BadRequest("INVALID_TRANSACTION_ID", "Invalid transaction UUID.".to_string()))?;
+
+    // Subscribed before the read, so an outcome landing in between is still seen
+    let updates = state.status_updates.subscribe();
+    let transaction = fetch_transaction(&state.db, merchant, transaction_uuid).await?;
+
+    let watch = StatusWatch {
+        db: state.db.clone(),
+        merchant,
+        transaction_uuid,
+        updates,
+        unsent: Some(transaction.status),
+        finished: false,
+    };
+    // Nothing is spawned: a client that disconnects drops the stream and its subscription
+    let events = stream::unfold(watch, StatusWatch::next).map(move |status| {
+        Event::default()
+            .event("status")
+            .json_data(serde_json::json!({ "transaction_id": transaction_uuid, "status": status }))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+// Follows one transaction until it leaves Pending
+pub(crate) struct StatusWatch {
+    pub(crate) db: PgPool,
+    pub(crate) merchant: MerchantId,
+    pub(crate) transaction_uuid: Uuid,
+    pub(crate) updates: broadcast::Receiver<StatusUpdate>,
+    pub(crate) unsent: Option<TransactionStatus>, // Known, not yet sent to the client
+    pub(crate) finished: bool,
+}
+
+impl StatusWatch {
+    pub(crate) async fn next(mut self) -> Option<(TransactionStatus, Self)> {
+        if self.finished {
+            return None;
+        }
+
+        let status = match self.unsent.take() {
+            Some(status) => status,
+            None => loop {
+                // Updates are per instance, and a lagging receiver drops some; the row is the
+                // truth, so it is re-read whenever one might have been missed
+                match tokio::time::timeout(STATUS_STREAM_RECHECK, self.updates.recv()).await {
+                    Ok(Ok(update)) if update.transaction_uuid == self.transaction_uuid => break update.status,
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                    Ok(Err(broadcast::error::RecvError::Lagged(_))) | Err(_) => {
+                        match fetch_transaction(&self.db, self.merchant, self.transaction_uuid).await {
+                            Ok(transaction) if transaction.status != TransactionStatus::Pending => break transaction.status,
+                            Ok(_) => continue,
+                            Err(err) => {
+                                warn!(transaction_uuid = %self.transaction_uuid, error = %err, "status stream lookup failed");
+                                return None;
+                            }
+                        }
+                    }
+                }
+            },
+        };
+
+        self.finished = status != TransactionStatus::Pending;
+        Some((status, self))
+    }
+}
+
+pub(crate) const DEFAULT_LIST_LIMIT: i64 = 50;
+pub(crate) const MAX_LIST_LIMIT: i64 = 200;
+
+// List transactions, newest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/payments",
+    params(ListParams),
+    responses((status = 200, body = TransactionList), (status = 400, body = ErrorBody)),
+    security(("api_key" = []))
+)]
+pub(crate) async fn list_transactions(
+    State(state): State<AppState>,
+    _role: ReadRole,
+    Extension(merchant): Extension<MerchantId>,
+    params: Result<Query<ListParams>, QueryRejection>,
+) -> Result<Json<TransactionList>, AppError> {
+
+    let Query(params) = params.map_err(|e| AppError::BadRequest("INVALID_QUERY", format!("Invalid query parameters: {}", e)))?;
+
+    // Out-of-range values are clamped rather than rejected
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+    let after = params.cursor.as_deref().map(decode_list_cursor).transpose()?;
+    if after.is_some() && offset > 0 {
+        return Err(AppError::BadRequest("INVALID_QUERY", "'cursor' and 'offset' cannot be combined.".to_string()));
+    }
+
+    let currency = params.currency.as_deref().map(|c| c.trim().to_uppercase());
+    let from = parse_rfc3339_param("from", params.from.as_deref())?;
+    let to = parse_rfc3339_param("to", params.to.as_deref())?;
+
+    // Every filter is optional: a NULL parameter disables its clause. One extra row is read to
+    // tell whether another page follows.
+    let mut items = query_transactions!(
+        r#"
+        WHERE merchant_id = $3
+          AND ($4::transaction_status IS NULL OR status = $4)
+          AND ($5::text IS NULL OR currency = $5)
+          AND ($6::timestamptz IS NULL OR created_at >= $6)
+          AND ($7::timestamptz IS NULL OR created_at <= $7)
+          AND ($8::timestamptz IS NULL OR (created_at, id) < ($8, $9::int4))
+        ORDER BY created_at DESC, id DESC
+        LIMIT $1 OFFSET $2
+        "#,
+        limit + 1,
+        offset,
+        merchant.0,
+        params.status as _,
+        currency,
+        from,
+        to,
+        after.map(|(created_at, _)| created_at),
+        after.map(|(_, id)| id)
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|transaction| encode_list_cursor(transaction.created_at, transaction.id))
+    } else {
+        None
+    };
+
+    let total = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM transactions
+        WHERE merchant_id = $1
+          AND ($2::transaction_status IS NULL OR status = $2)
+          AND ($3::text IS NULL OR currency = $3)
+          AND ($4::timestamptz IS NULL OR created_at >= $4)
+          AND ($5::timestamptz IS NULL OR created_at <= $5)
+        "#,
+        merchant.0,
+        params.status as _,
+        currency,
+        from,
+        to
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(TransactionList { items, total, limit, offset, next_cursor }))
+}
+
+// The (created_at, id) of a page's last row. Opaque to clients, who only hand it back.
+pub(crate) fn encode_list_cursor(created_at: DateTime<Utc>, id: i32) -> String {
+    hex::encode(format!("{}:{}", created_at.timestamp_micros(), id))
+}
+
+pub(crate) fn decode_list_cursor(cursor: &str) -> Result<(DateTime<Utc>, i32), AppError> {
+    let invalid = || AppError::BadRequest("INVALID_CURSOR", "Invalid pagination cursor.".to_string());
+
+    let decoded = hex::decode(cursor).ok().and_then(|bytes| String::from_utf8(bytes).ok()).ok_or_else(invalid)?;
+    let (micros, id) = decoded.split_once(':').ok_or_else(invalid)?;
+    let created_at = micros
+        .parse::<i64>()
+        .ok()
+        .and_then(DateTime::from_timestamp_micros)
+        .ok_or_else(invalid)?;
+    let id = id.parse::<i32>().map_err(|_| invalid())?;
+
+    Ok((created_at, id))
+}
+
+pub(crate) const EXPORT_PAGE_SIZE: i64 = 500;
+pub(crate) const EXPORT_COLUMNS: [&str; 6] = ["transaction_id", "amount", "currency", "status", "masked_card_number", "created_at"];
+
+// CSV of every transaction matching the list filters, newest first; limit and offset are ignored
+#[utoipa::path(
+    get,
+    path = "/api/v1/payments/export.csv",
+    params(ListParams),
+    responses(
+        (status = 200, description = "CSV attachment, one row per transaction", content_type = "text/csv", body = String),
+        (status = 400, body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn export_transactions(
+    State(state): State<AppState>,
+    _role: ReadRole,
+    Extension(merchant): Extension<MerchantId>,
+    params: Result<Query<ListParams>, QueryRejection>,
+) -> Result<Response, AppError> {
+
+    let Query(params) = params.map_err(|e| AppError::BadRequest("INVALID_QUERY", format!("Invalid query parameters: {}", e)))?;
+
+    let export = TransactionExport {
+        db: state.db.clone(),
+        merchant,
+        status: params.status,
+        currency: params.currency.as_deref().map(|c| c.trim().to_uppercase()),
+        from: parse_rfc3339_param("from", params.from.as_deref())?,
+        to: parse_rfc3339_param("to", params.to.as_deref())?,
+        after: None,
+        header_written: false,
+        finished: false,
+    };
+    // One page is fetched per body chunk, so memory stays flat however many rows match
+    let body = axum::body::Body::from_stream(stream::unfold(export, TransactionExport::next_chunk));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"transactions.csv\""),
+        ],
+        body,
+    ).into_response())
+}
+
+// Keyset-paginated walk over the filtered transactions, rendered as CSV chunks
+pub(crate) struct TransactionExport {
+    pub(crate) db: PgPool,
+    pub(crate) merchant: MerchantId,
+    pub(crate) status: Option<TransactionStatus>,
+    pub(crate) currency: Option<String>,
+    pub(crate) from: Option<DateTime<Utc>>,
+    pub(crate) to: Option<DateTime<Utc>>,
+    pub(crate) after: Option<(DateTime<Utc>, i32)>, // (created_at, id) of the last row written
+    pub(crate) header_written: bool,
+    pub(crate) finished: bool,
+}
+
+impl TransactionExport {
+    pub(crate) async fn next_chunk(mut self) -> Option<(Result<Bytes, AppError>, Self)> {
+        if self.finished {
+            return None;
+        }
+
+        let chunk = self.render_page().await;
+        if let Err(err) = &chunk {
+            // The 200 is already sent, so all that can be done is cut the body short
+            error!(error = %err, "transaction export failed");
+            self.finished = true;
+        }
+        Some((chunk, self))
+    }
+
+    pub(crate) async fn render_page(&mut self) -> Result<Bytes, AppError> {
+        let page = query_transactions!(
+            r#"
+            WHERE merchant_id = $2
+              AND ($3::transaction_status IS NULL OR status = $3)
+              AND ($4::text IS NULL OR currency = $4)
+              AND ($5::timestamptz IS NULL OR created_at >= $5)
+              AND ($6::timestamptz IS NULL OR created_at <= $6)
+              AND ($7::timestamptz IS NULL OR (created_at, id) < ($7, $8::int4))
+            ORDER BY created_at DESC, id DESC
+            LIMIT $1
+            "#,
+            EXPORT_PAGE_SIZE,
+            self.merchant.0,
+            self.status as _,
+            self.currency,
+            self.from,
+            self.to,
+            self.after.map(|(created_at, _)| created_at),
+            self.after.map(|(_, id)| id)
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        // The csv writer takes care of quoting and escaping
+        let csv_error = |e: csv::Error| AppError::InternalServerError(format!("Failed to write CSV: {}", e));
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        if !self.header_written {
+            writer.write_record(EXPORT_COLUMNS).map_err(csv_error)?;
+            self.header_written = true;
+        }
+        for transaction in &page {
+            writer
+                .write_record([
+                    transaction.transaction_uuid.to_string(),
+                    transaction.amount.to_string(),
+                    transaction.currency.clone(),
+                    transaction.status.as_str().to_string(),
+                    transaction.masked_card_number.clone(),
+                    transaction.created_at.to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                ])
+                .map_err(csv_error)?;
+        }
+
+        self.finished = page.len() < EXPORT_PAGE_SIZE as usize;
+        self.after = page.last().map(|transaction| (transaction.created_at, transaction.id));
+
+        let bytes = writer
+            .into_inner()
+            .map_err(|e| AppError::InternalServerError(format!("Failed to write CSV: {}", e)))?;
+        Ok(Bytes::from(bytes))
+    }
+}
+
+// Handler for POST /api/v1/admin/gateway-key/reload: re-reads PAYMENT_GATEWAY_API_KEY_FILE and
+// switches every gateway call to the new key without a restart. The old key stays in use if
+// the file can't be read or doesn't hold a valid key.
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/gateway-key/reload",
+    responses(
+        (status = 200, body = GatewayKeyRotation),
+        (status = 400, description = "No key file is configured, or it holds an invalid key", body = ErrorBody),
+        (status = 500, description = "The key file could not be read", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn reload_gateway_key(
+    State(state): State<AppState>,
+    _role: AdminRole,
+) -> Result<Json<GatewayKeyRotation>, AppError> {
+    let changed = state.api_key.reload()?;
+    info!(changed, "gateway API key reloaded");
+
+    Ok(Json(GatewayKeyRotation { changed, reloaded_at: Utc::now() }))
+}
+
+// Handler for GET /api/v1/reports/summary
+#[utoipa::path(
+    get,
+    path = "/api/v1/reports/summary",
+    params(SummaryParams),
+    responses((status = 200, body = PaymentSummary), (status = 400, body = ErrorBody)),
+    security(("api_key" = []))
+)]
+pub(crate) async fn payments_summary(
+    State(state): State<AppState>,
+    _role: AdminRole,
+    Extension(merchant): Extension<MerchantId>,
+    params: Result<Query<SummaryParams>, QueryRejection>,
+) -> Result<Json<PaymentSummary>, AppError> {
+
+    let Query(params) = params.map_err(|e| AppError::BadRequest("INVALID_QUERY", format!("Invalid query parameters: {}", e)))?;
+
+    let currency = params
+        .currency
+        .as_deref()
+        .map(|c| Currency::from_code(&c.trim().to_uppercase()))
+        .transpose()?;
+    let from = parse_rfc3339_param("from", params.from.as_deref())?;
+    let to = parse_rfc3339_param("to", params.to.as_deref())?;
+
+    // SUM(bigint) yields NUMERIC in Postgres, so cast back to BIGINT
+    let rows = sqlx::query!(
+        r#"
+        SELECT status AS "status!: TransactionStatus",
+               COUNT(*) AS "count!",
+               COALESCE(SUM(amount), 0)::BIGINT AS "amount!",
+               COALESCE(SUM(refunded_amount), 0)::BIGINT AS "refunded!"
+        FROM transactions
+        WHERE merchant_id = $1
+          AND ($2::text IS NULL OR currency = $2)
+          AND ($3::timestamptz IS NULL OR created_at >= $3)
+          AND ($4::timestamptz IS NULL OR created_at <= $4)
+        GROUP BY status
+        "#,
+        merchant.0,
+        currency.map(|c| c.code()),
+        from,
+        to
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    // Starts zero-filled so an empty range still returns a full summary
+    let mut summary = PaymentSummary::default();
+    for row in rows {
+        summary.total_count += row.count;
+        summary.total_refunded_amount += row.refunded;
+        match row.status {
+            TransactionStatus::Success | TransactionStatus::Refunded | TransactionStatus::PartiallyRefunded => {
+                summary.successful_count += row.count;
+                summary.total_successful_amount += row.amount;
+            }
+            TransactionStatus::Failed => summary.declined_count += row.count,
+            // Voided authorizations never moved money, so they count as neither
+            TransactionStatus::Pending | TransactionStatus::Voided | TransactionStatus::Authorized => {}
+        }
+    }
+
+    let settled = summary.successful_count + summary.declined_count;
+    if settled > 0 {
+        summary.decline_rate = summary.declined_count as f64 / settled as f64;
+    }
+
+    if let Some(currency) = currency {
+        summary.total_successful_display = Some(Money::new(summary.total_successful_amount, currency).to_string());
+        summary.total_refunded_display = Some(Money::new(summary.total_refunded_amount, currency).to_string());
+    }
+
+    Ok(Json(summary))
+}
+
+// Swaps a saved payment method for its gateway token, returning the stored card's mask and
+// brand and the gateway that issued the token. Another merchant's method is reported as
+// unknown, like one that doesn't exist.
+pub(crate) async fn resolve_saved_payment_method(
+    state: &AppState,
+    merchant: MerchantId,
+    payment_data: &mut PaymentRequest,
+) -> Result<Option<(String, CardBrand, RoutedGateway)>, AppError> {
+    let PaymentInstrument::SavedMethod { token_id } = payment_data.instrument else {
+        return Ok(None);
+    };
+
+    let method = sqlx::query!(
+        r#"
+        SELECT payment_token, masked_card_number, card_brand AS "card_brand: CardBrand", gateway
+        FROM payment_methods
+        WHERE token_id = $1 AND merchant_id = $2
+        "#,
+        token_id,
+        merchant.0
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::BadRequest("UNKNOWN_PAYMENT_METHOD", "Unknown payment method.".to_string()))?;
+    let issuer = state.gateways.by_name(&method.gateway).cloned().ok_or_else(|| {
+        AppError::BadRequest(
+            "UNKNOWN_PAYMENT_METHOD",
+            format!("Payment method was issued by gateway '{}', which is no longer configured.", method.gateway),
+        )
+    })?;
+
+    payment_data.instrument = PaymentInstrument::Token { payment_token: method.payment_token };
+    Ok(Some((method.masked_card_number, method.card_brand, issuer)))
+}
+
+// Handler for POST /api/v1/tokens: vaults a card at the gateway so later payments can pass
+// its token_id instead of the PAN
+#[utoipa::path(
+    post,
+    path = "/api/v1/tokens",
+    request_body = CardDetails,
+    responses(
+        (status = 201, body = PaymentMethod),
+        (status = 400, description = "Validation failed", body = ErrorBody),
+        (status = 502, description = "Gateway error", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn create_payment_method(
+    State(state): State<AppState>,
+    _role: ChargeRole,
+    Extension(merchant): Extension<MerchantId>,
+    Json(card): Json<CardDetails>,
+) -> Result<(StatusCode, Json<PaymentMethod>), AppError> {
+    let card_brand = validate_card(&card).map_err(AppError::Validation)?;
+    let masked_card = mask_card_number(&card.card_number);
+
+    // Tokens only mean something to the gateway that issued them, which is recorded alongside
+    let gateway = &state.gateways.default[0].gateway;
+    let payment_token = gateway.tokenize(&card).await?;
+
+    let method = sqlx::query_as!(
+        PaymentMethod,
+        r#"
+        INSERT INTO payment_methods (token_id, merchant_id, payment_token, masked_card_number, card_brand, gateway)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING token_id, masked_card_number, card_brand AS "card_brand: CardBrand", created_at
+        "#,
+        Uuid::new_v4(),
+        merchant.0,
+        payment_token,
+        masked_card,
+        card_brand as _,
+        gateway.name()
+    )
+    .fetch_one(&state.db)
+    .await?;
+    info!(token_id = %method.token_id, masked_card = %method.masked_card_number, "payment method saved");
+
+    Ok((StatusCode::CREATED, Json(method)))
+}
+
+// Handler for POST /api/v1/subscriptions: schedules a recurring charge against a stored token
+#[utoipa::path(
+    post,
+    path = "/api/v1/subscriptions",
+    request_body = SubscriptionRequest,
+    responses(
+        (status = 200, body = Subscription),
+        (status = 400, description = "Validation failed", body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn create_subscription(
+    State(state): State<AppState>,
+    _role: ChargeRole,
+    Extension(merchant): Extension<MerchantId>,
+    Json(request): Json<SubscriptionRequest>,
+) -> Result<Json<Subscription>, AppError> {
+    let currency_code = request.currency.trim().to_uppercase();
+    let mut errors = FieldErrors::default();
+
+    let currency = errors.check(validate_currency("currency", &currency_code));
+    if let Some(currency) = currency {
+        errors.check(validate_amount(Money::new(request.amount, currency), &state.amount_limits));
+    }
+    errors.check(validate_payment_token(&request.payment_token));
+    let start_at = errors.check(
+        parse_rfc3339_param("start_at", request.start_at.as_deref()).map_err(|err| field_error("start_at", err)),
+    );
+    let start_at = errors.finish(start_at)?.unwrap_or_else(Utc::now);
+
+    let subscription = sqlx::query_as!(
+        Subscription,
+        r#"
+        INSERT INTO subscriptions (
+            subscription_uuid, merchant_id, amount, currency, payment_token, billing_interval, next_billing_at, next_attempt_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $7)
+        RETURNING subscription_uuid, amount, currency, billing_interval AS "interval: BillingInterval", status AS "status: SubscriptionStatus", next_billing_at, next_attempt_at, failed_attempts, created_at
+        "#,
+        Uuid::new_v4(),
+        merchant.0,
+        request.amount,
+        currency_code,
+        request.payment_token,
+        request.interval as _,
+        start_at
+    )
+    .fetch_one(&state.db)
+    .await?;
+    info!(subscription_uuid = %subscription.subscription_uuid, interval = ?subscription.interval, "subscription created");
+
+    Ok(Json(subscription))
+}
+
+// Handler for GET /api/v1/subscriptions/:uuid
+#[utoipa::path(
+    get,
+    path = "/api/v1/subscriptions/{uuid}",
+    params(("uuid" = Uuid, Path, description = "Subscription UUID")),
+    responses(
+        (status = 200, body = Subscription),
+        (status = 400, description = "Malformed UUID", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    security(("api_key" = []))
+)]
+pub(crate) async fn get_subscription(
+    State(state): State<AppState>,
+    _role: ReadRole,
+    Extension(merchant): Extension<MerchantId>,
+    subscription_uuid: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<Subscription>, AppError> {
+    let Path(subscription_uuid) = subscription_uuid
+        .map_err(|_| AppError::BadRequest("INVALID_SUBSCRIPTION_ID", "Invalid subscription UUID.".to_string()))?;
+
+    let subscription = sqlx::query_as!(
+        Subscription,
+        r#"
+        SELECT subscription_uuid, amount, currency, billing_interval AS "interval: BillingInterval", status AS "status: SubscriptionStatus", next_billing_at, next_attempt_at, failed_attempts, created_at
+        FROM subscriptions
+        WHERE subscription_uuid = $1 AND merchant_id = $2
+        "#,
+        subscription_uuid,
+        merchant.0
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("Subscription not found.".to_string()))?;
+
+    Ok(Json(subscription))
+}
+
+// Most subscriptions billed per scan, so one tick can't monopolize the pool
+pub(crate) const SUBSCRIPTION_BATCH_SIZE: usize = 100;
+
+// Bills due subscriptions every `poll` until `stop` flips, finishing the cycle in progress first
+pub(crate) async fn run_subscription_billing(state: AppState, poll: Duration, mut stop: tokio::sync::watch::Receiver<bool>) {
+    let mut ticker = tokio::time::interval(poll);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop.changed() => break,
+        }
+        match bill_due_subscriptions(&state).await {
+            Ok(0) => {}
+            Ok(billed) => info!(billed, "subscription cycles processed"),
+            Err(err) => error!(error = %err, "subscription billing scan failed"),
+        }
+        if *stop.borrow() {
+            break;
+        }
+    }
+    info!("subscription billing stopped");
+}
+
+// A subscription row claimed for billing
+pub(crate) struct DueSubscription {
+    pub(crate) id: i32,
+    pub(crate) subscription_uuid: Uuid,
+    pub(crate) merchant_id: i32,
+    pub(crate) amount: i64,
+    pub(crate) currency: String,
+    pub(crate) payment_token: String,
+    pub(crate) interval: BillingInterval,
+    pub(crate) next_billing_at: DateTime<Utc>,
+    pub(crate) failed_attempts: i32,
+}
+
+// How long a claimed cycle is hidden from other scans. Only matters if this instance dies
+// mid-charge; the cycle is then retried under the same idempotency key.
+pub(crate) const SUBSCRIPTION_CLAIM_LEASE: chrono::Duration = chrono::Duration::minutes(10);
+
+// Claims due subscriptions one at a time by pushing next_attempt_at past the lease, so no row
+// lock is held while the gateway is called. Returns how many cycles were attempted.
+pub(crate) async fn bill_due_subscriptions(state: &AppState) -> Result<usize, AppError> {
+    for billed in 0..SUBSCRIPTION_BATCH_SIZE {
+        let now = Utc::now();
+        let Some(due) = sqlx::query_as!(
+            DueSubscription,
+            r#"
+            UPDATE subscriptions
+            SET next_attempt_at = $2
+            WHERE id = (
+                SELECT id FROM subscriptions
+                WHERE status = 'active' AND next_attempt_at <= $1
+                ORDER BY next_attempt_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, subscription_uuid, merchant_id, amount, currency, payment_token,
+                      billing_interval AS "interval: BillingInterval", next_billing_at, failed_attempts
+            "#,
+            now,
+            now + SUBSCRIPTION_CLAIM_LEASE
+        )
+        .fetch_optional(&state.db)
+        .await?
+        else {
+            return Ok(billed);
+        };
+
+        bill_subscription_cycle(state, due).await?;
+    }
+
+    Ok(SUBSCRIPTION_BATCH_SIZE)
+}
+
+// Charges one cycle through the regular payment path, then schedules the next cycle or,
+// on failure, the next retry with exponential backoff until the retries run out
+#[tracing::instrument(name = "subscription_cycle", skip_all, fields(subscription_uuid = %due.subscription_uuid))]
+pub(crate) async fn bill_subscription_cycle(state: &AppState, due: DueSubscription) -> Result<(), AppError> {
+    let request_id = Uuid::new_v4().to_string();
+    // Keyed by cycle and attempt number, so a cycle charged just before a crash is replayed
+    // rather than charged twice, while a dunning retry is a fresh attempt
+    let origin = ChargeOrigin {
+        request_id: &request_id,
+        merchant: MerchantId(due.merchant_id),
+        idempotency_key: Some(format!(
+            "subscription:{}:{}:{}",
+            due.subscription_uuid,
+            due.next_billing_at.timestamp(),
+            due.failed_attempts
+        )),
+        subscription_id: Some(due.id),
+    };
+    let payment = PaymentRequest {
+        amount: due.amount,
+        amount_decimal: None,
+        currency: due.currency,
+        instrument: PaymentInstrument::Token { payment_token: due.payment_token },
+        capture: true,
+        settlement_currency: None,
+        metadata: None,
+    };
+    let outcome = CURRENT_REQUEST_ID.scope(request_id.clone(), charge_payment(state, origin, payment)).await;
+
+    let reason = match outcome {
+        Ok(response) if response.success => {
+            let next_billing_at = due.interval.advance(due.next_billing_at);
+            info!(transaction_uuid = %response.transaction_id, %next_billing_at, "subscription cycle paid");
+            sqlx::query!(
+                r#"
+                UPDATE subscriptions
+                SET next_billing_at = $1, next_attempt_at = $1, failed_attempts = 0
+                WHERE id = $2
+                "#,
+                next_billing_at,
+                due.id
+            )
+            .execute(&state.db)
+            .await?;
+            return Ok(());
+        }
+        Ok(response) => response.message,
+        Err(AppError::GatewayTimeout | AppError::GatewayAmbiguous(_) | AppError::Conflict(PAYMENT_IN_PROGRESS, _)) => {
+            // The charge may have landed. Counting a failed attempt would change the key and
+            // could charge twice, so leave the row leased; the next claim replays this cycle.
+            warn!(subscription_uuid = %due.subscription_uuid, "subscription charge outcome unknown; will replay");
+            return Ok(());
+        }
+        Err(err) => err.to_string(),
+    };
+
+    let failed_attempts = due.failed_attempts + 1;
+    let status = if failed_attempts > state.dunning.max_retries {
+        warn!(failed_attempts, reason, "subscription retries exhausted; marking past due");
+        SubscriptionStatus::PastDue
+    } else {
+        warn!(failed_attempts, reason, "subscription cycle failed; will retry");
+        SubscriptionStatus::Active
+    };
+    // retry_delay, then twice that, and so on
+    let backoff = state.dunning.retry_delay * 2i32.pow((failed_attempts - 1).clamp(0, 16) as u32);
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = $1, failed_attempts = $2, next_attempt_at = $3
+        WHERE id = $4
+        "#,
+        status as _,
+        failed_attempts,
+        Utc::now() + backoff,
+        due.id
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(())
+}
+
+// Background task: settles transactions left Pending by a crash or a gateway timeout
+pub(crate) async fn run_reconciliation(
+    state: AppState,
+    poll: Duration,
+    pending_after: chrono::Duration,
+    mut stop: tokio::sync::watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(poll);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop.changed() => break,
+        }
+        match reconcile_pending_transactions(&state, pending_after).await {
+            Ok(0) => {}
+            Ok(reconciled) => info!(reconciled, "pending transactions reconciled"),
+            Err(err) => error!(error = %err, "reconciliation scan failed"),
+        }
+        if *stop.borrow() {
+            break;
+        }
+    }
+    info!("reconciliation stopped");
+}
+
+// Background task: applies the retention policy to transactions past the retention period
+pub(crate) async fn run_retention(
+    state: AppState,
+    poll: Duration,
+    policy: RetentionPolicy,
+    mut stop: tokio::sync::watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(poll);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    info!(after_days = policy.after_days, mode = policy.mode.as_str(), "data retention enabled");
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop.changed() => break,
+        }
+        match purge_expired_transactions(&state.db, policy).await {
+            Ok(0) => {}
+            Ok(affected) => info!(affected, mode = policy.mode.as_str(), "expired transactions purged"),
+            Err(err) => error!(error = %err, "retention purge failed"),
+        }
+        if *stop.borrow() {
+            break;
+        }
+    }
+    info!("data retention stopped");
+}
+
+// Anonymize or delete settled transactions created more than `after_days` ago, in batches so
+// no single statement holds locks on a large backlog. Pending rows are left for
+// reconciliation. Returns how many rows were affected.
+pub(crate) async fn purge_expired_transactions(db: &PgPool, policy: RetentionPolicy) -> Result<u64, AppError> {
+    let cutoff = Utc::now() - chrono::Duration::days(policy.after_days);
+    let mut affected = 0;
+
+    loop {
+        let mut tx = db.begin().await?;
+        let batch = match policy.mode {
+            RetentionMode::Anonymize => sqlx::query!(
+                r#"
+                UPDATE transactions
+                SET masked_card_number = $1, card_fingerprint = NULL, card_expiry_month = NULL, card_expiry_year = NULL,
+                    metadata = NULL, request_hash = NULL, anonymized_at = NOW(), version = version + 1
+                WHERE id IN (
+                    SELECT id FROM transactions
+                    WHERE created_at < $2 AND status <> 'pending' AND anonymized_at IS NULL
+                    ORDER BY id
+                    LIMIT $3
+                    FOR UPDATE SKIP LOCKED
+                )
+                "#,
+                ANONYMIZED_CARD_MASK,
+                cutoff,
+                RETENTION_BATCH_SIZE
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected(),
+            RetentionMode::Delete => {
+                // Lifts the append-only trigger on transaction_events for this SQL transaction only
+                sqlx::query!("SELECT set_config('payments.retention_purge', 'on', true)")
+                    .fetch_one(&mut *tx)
+                    .await?;
+                sqlx::query!(
+                    r#"
+                    WITH expired AS (
+                        SELECT id FROM transactions
+                        WHERE created_at < $1 AND status <> 'pending'
+                        ORDER BY id
+                        LIMIT $2
+                        FOR UPDATE SKIP LOCKED
+                    ), events AS (
+                        DELETE FROM transaction_events WHERE transaction_id IN (SELECT id FROM expired)
+                    )
+                    DELETE FROM transactions WHERE id IN (SELECT id FROM expired)
+                    "#,
+                    cutoff,
+                    RETENTION_BATCH_SIZE
+                )
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+            }
+        };
+        tx.commit().await?;
+
+        affected += batch;
+        metrics::counter!("transactions_retention_purged_total", "mode" => policy.mode.as_str()).increment(batch);
+        if batch < RETENTION_BATCH_SIZE as u64 {
+            return Ok(affected);
+        }
+    }
+}
+
+// Ask the gateway what became of each transaction Pending for longer than `pending_after`, and
+// record that. The threshold must outlast a charge in flight (or queued), or a live payment
+// could be settled under it. Only rows still Pending are written, so instances running this
+// side by side never apply an outcome twice. Returns how many rows were settled.
+pub(crate) async fn reconcile_pending_transactions(state: &AppState, pending_after: chrono::Duration) -> Result<usize, AppError> {
+    let cutoff = Utc::now() - pending_after;
+    let stuck = query_transactions!(
+        "WHERE status = 'pending' AND created_at < $1 ORDER BY created_at LIMIT $2",
+        cutoff,
+        RECONCILIATION_BATCH_SIZE
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut reconciled = 0;
+    for transaction in stuck {
+        let gateway = gateway_for_transaction(state, &transaction);
+        let charge = match gateway.lookup_charge(&transaction).await {
+            Ok(Some(charge)) => charge,
+            // Authoritative: gateways that can't be sure answer with an error instead
+            Ok(None) => GatewayCharge::new(TransactionStatus::Failed, "Gateway has no record of the charge."),
+            Err(err) => {
+                // Left for the next scan
+                warn!(transaction_uuid = %transaction.transaction_uuid, gateway = gateway.name(), error = %err, "charge lookup failed");
+                continue;
+            }
+        };
+        if settle_pending_transaction(state, &transaction, gateway.name(), charge).await? {
+            reconciled += 1;
+        }
+    }
+
+    Ok(reconciled)
+}
+
+// Apply a looked-up outcome to a Pending row; false if something else settled it first
+pub(crate) async fn settle_pending_transaction(
+    state: &AppState,
+    transaction: &Transaction,
+    gateway: &'static str,
+    charge: GatewayCharge,
+) -> Result<bool, AppError> {
+    let status = match charge.status {
+        TransactionStatus::Success | TransactionStatus::Authorized => charge.status,
+        _ => TransactionStatus::Failed,
+    };
+    let authorized_amount = (status == TransactionStatus::Authorized).then_some(transaction.amount);
+    let decline_reason = charge.decline_reason.filter(|_| status == TransactionStatus::Failed);
+
+    let mut tx = state.db.begin().await?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE transactions
+        SET status = $1, gateway = $2, gateway_ref = COALESCE($3, gateway_ref), authorized_amount = $4,
+            masked_card_number = COALESCE($5, masked_card_number), card_brand = COALESCE($6, card_brand),
+            decline_reason = $7, version = version + 1
+        WHERE id = $8 AND status = 'pending'
+        "#,
+        status as _,
+        gateway,
+        charge.gateway_ref,
+        authorized_amount,
+        charge.masked_card_number,
+        charge.card_brand as _,
+        decline_reason as _,
+        transaction.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+    record_event(&mut tx, transaction.id, TransactionEventType::for_charge(status), status, transaction.amount).await?;
+    tx.commit().await?;
+
+    info!(
+        transaction_uuid = %transaction.transaction_uuid,
+        gateway,
+        status = ?status,
+        reason = %charge.message,
+        "pending transaction reconciled"
+    );
+    metrics::counter!("transactions_reconciled_total", "status" => status.as_str()).increment(1);
+    publish_status(state, transaction.transaction_uuid, status);
+    if let Some(webhooks) = &state.webhooks {
+        webhooks.notify(PaymentEvent {
+            transaction_uuid: transaction.transaction_uuid,
+            status,
+            amount: transaction.amount,
+            currency: transaction.currency.clone(),
+            timestamp: Utc::now(),
+        });
+    }
+
+    Ok(true)
+}
+
+// Optional RFC3339 query parameter, converted to the naive UTC timestamps stored in the table
+pub(crate) fn parse_rfc3339_param(name: &str, value: Option<&str>) -> Result<Option<DateTime<Utc>>, AppError> {
+    value
+        .map(|v| {
+            DateTime::parse_from_rfc3339(v)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|_| AppError::BadRequest("INVALID_QUERY", format!("'{}' must be an RFC3339 timestamp.", name)))
+        })
+        .transpose()
+}
+
+pub(crate) const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct HealthParams {
+    #[serde(default)]
+    pub(crate) deep: bool, // Also probe the gateway's health URL
+}
+
+impl GatewayHealthProbe {
+    pub(crate) fn new(client: Client, url: &str) -> Self {
+        GatewayHealthProbe { client, url: url.into(), last: Arc::new(Mutex::new(None)) }
+    }
+
+    // Any 2xx within the timeout counts as up; the answer is reused for GATEWAY_HEALTH_CACHE_TTL
+    pub(crate) async fn is_reachable(&self) -> bool {
+        if let Some((checked_at, up)) = *self.last.lock().expect("gateway health probe mutex poisoned")
+            && checked_at.elapsed() < GATEWAY_HEALTH_CACHE_TTL
+        {
+            return up;
+        }
+
+        let up = matches!(
+            tokio::time::timeout(GATEWAY_HEALTH_TIMEOUT, self.client.get(&*self.url).headers(trace_context_headers()).send()).await,
+            Ok(Ok(response)) if response.status().is_success()
+        );
+        if !up {
+            warn!(url = %self.url, "gateway health probe failed");
+        }
+
+        *self.last.lock().expect("gateway health probe mutex poisoned") = Some((Instant::now(), up));
+        up
+    }
+}
+
+// Liveness + database check for load balancers. `?deep=true` also reports gateway
+// reachability; only the database is critical, since a gateway outage is already handled by
+// breakers and failover and taking every instance out of rotation would not help.
+pub(crate) async fn health_check(
+    State(state): State<AppState>,
+    params: Option<Query<HealthParams>>,
+) -> impl IntoResponse {
+    let deep = params.is_some_and(|Query(params)| params.deep);
+    let db_ok = matches!(
+        tokio::time::timeout(
+            HEALTH_CHECK_TIMEOUT,
+            sqlx::query_scalar!("SELECT 1 AS one").fetch_one(&state.db),
+        ).await,
+        Ok(Ok(_))
+    );
+
+    let mut gateway = serde_json::json!({ "circuit_breakers": state.gateways.breaker_states() });
+    let mut gateway_ok = true;
+    if deep {
+        let reachable = match &state.gateway_probe {
+            Some(probe) => Some(probe.is_reachable().await),
+            None => None, // No GATEWAY_HEALTH_URL configured
+        };
+        gateway_ok = reachable != Some(false);
+        gateway["reachable"] = serde_json::json!(reachable);
+        let (payments, declines) = state.decline_rate.counts_at(Instant::now());
+        gateway["decline_rate"] = serde_json::json!({
+            "window_minutes": state.decline_rate.window.as_secs() / 60,
+            "payments": payments,
+            "declines": declines,
+            "rate": DeclineRate::rate(payments, declines),
+        });
+    }
+
+    let (status, summary) = match (db_ok, gateway_ok) {
+        (false, _) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+        (true, false) => (StatusCode::OK, "degraded"),
+        (true, true) => (StatusCode::OK, "ok"),
+    };
+
+    (status, Json(serde_json::json!({
+        "status": summary,
+        "database": {
+            "reachable": db_ok,
+            "connections": state.db.size(),
+            "idle_connections": state.db.num_idle(),
+        },
+        "gateway": gateway,
+    })))
+}
+
+// Prometheus scrape endpoint; pool gauges are sampled at scrape time
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> String {
+    let active = (state.db.size() as usize).saturating_sub(state.db.num_idle());
+    metrics::gauge!("db_connections_active").set(active as f64);
+    metrics::gauge!("db_connections_total").set(state.db.size() as f64);
+    // A quiet window reads as 0; alert rules should also require some volume in it
+    let (payments, declines) = state.decline_rate.counts_at(Instant::now());
+    metrics::gauge!("payment_decline_rate").set(DeclineRate::rate(payments, declines).unwrap_or(0.0));
+    metrics::gauge!("payment_decline_rate_window_payments").set(payments as f64);
+
+    state.metrics.render()
+}
+
+// Liveness probe: the process is up and serving
+pub(crate) async fn livez() -> StatusCode {
+    StatusCode::OK
+}
+
+// Kept short: orchestrators probe often and time out on their own
+pub(crate) const READINESS_DB_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Readiness probe: startup finished, the database answers and the gateway key is set.
+// The pool is queried rather than counted: with DB_MIN_CONNECTIONS=0 an idle instance has
+// no open connections, and that alone must not take it out of rotation.
+pub(crate) async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let started = state.ready.load(Ordering::Acquire);
+    let db_connected = matches!(
+        tokio::time::timeout(
+            READINESS_DB_TIMEOUT,
+            sqlx::query_scalar!("SELECT 1 AS one").fetch_one(&state.db),
+        ).await,
+        Ok(Ok(_))
+    );
+    let api_key_present = !state.api_key.current().is_empty();
+
+    let ready = started && db_connected && api_key_present;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    (status, Json(serde_json::json!({
+        "ready": ready,
+        "started": started,
+        "database_connected": db_connected,
+        "api_key_present": api_key_present,
+    })))
+}
+
+// Resolves on Ctrl+C or SIGTERM so axum can drain in-flight requests
+pub(crate) async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler.");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler.")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("shutdown signal received, draining in-flight requests");
+}
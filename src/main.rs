@@ -1,274 +1,484 @@
-use axum::{
-    extract::{Json, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::{post},
-    Router,
-};
 use tokio::net::TcpListener;
-use serde::{Deserialize, Serialize};
-use chrono::{Utc, NaiveDateTime};
-use uuid::Uuid;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use chrono::Utc;
 use dotenvy::dotenv;
-use std::{env, time::Duration};
-use sqlx::{PgPool, postgres::PgPoolOptions};
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 use anyhow::Result;
 use reqwest::Client;
-
-
-// --- 0. STATE MANAGEMENT ---
-
-// State struct holding DB pool, API key, and Reqwest client
-#[derive(Clone)]
-struct AppState {
-    db: PgPool,
-    api_key: String,
-    http_client: Client,
-}
-
-// --- 1. MODELS ---
-
-// Payment Request (Inbound Data)
-#[derive(Debug, Deserialize)]
-pub struct PaymentRequest {
-    pub amount: i32, // Cents/Minor Unit
-    pub currency: String, // E.g., "USD", "TRY"
-    pub card_number: String,
-    pub expiry_month: i32,
-    pub expiry_year: i32,
-    pub cvv: String,
-}
-
-// Payment Response (Outbound Data)
-#[derive(Debug, Serialize)]
-pub struct PaymentResponse {
-    pub success: bool,
-    pub transaction_id: String,
-    pub message: String,
-    pub timestamp: NaiveDateTime,
-}
-
-impl PaymentResponse {
-    pub fn new_success(transaction_id: String, message: String) -> Self {
-        PaymentResponse {
-            success: true,
-            transaction_id,
-            message,
-            timestamp: Utc::now().naive_utc(),
+use tracing::{error, info, warn};
+use tracing_subscriber::{
+    fmt::{format::{JsonFields, Writer}, FmtContext, FormatEvent, FormattedFields},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use axum_server::tls_rustls::RustlsConfig;
+
+mod config;
+mod db;
+mod errors;
+mod gateway;
+mod handlers;
+mod middleware;
+mod models;
+mod routes;
+mod validation;
+#[cfg(test)]
+mod tests;
+
+use config::*;
+use db::*;
+use errors::*;
+use gateway::*;
+use handlers::*;
+use middleware::*;
+use models::*;
+use routes::*;
+use validation::*;
+
+// --- MAIN FUNCTION ---
+
+// One JSON object per line for log aggregators. Fields of the enclosing spans (request_id,
+// transaction_uuid, ...) are lifted to the top level next to the event's own fields, so they
+// can be queried directly instead of digging through a nested span list.
+struct JsonLogFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonLogFormat
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        let metadata = event.metadata();
+        let mut fields = serde_json::Map::new();
+        fields.insert("timestamp".into(), Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true).into());
+        fields.insert("level".into(), metadata.level().as_str().into());
+        fields.insert("target".into(), metadata.target().into());
+
+        // Outermost span first, so inner spans and the event win on a name clash
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                if let Some(recorded) = span.extensions().get::<FormattedFields<JsonFields>>()
+                    && let Ok(serde_json::Value::Object(span_fields)) = serde_json::from_str(recorded)
+                {
+                    fields.extend(span_fields);
+                }
+            }
         }
-    }
+        event.record(&mut JsonFieldVisitor(&mut fields));
 
-    pub fn new_failure(transaction_id: String, message: String) -> Self {
-        PaymentResponse {
-            success: false,
-            transaction_id,
-            message,
-            timestamp: Utc::now().naive_utc(),
-        }
+        writeln!(writer, "{}", serde_json::Value::Object(fields))
     }
 }
 
-// TRANSACTION MODELS
-#[derive(Debug, Serialize, sqlx::FromRow)]
-pub struct Transaction {
-    pub id: i32,
-    pub transaction_uuid: Uuid,
-    pub amount: i32,
-    pub currency: String,
-    pub status: String,
-    pub masked_card_number: String,
-    pub created_at: NaiveDateTime,
-}
+struct JsonFieldVisitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
 
-// --- 2. ERROR HANDLING (ADVANCED) ---
-
-// Advanced error handling: AppError
-#[derive(Debug)]
-enum AppError {
-    InternalServerError(String),
-    BadRequest(String),
-    DatabaseError(sqlx::Error),
-    EnvironmentError(String),
-    GatewayError(String),
-}
-
-impl std::fmt::Display for AppError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+impl tracing::field::Visit for JsonFieldVisitor<'_> {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
     }
-}
 
-impl std::error::Error for AppError {}
-
-impl From<sqlx::Error> for AppError {
-    fn from(err: sqlx::Error) -> Self {
-        AppError::DatabaseError(err)
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
     }
-}
 
-impl From<reqwest::Error> for AppError {
-    fn from(err: reqwest::Error) -> Self {
-        AppError::GatewayError(format!("External gateway call failed: {}", err))
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
     }
-}
 
-// Handle error conversion
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError::InternalServerError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
-            AppError::DatabaseError(err) => {
-                eprintln!("SQLx Error: {:?}", err);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR, 
-                    "Database operation failed.".to_string()
-                )
-            },
-            AppError::EnvironmentError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            AppError::GatewayError(msg) => (StatusCode::BAD_GATEWAY, msg),
-        };
-
-        (status, Json(serde_json::json!({"error": error_message}))).into_response()
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
     }
-}
-
 
-// --- 3. EXTERNAL GATEWAY SIMULATION ---
-
-async fn call_external_payment_gateway(
-    _client: &Client, 
-    api_key: &str, 
-    data: &PaymentRequest, 
-    _transaction_uuid: &Uuid
-) -> Result<(String, String), AppError> { 
-    
-    if api_key.is_empty() {
-        return Err(AppError::EnvironmentError("API Key is missing.".to_string()));
-    }
-    
-    // Simulation Rule: Card starting with 4000 fails
-    if data.card_number.starts_with("4000") {
-        return Ok(("FAILED".to_string(), "Card declined: Insufficient funds (Simulation).".to_string()));
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
     }
-    
-    println!("-> External Gateway Call Successful. Key Used: {}...", &api_key[..5]);
 
-    Ok(("SUCCESS".to_string(), "Payment successfully processed by external gateway.".to_string())) 
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().into(), format!("{:?}", value).into());
+    }
 }
 
+// Log level is controlled by RUST_LOG (e.g. RUST_LOG=rust_payment_api=debug);
+// LOG_FORMAT=json switches from human-readable output to one JSON object per line.
+// Setting OTEL_EXPORTER_OTLP_ENDPOINT (the collector's base URL) also exports spans over
+// OTLP/HTTP. Spans never carry card data: handlers use skip_all and record only masked fields.
+fn init_tracing() -> Result<Option<opentelemetry_sdk::trace::TracerProvider>, AppError> {
+    let log_layer = match env::var("LOG_FORMAT").as_deref().map(str::trim) {
+        Ok("json") => tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(JsonLogFormat)
+            .boxed(),
+        Ok("pretty") | Ok("") | Err(_) => tracing_subscriber::fmt::layer().boxed(),
+        Ok(other) => {
+            return Err(AppError::EnvironmentError(format!(
+                "LOG_FORMAT must be \"json\" or \"pretty\", got {:?}.", other
+            )));
+        }
+    };
+
+    let tracer_provider = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) if !endpoint.trim().is_empty() => {
+            // The exporter reads the endpoint (and OTEL_EXPORTER_OTLP_HEADERS etc.) itself
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .build()
+                .map_err(|e| AppError::EnvironmentError(format!("Invalid OTLP exporter configuration: {}", e)))?;
+            let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+                .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+                .with_resource(opentelemetry_sdk::Resource::new_with_defaults([
+                    KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+                ]))
+                .build();
+            opentelemetry::global::set_text_map_propagator(
+                opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+            );
+            Some(provider)
+        }
+        _ => None,
+    };
+    let otel_layer = tracer_provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME"))));
+
+    tracing_subscriber::registry()
+        .with(log_layer)
+        .with(otel_layer)
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    Ok(tracer_provider)
+}
 
-// --- 4. HANDLER FUNCTION ---
-
-async fn process_payment(
-    State(state): State<AppState>,
-    Json(payment_data): Json<PaymentRequest>,
-) -> Result<Json<PaymentResponse>, AppError> {
-    
-    // 1. Basic Validation
-    if payment_data.amount <= 0 {
-        return Err(AppError::BadRequest("Payment amount must be greater than zero.".to_string()));
-    }
-    if payment_data.card_number.len() < 12 || payment_data.card_number.len() > 19 {
-        return Err(AppError::BadRequest("Invalid card number.".to_string()));
-    }
-    
-    let masked_card = format!("XXXX-XXXX-XXXX-{}", &payment_data.card_number[payment_data.card_number.len() - 4..]);
-    let transaction_uuid = Uuid::new_v4();
+pub(crate) async fn load_tls_config(paths: &TlsPaths) -> Result<RustlsConfig, AppError> {
+    RustlsConfig::from_pem_file(&paths.cert_path, &paths.key_path).await.map_err(|e| {
+        AppError::EnvironmentError(format!(
+            "Failed to load TLS certificate {} and key {}: {}",
+            paths.cert_path.display(),
+            paths.key_path.display(),
+            e
+        ))
+    })
+}
 
-    // 2. EXTERNAL GATEWAY CALL
-    let (status, response_message) = call_external_payment_gateway(
-        &state.http_client, 
-        &state.api_key, 
-        &payment_data, 
-        &transaction_uuid
-    ).await?;
+// Latest modification time of the certificate and key; a renewal rewrites at least one
+pub(crate) fn tls_files_modified(paths: &TlsPaths) -> Option<SystemTime> {
+    [&paths.cert_path, &paths.key_path]
+        .into_iter()
+        .filter_map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .max()
+}
 
-    
-    // 3. PERSIST TRANSACTION TO DATABASE
-    sqlx::query!(
-        r#"
-        INSERT INTO transactions (transaction_uuid, amount, currency, status, masked_card_number)
-        VALUES ($1, $2, $3, $4, $5)
-        "#,
-        transaction_uuid,
-        payment_data.amount,
-        payment_data.currency,
-        status,
-        masked_card
-    )
-    .execute(&state.db)
-    .await?; 
-
-
-    // 4. Send Response to Customer
-    
-    if status == "SUCCESS" {
-        println!("Successful payment: {} ({} {})", 
-            masked_card, payment_data.amount, payment_data.currency);
-        
-        Ok(Json(PaymentResponse::new_success(
-            transaction_uuid.to_string(),
-            response_message
-        )))
-    } else {
-        eprintln!("Failed payment: {} ({} {})", 
-            masked_card, payment_data.amount, payment_data.currency);
-
-        Ok(Json(PaymentResponse::new_failure(
-            transaction_uuid.to_string(),
-            response_message
-        )))
+// Picks up renewed certificates without a restart: new connections get them, open ones keep
+// theirs. A renewal caught half-written fails to load and is retried on the next poll.
+pub(crate) async fn watch_tls_certificates(tls: RustlsConfig, paths: TlsPaths, poll: Duration) {
+    let mut loaded = tls_files_modified(&paths);
+    let mut ticker = tokio::time::interval(poll);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let modified = tls_files_modified(&paths);
+        if modified == loaded {
+            continue;
+        }
+        match tls.reload_from_pem_file(&paths.cert_path, &paths.key_path).await {
+            Ok(()) => {
+                info!(cert_path = %paths.cert_path.display(), "TLS certificate reloaded");
+                loaded = modified;
+            }
+            Err(e) => warn!(error = %e, "failed to reload TLS certificate; keeping the current one"),
+        }
     }
 }
 
-// --- 5. MAIN FUNCTION AND ROUTE SETUP ---
-
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
+
+    let tracer_provider = init_tracing()?;
+    if tracer_provider.is_some() {
+        info!("exporting traces over OTLP");
+    }
     
+    let config = Config::from_env()?;
+
     // Database connection
-    let database_url = env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set in the .env file");
+    let db_pool = connect_with_retry(&config).await?;
 
-    let db_pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("Failed to create PostgreSQL connection pool.");
+    info!("successfully connected to the database");
 
-    println!("-> Successfully connected to the database.");
-    
-    let api_key = env::var("PAYMENT_GATEWAY_API_KEY")
-        .expect("PAYMENT_GATEWAY_API_KEY must be set in the .env file");
+    run_migrations(&db_pool).await?;
+
+    let merchant_keys = register_merchants(&db_pool, &config.merchant_api_keys).await?;
 
     // Initialize reqwest client
     let http_client = Client::builder()
-        .timeout(Duration::from_secs(10)) 
+        .timeout(Duration::from_secs(config.gateway_timeout_secs)) 
         .build()
         .expect("Failed to create HTTP client.");
-        
-    let app_state = AppState { db: db_pool, api_key, http_client };
 
-    // Application routes
-    // Rate limiting katmanı kaldırıldı.
-    let app = Router::new()
-        .route("/api/payment", post(process_payment))
-        // .layer(rate_limit_layer) <--- KALDIRILDI
-        .with_state(app_state);
+    // Request latency is exported as a real histogram so SLOs can be computed across instances
+    let metrics = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full("http_request_duration_seconds".to_string()), HTTP_LATENCY_BUCKETS)
+        .expect("latency buckets are valid")
+        .install_recorder()
+        .expect("Failed to install Prometheus metrics recorder.");
+
+    let gateway_api_key = GatewayApiKey::new(&config.api_key).with_source(config.api_key_file.as_deref());
+
+    // One shared instance (and breaker) per gateway kind, however many chains include it
+    let test_cards = TestCards::new(config.test_cards.clone());
+    let new_breaker = || CircuitBreaker::new(
+        config.circuit_breaker_threshold,
+        Duration::from_secs(config.circuit_breaker_cooldown_secs),
+    );
+    let real_gateway = RoutedGateway {
+        gateway: Arc::new(RealGateway {
+            client: http_client.clone(),
+            api_key: gateway_api_key.clone(),
+            url: config.gateway_url.clone(),
+            charge_timeout: Duration::from_millis(config.gateway_charge_timeout_ms),
+            test_cards: test_cards.clone(),
+            signing: config.gateway_signing.clone(),
+        }),
+        breaker: new_breaker(),
+    };
+    let mock_gateway = RoutedGateway { gateway: Arc::new(MockGateway { test_cards }), breaker: new_breaker() };
+    let chain_for = |modes: &[GatewayMode]| -> Vec<RoutedGateway> {
+        modes
+            .iter()
+            .map(|mode| match mode {
+                GatewayMode::Real => real_gateway.clone(),
+                GatewayMode::Mock => mock_gateway.clone(),
+            })
+            .collect()
+    };
+    let chain_names = |chain: &[RoutedGateway]| -> Vec<&'static str> {
+        chain.iter().map(|routed| routed.gateway.name()).collect()
+    };
+
+    let mut amount_limits = AmountLimits::new(AmountRange { min: 1, max: config.max_payment_amount });
+    for (currency, range) in &config.amount_limits {
+        amount_limits = amount_limits.with_currency(currency, *range);
+        info!(currency, min = range.min, max = range.max, "payment amount limits added");
+    }
+
+    let mut gateways = GatewayRouter::new(chain_for(&config.default_gateways));
+    info!(gateways = ?chain_names(&gateways.default), "default payment gateway chain selected");
+    for (currency, modes) in &config.gateway_routes {
+        gateways = gateways.with_route(currency, chain_for(modes));
+        info!(currency, gateways = ?chain_names(gateways.route(currency)), "payment gateway route added");
+    }
+
+    let ready = Arc::new(AtomicBool::new(false));
+    let (payment_queue, queued_payments) = mpsc::channel(config.payment_queue_capacity);
+    let app_state = AppState {
+        db: db_pool,
+        api_key: gateway_api_key,
+        amount_limits,
+        ready: ready.clone(),
+        rate_limiter: RateLimiter::new(config.rate_limit_per_minute),
+        merchant_keys: Arc::new(merchant_keys),
+        gateway_retry: RetryPolicy {
+            max_attempts: config.gateway_retry_attempts,
+            base_delay: Duration::from_millis(config.gateway_retry_base_delay_ms),
+        },
+        metrics,
+        gateways,
+        cors_allowed_origins: Arc::new(config.cors_allowed_origins.clone()),
+        max_request_body_bytes: config.max_request_body_bytes,
+        http_logging: config.http_logging,
+        webhooks: config.webhook.as_ref().map(|(url, secret)| WebhookNotifier {
+            client: http_client.clone(),
+            url: url.as_str().into(),
+            secret: secret.as_bytes().into(),
+            max_attempts: config.webhook_retry_attempts,
+        }),
+        request_signing_secret: config.request_signing_secret.as_deref().map(|secret| secret.as_bytes().into()),
+        nonces: NonceCache::new(config.replay_window_secs),
+        gateway_probe: config.gateway_health_url.as_deref().map(|url| GatewayHealthProbe::new(http_client.clone(), url)),
+        velocity: VelocityLimit {
+            max_charges: config.velocity_max_charges,
+            window: chrono::Duration::minutes(config.velocity_window_minutes),
+        },
+        decline_rate: DeclineRate::new(Duration::from_secs(config.decline_rate_window_minutes * 60)),
+        card_fingerprint_salt: config.card_fingerprint_salt.as_bytes().into(),
+        store_card_expiry: config.store_card_expiry,
+        fx: config.fx_rates_url.as_ref().map(|url| FxConverter::new(
+            HttpFxRateProvider { client: http_client.clone(), url: url.clone() },
+            Duration::from_secs(config.fx_rates_ttl_secs),
+            config.fx_rounding,
+        )),
+        ip_allowlist: IpAllowlist {
+            allowed: config.ip_allowlist.as_slice().into(),
+            trusted_proxies: config.trusted_proxies.as_slice().into(),
+        },
+        dunning: DunningPolicy {
+            max_retries: config.subscription_max_retries.min(i32::MAX as u32) as i32,
+            retry_delay: chrono::Duration::minutes(config.subscription_retry_delay_minutes),
+        },
+        payment_queue,
+        payment_slots: Arc::new(Semaphore::new(config.max_concurrent_payments)),
+        status_updates: broadcast::channel(STATUS_UPDATES_CAPACITY).0,
+    };
+
+    let db = app_state.db.clone();
+    let app = build_router(app_state.clone());
+
+    let (stop_workers, workers_stopped) = tokio::sync::watch::channel(false);
+    let billing = tokio::spawn(run_subscription_billing(
+        app_state.clone(),
+        Duration::from_secs(config.subscription_poll_secs),
+        workers_stopped.clone(),
+    ));
+    let payment_worker = tokio::spawn(run_payment_worker(
+        app_state.clone(),
+        queued_payments,
+        config.payment_workers,
+        workers_stopped.clone(),
+    ));
+    let reconciliation = tokio::spawn(run_reconciliation(
+        app_state.clone(),
+        Duration::from_secs(config.reconciliation_poll_secs),
+        chrono::Duration::seconds(config.reconciliation_pending_after_secs),
+        workers_stopped.clone(),
+    ));
+    let retention = match config.retention {
+        Some(policy) => Some(tokio::spawn(run_retention(
+            app_state.clone(),
+            Duration::from_secs(config.retention_poll_secs),
+            policy,
+            workers_stopped,
+        ))),
+        None => {
+            info!("data retention disabled; set RETENTION_ENABLED to purge old transactions");
+            None
+        }
+    };
+
+    // Metrics stay unauthenticated; METRICS_BIND_ADDR moves them off the public listener
+    let metrics_router = build_metrics_router(app_state);
+
+    let app = match config.metrics_bind_addr {
+        Some(metrics_addr) => {
+            let metrics_listener = TcpListener::bind(metrics_addr)
+                .await
+                .map_err(|e| AppError::InternalServerError(format!("Failed to start metrics listener: {}", e)))?;
+            info!(addr = %metrics_addr, "metrics endpoint listening");
+
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(metrics_listener, metrics_router).await {
+                    error!(error = %e, "metrics server error");
+                }
+            });
+            app
+        }
+        None => app.merge(metrics_router),
+    };
 
     // Bind and serve
-    let listener = TcpListener::bind("127.0.0.1:3000")
+    info!(bind_addr = %config.bind_addr, "resolved bind address");
+
+    let listener = TcpListener::bind(config.bind_addr)
         .await
         .map_err(|e| AppError::InternalServerError(format!("Failed to start TCP listener: {}", e)))?;
 
+    // Loaded before readiness so a bad certificate fails startup instead of every handshake
+    let tls = match &config.tls {
+        Some(paths) => {
+            let tls = load_tls_config(paths).await?;
+            tokio::spawn(watch_tls_certificates(
+                tls.clone(),
+                paths.clone(),
+                Duration::from_secs(config.tls_reload_poll_secs),
+            ));
+            Some(tls)
+        }
+        None => {
+            warn!("TLS disabled; set TLS_CERT_PATH and TLS_KEY_PATH unless a proxy terminates TLS");
+            None
+        }
+    };
+
     let addr = listener.local_addr().unwrap();
-    println!("-> Ultra Secure Payment API is running: http://{}", addr);
+    info!(%addr, tls = tls.is_some(), "Ultra Secure Payment API is running");
+
+    ready.store(true, Ordering::Release);
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            let shutdown = handle.clone();
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                shutdown.graceful_shutdown(None);
+            });
+
+            let listener = listener
+                .into_std()
+                .map_err(|e| AppError::InternalServerError(format!("Failed to hand over TCP listener: {}", e)))?;
+            axum_server::from_tcp_rustls(listener, tls)
+                .handle(handle)
+                .serve(app)
+                .await
+                .map_err(|e| AppError::InternalServerError(format!("Server error: {}", e)))?;
+        }
+        None => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .map_err(|e| AppError::InternalServerError(format!("Server error: {}", e)))?;
+        }
+    }
 
-    axum::serve(listener, app)
-        .await
-        .map_err(|e| AppError::InternalServerError(format!("Server error: {}", e)))?;
+    // Let charges in flight (and accepted async payments) finish before the pool goes away
+    let _ = stop_workers.send(true);
+    if let Err(e) = billing.await {
+        error!(error = %e, "subscription billing task failed");
+    }
+    if let Err(e) = payment_worker.await {
+        error!(error = %e, "payment worker task failed");
+    }
+    if let Err(e) = reconciliation.await {
+        error!(error = %e, "reconciliation task failed");
+    }
+    if let Some(retention) = retention
+        && let Err(e) = retention.await
+    {
+        error!(error = %e, "retention task failed");
+    }
+
+    // Requests have drained; close the pool so Postgres isn't left with orphaned sessions.
+    // close() waits for checked-out connections to be returned before closing them.
+    let connections = db.size();
+    db.close().await;
+    info!(connections, "database pool closed");
+
+    // Flush spans still sitting in the batch exporter
+    if let Some(provider) = tracer_provider
+        && let Err(e) = provider.shutdown()
+    {
+        warn!(error = %e, "failed to flush traces");
+    }
+
+    info!("shutdown complete");
     
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,542 @@
+// --- MIDDLEWARE ---
+
+use axum::{
+    extract::{ConnectInfo, Extension, FromRequestParts, MatchedPath, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+use uuid::Uuid;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+use tracing::{debug, warn, Instrument};
+
+use crate::{config::*, errors::*};
+
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// Correlation ID for the current request, available as an extension
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+
+// The merchant the API key belongs to (merchants.id); every query on their behalf filters on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MerchantId(pub(crate) i32);
+
+// What an API key may do, as a bit set; admin implies every other role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Roles(pub(crate) u8);
+
+impl Roles {
+    pub(crate) const CHARGE: u8 = 1;
+    pub(crate) const REFUND: u8 = 1 << 1;
+    pub(crate) const READ: u8 = 1 << 2;
+    pub(crate) const ADMIN: u8 = 1 << 3;
+    pub(crate) const ALL: Roles = Roles(Self::CHARGE | Self::REFUND | Self::READ | Self::ADMIN);
+
+    pub(crate) const NAMES: [(&'static str, u8); 4] = [
+        ("charge", Self::CHARGE),
+        ("refund", Self::REFUND),
+        ("read", Self::READ),
+        ("admin", Self::ADMIN),
+    ];
+
+    // `+`-separated role names, e.g. "charge+read"
+    pub(crate) fn parse(list: &str) -> Result<Roles, String> {
+        list.split('+').map(str::trim).try_fold(Roles(0), |roles, name| {
+            Self::NAMES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, bit)| Roles(roles.0 | bit))
+                .ok_or_else(|| format!("unknown role {:?}", name))
+        })
+    }
+
+    pub(crate) fn name(role: u8) -> &'static str {
+        Self::NAMES.iter().find(|(_, bit)| *bit == role).map_or("unknown", |(name, _)| name)
+    }
+
+    pub(crate) fn allows(self, role: u8) -> bool {
+        self.0 & (role | Self::ADMIN) != 0
+    }
+}
+
+// Handler argument that rejects the request with 403 unless the API key holds ROLE
+pub(crate) struct RequireRole<const ROLE: u8>;
+
+pub(crate) type ChargeRole = RequireRole<{ Roles::CHARGE }>;
+pub(crate) type RefundRole = RequireRole<{ Roles::REFUND }>;
+pub(crate) type ReadRole = RequireRole<{ Roles::READ }>;
+pub(crate) type AdminRole = RequireRole<{ Roles::ADMIN }>;
+
+#[async_trait]
+impl<S: Send + Sync, const ROLE: u8> FromRequestParts<S> for RequireRole<ROLE> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // require_api_key inserts the roles; without them nothing is allowed
+        let roles = parts.extensions.get::<Roles>().copied().unwrap_or(Roles(0));
+        if roles.allows(ROLE) {
+            Ok(RequireRole)
+        } else {
+            Err(AppError::Forbidden(format!("This API key lacks the {} role.", Roles::name(ROLE))))
+        }
+    }
+}
+
+tokio::task_local! {
+    // Same ID, reachable from code without the request (error responses)
+    pub(crate) static CURRENT_REQUEST_ID: String;
+    // Negotiated from Accept-Language by `negotiate_locale`
+    pub(crate) static CURRENT_LOCALE: Locale;
+}
+
+// Picks the response language and says which one was used
+pub(crate) async fn negotiate_locale(request: Request, next: Next) -> Response {
+    let locale = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(Locale::En, Locale::negotiate);
+
+    let mut response = CURRENT_LOCALE.scope(locale, next.run(request)).await;
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(locale.tag()));
+    headers.append(header::VARY, HeaderValue::from_static("accept-language"));
+
+    response
+}
+
+// Reuses a sane incoming X-Request-Id or mints a UUID, then tags every event in the request with it
+pub(crate) async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        duration_ms = tracing::field::Empty, // Filled in by response_time
+    );
+
+    let mut response = CURRENT_REQUEST_ID
+        .scope(id.clone(), next.run(request))
+        .instrument(span)
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
+
+pub(crate) const RESPONSE_TIME_HEADER: &str = "X-Response-Time-Ms";
+pub(crate) const HTTP_LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Times everything inside the request span, body extraction included, and reports it
+// in a header, on the span and as a per-route histogram
+pub(crate) async fn response_time(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    // The route template, not the raw path, so UUIDs don't explode the label set
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+
+    let mut response = next.run(request).await;
+
+    let elapsed = started.elapsed();
+    let millis = elapsed.as_secs_f64() * 1000.0;
+    tracing::Span::current().record("duration_ms", millis);
+    metrics::histogram!(
+        "http_request_duration_seconds",
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string(),
+    )
+    .record(elapsed.as_secs_f64());
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.3}", millis)) {
+        response.headers_mut().insert(RESPONSE_TIME_HEADER, value);
+    }
+
+    response
+}
+
+// Never logged as sent, whatever the level: card fields anywhere in a JSON body, and credentials
+pub(crate) const REDACTED_FIELDS: &[&str] = &["card_number", "cvv"];
+pub(crate) const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "x-api-key"];
+pub(crate) const REDACTED: &str = "[REDACTED]";
+// Digit runs this long (spaces and dashes aside) could be a PAN under a field name we don't
+// know, so they go too
+pub(crate) const MIN_REDACTED_DIGITS: usize = 12;
+
+pub(crate) fn longest_digit_run(text: &str) -> usize {
+    let (mut longest, mut run) = (0, 0);
+    for c in text.chars().filter(|c| !matches!(c, ' ' | '-')) {
+        run = if c.is_ascii_digit() { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    longest
+}
+
+pub(crate) fn redact_json(value: &mut serde_json::Value) {
+    use serde_json::Value;
+
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                if REDACTED_FIELDS.contains(&name.as_str()) {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        Value::String(text) if longest_digit_run(text) >= MIN_REDACTED_DIGITS => {
+            *value = Value::String(REDACTED.to_string());
+        }
+        Value::Number(number) if longest_digit_run(&number.to_string()) >= MIN_REDACTED_DIGITS => {
+            *value = Value::String(REDACTED.to_string());
+        }
+        _ => {}
+    }
+}
+
+// A body as it may be logged. Anything that isn't JSON can't be searched for card data, so
+// only its size is.
+pub(crate) fn loggable_body(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return String::new();
+    }
+    match serde_json::from_slice::<serde_json::Value>(bytes) {
+        Ok(mut body) => {
+            redact_json(&mut body);
+            body.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", bytes.len()),
+    }
+}
+
+pub(crate) fn loggable_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) { REDACTED } else { value.to_str().unwrap_or("<binary>") };
+            format!("{}: {}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Opt-in (HTTP_LOGGING) and only at debug level, since bodies are buffered to be logged. Only
+// JSON responses are buffered; CSV exports and event streams pass through unlogged.
+pub(crate) async fn log_http(State(state): State<AppState>, request: Request, next: Next) -> Result<Response, AppError> {
+    if !state.http_logging || !tracing::enabled!(tracing::Level::DEBUG) {
+        return Ok(next.run(request).await);
+    }
+    let started = Instant::now();
+
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, state.max_request_body_bytes)
+        .await
+        .map_err(|_| AppError::PayloadTooLarge("Request body is too large.".to_string()))?;
+    debug!(
+        method = %parts.method,
+        path = %parts.uri.path(),
+        headers = %loggable_headers(&parts.headers),
+        body = %loggable_body(&bytes),
+        "http request"
+    );
+
+    let response = next.run(Request::from_parts(parts, axum::body::Body::from(bytes))).await;
+
+    let (parts, body) = response.into_parts();
+    let is_json = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let (logged_body, body) = if is_json {
+        let bytes = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(|e| AppError::InternalServerError(format!("Failed to read response body: {}", e)))?;
+        (loggable_body(&bytes), axum::body::Body::from(bytes))
+    } else {
+        (String::new(), body)
+    };
+    debug!(
+        status = parts.status.as_u16(),
+        latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+        headers = %loggable_headers(&parts.headers),
+        body = %logged_body,
+        "http response"
+    );
+
+    Ok(Response::from_parts(parts, body))
+}
+
+pub(crate) const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+pub(crate) const RATE_LIMIT_PRUNE_THRESHOLD: usize = 10_000;
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_minute: u32) -> Self {
+        RateLimiter {
+            requests_per_minute,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Count a request; Err carries the seconds left in the client's window
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("rate limiter mutex poisoned");
+
+        if windows.len() > RATE_LIMIT_PRUNE_THRESHOLD {
+            windows.retain(|_, (started, _)| now.duration_since(*started) < RATE_LIMIT_WINDOW);
+        }
+
+        let (started, count) = windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*started) >= RATE_LIMIT_WINDOW {
+            *started = now;
+            *count = 0;
+        }
+
+        if *count >= self.requests_per_minute {
+            let remaining = RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(*started));
+            return Err(remaining.as_secs().max(1));
+        }
+
+        *count += 1;
+        Ok(())
+    }
+}
+
+pub(crate) async fn rate_limit(
+    State(state): State<AppState>,
+    Extension(ClientIp(client)): Extension<ClientIp>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    state.rate_limiter.check(client).map_err(AppError::RateLimited)?;
+
+    Ok(next.run(request).await)
+}
+
+impl IpAllowlist {
+    // The peer address, unless it is a trusted proxy: then walk X-Forwarded-For from the right,
+    // skipping further trusted hops, and take the first address nobody we trust could have forged
+    pub(crate) fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let trusted = |ip: &IpAddr| self.trusted_proxies.iter().any(|net| net.contains(ip));
+
+        let mut client = peer.to_canonical();
+        if !trusted(&client) {
+            return client;
+        }
+
+        let forwarded = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in forwarded.into_iter().rev() {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip.to_canonical();
+                    if !trusted(&client) {
+                        break;
+                    }
+                }
+                // Garbage in the chain means we can't see past it; stop at the last good hop
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&ip))
+    }
+}
+
+pub(crate) async fn check_ip_allowlist(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let client = state.ip_allowlist.client_ip(addr.ip(), request.headers());
+    if !state.ip_allowlist.allows(client) {
+        warn!(client = %client, peer = %addr.ip(), "request from an address outside the allowlist");
+        return Err(AppError::Forbidden("Requests from this address are not allowed.".to_string()));
+    }
+    // Resolved once here so later layers (the rate limiter) see the same client
+    request.extensions_mut().insert(ClientIp(client));
+
+    Ok(next.run(request).await)
+}
+
+pub(crate) const API_KEY_HEADER: &str = "X-API-Key";
+
+// Accepts `Authorization: Bearer <key>` or `X-API-Key: <key>`
+pub(crate) fn api_key_from_headers(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        return value.strip_prefix("Bearer ").map(str::trim);
+    }
+
+    headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()).map(str::trim)
+}
+
+// Keys are compared as SHA-256 digests, in constant time, against every configured key
+pub(crate) fn authenticate_merchant(
+    merchant_keys: &[([u8; 32], MerchantId, Roles)],
+    presented: &str,
+) -> Option<(MerchantId, Roles)> {
+    let presented: [u8; 32] = Sha256::digest(presented.as_bytes()).into();
+
+    let mut found = subtle::Choice::from(0);
+    let mut merchant_id = 0i32;
+    let mut roles = 0u8;
+    for (hash, merchant, key_roles) in merchant_keys {
+        let matches = hash.ct_eq(&presented);
+        merchant_id.conditional_assign(&merchant.0, matches);
+        roles.conditional_assign(&key_roles.0, matches);
+        found |= matches;
+    }
+
+    bool::from(found).then_some((MerchantId(merchant_id), Roles(roles)))
+}
+
+pub(crate) async fn require_api_key(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let (merchant, roles) = api_key_from_headers(request.headers())
+        .and_then(|key| authenticate_merchant(&state.merchant_keys, key))
+        .ok_or_else(|| AppError::Unauthorized("Missing or invalid API key.".to_string()))?;
+
+    request.extensions_mut().insert(merchant);
+    request.extensions_mut().insert(roles);
+    Ok(next.run(request).await)
+}
+
+pub(crate) const SIGNATURE_HEADER: &str = "X-Signature";
+pub(crate) const TIMESTAMP_HEADER: &str = "X-Timestamp";
+pub(crate) const NONCE_HEADER: &str = "X-Nonce";
+pub(crate) const NONCE_PRUNE_THRESHOLD: usize = 10_000;
+
+impl NonceCache {
+    pub(crate) fn new(window_secs: i64) -> Self {
+        NonceCache { window_secs, seen: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // Rejects stale timestamps and reused nonces; a nonce is remembered until its timestamp expires
+    pub(crate) fn check(&self, nonce: &str, timestamp: i64, now: i64) -> Result<(), AppError> {
+        if (now - timestamp).abs() > self.window_secs {
+            return Err(AppError::Unauthorized("Request timestamp is outside the allowed window.".to_string()));
+        }
+
+        let mut seen = self.seen.lock().expect("nonce cache mutex poisoned");
+        if seen.len() > NONCE_PRUNE_THRESHOLD {
+            seen.retain(|_, seen_at| (now - *seen_at).abs() <= self.window_secs);
+        }
+        if seen.insert(nonce.to_string(), timestamp).is_some() {
+            return Err(AppError::Unauthorized("Request nonce has already been used.".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+// What the client signs: timestamp and nonce are bound to the body so neither can be swapped
+pub(crate) fn signing_payload(timestamp: &str, nonce: &str, body: &[u8]) -> Vec<u8> {
+    [timestamp.as_bytes(), b".", nonce.as_bytes(), b".", body].concat()
+}
+
+pub(crate) fn signing_header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, AppError> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .ok_or_else(|| AppError::Unauthorized(format!("Missing or malformed {} header.", name)))
+}
+
+// Opt-in: X-Signature must be the hex HMAC-SHA256 (an optional "sha256=" prefix is accepted) of
+// "<X-Timestamp>.<X-Nonce>.<body>". X-Timestamp is unix seconds and must be within the replay
+// window; each X-Nonce is accepted once. The body is buffered, checked, then handed on untouched.
+pub(crate) async fn verify_signature(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(secret) = state.request_signing_secret.as_deref() else {
+        return Ok(next.run(request).await);
+    };
+
+    let headers = request.headers();
+    let presented = hex::decode(signing_header(headers, SIGNATURE_HEADER)?.trim_start_matches("sha256="))
+        .map_err(|_| AppError::Unauthorized("Missing or malformed X-Signature header.".to_string()))?;
+    let timestamp = signing_header(headers, TIMESTAMP_HEADER)?.to_string();
+    let nonce = signing_header(headers, NONCE_HEADER)?.to_string();
+    let timestamp_secs: i64 = timestamp
+        .parse()
+        .map_err(|_| AppError::Unauthorized("Missing or malformed X-Timestamp header.".to_string()))?;
+
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, state.max_request_body_bytes)
+        .await
+        .map_err(|_| AppError::PayloadTooLarge("Request body is too large.".to_string()))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&signing_payload(&timestamp, &nonce, &bytes));
+    // verify_slice compares in constant time
+    if mac.verify_slice(&presented).is_err() {
+        return Err(AppError::Unauthorized("Invalid request signature.".to_string()));
+    }
+
+    // Only authentic requests get to occupy the nonce cache
+    state.nonces.check(&nonce, timestamp_secs, Utc::now().timestamp())?;
+
+    Ok(next.run(Request::from_parts(parts, axum::body::Body::from(bytes))).await)
+}
+
+// Sunset date advertised on the unversioned /api aliases
+pub(crate) const LEGACY_API_SUNSET: &str = "Thu, 01 Jul 2027 00:00:00 GMT";
+
+// Deprecation/Sunset headers (RFC 8594) plus a pointer to the /v1 equivalent
+pub(crate) async fn mark_deprecated(request: Request, next: Next) -> Response {
+    let successor = format!("</api/v1{}>; rel=\"successor-version\"", request.uri().path());
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    headers.insert("Deprecation", HeaderValue::from_static("true"));
+    headers.insert("Sunset", HeaderValue::from_static(LEGACY_API_SUNSET));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, link);
+    }
+
+    response
+}
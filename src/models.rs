@@ -0,0 +1,648 @@
+// --- MODELS ---
+
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use utoipa::{IntoParams, ToSchema};
+use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
+
+use crate::errors::*;
+
+// Payment Request (Inbound Data)
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct PaymentRequest {
+    #[serde(default)]
+    pub amount: i64, // Cents/Minor Unit
+    // Alternative to `amount` in major units, e.g. "10.50"; converted to `amount` by validation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_decimal: Option<String>,
+    pub currency: String, // E.g., "USD", "TRY"
+    #[serde(flatten)]
+    pub instrument: PaymentInstrument,
+    // false only authorizes; the funds are taken later via /capture
+    #[serde(default = "capture_by_default")]
+    pub capture: bool,
+    // Charge the gateway in this currency instead, converted at the current FX rate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settlement_currency: Option<String>,
+    // Merchant's own data (order ID, customer ref, ...); must be a JSON object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+pub(crate) fn capture_by_default() -> bool {
+    true
+}
+
+// Either raw card fields, a gateway-issued token or a saved payment method; token requests
+// never touch PAN handling. A saved method is swapped for its gateway token before charging.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum PaymentInstrument {
+    Card(CardDetails),
+    Token { payment_token: String },
+    SavedMethod { token_id: Uuid },
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct CardDetails {
+    pub card_number: String,
+    pub expiry_month: i32,
+    pub expiry_year: i32,
+    pub cvv: String,
+}
+
+// A card vaulted through POST /tokens; the gateway token itself is never returned
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PaymentMethod {
+    pub token_id: Uuid,
+    pub masked_card_number: String,
+    pub card_brand: CardBrand,
+    pub created_at: DateTime<Utc>,
+}
+
+// Result of POST /admin/gateway-key/reload; the key itself is never echoed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GatewayKeyRotation {
+    pub changed: bool, // False when the file still holds the key in use
+    pub reloaded_at: DateTime<Utc>,
+}
+
+// One entry of a POST /payments/batch response, in request order. `status` is the HTTP status
+// the item would have got from POST /payment; exactly one of `payment` and `error` is set.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchPaymentResult {
+    pub index: usize,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment: Option<PaymentResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorDetail>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchPaymentResponse {
+    pub results: Vec<BatchPaymentResult>,
+}
+
+// Payment Response (Outbound Data)
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PaymentResponse {
+    pub success: bool,
+    pub transaction_id: String,
+    #[serde(default)]
+    pub message_key: String, // Stable, e.g. PAYMENT_DECLINED; `message` follows Accept-Language
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub card_brand: Option<CardBrand>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<i64>,
+    // `amount` formatted at the currency's ISO 4217 scale, e.g. "$10.50", "\u{a5}1050", "1.050 BHD"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_display: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub masked_card_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_ref: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation: Option<PaymentOperation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversion: Option<FxConversion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decline_reason: Option<DeclineReason>, // Set on gateway declines
+}
+
+// Body of the 202 from POST /payment/async; poll GET /payment/{uuid} for the outcome
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AcceptedPayment {
+    pub transaction_id: String,
+    pub status: TransactionStatus, // Always pending when accepted
+}
+
+// Body of the 200 from POST /payment/validate; failures are a 400 with the field errors
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PaymentValidation {
+    pub valid: bool,
+}
+
+// How a cross-currency charge was converted; `amount`/`currency` are what was charged
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FxConversion {
+    pub original_amount: i64, // Minor units of original_currency, as the customer was quoted
+    pub original_currency: String,
+    #[schema(value_type = String)]
+    pub rate: Decimal, // Units of the charged currency per unit of original_currency
+    pub rounding: RoundingMode,
+}
+
+impl PaymentResponse {
+    pub fn new_success(transaction_id: String, message_key: &str, message: String) -> Self {
+        PaymentResponse {
+            success: true,
+            transaction_id,
+            message_key: message_key.to_string(),
+            message,
+            timestamp: Utc::now(),
+            card_brand: None,
+            amount: None,
+            amount_display: None,
+            currency: None,
+            masked_card_number: None,
+            gateway_ref: None,
+            operation: None,
+            conversion: None,
+            decline_reason: None,
+        }
+    }
+
+    pub fn new_failure(transaction_id: String, message_key: &str, message: String) -> Self {
+        PaymentResponse {
+            success: false,
+            transaction_id,
+            message_key: message_key.to_string(),
+            message,
+            timestamp: Utc::now(),
+            card_brand: None,
+            amount: None,
+            amount_display: None,
+            currency: None,
+            masked_card_number: None,
+            gateway_ref: None,
+            operation: None,
+            conversion: None,
+            decline_reason: None,
+        }
+    }
+
+    // Swaps the English message for the request's locale. Applied on the way out, so stored
+    // idempotent responses stay English and replays are localized per request.
+    pub fn localized(mut self) -> Self {
+        self.message = Locale::current().message(&self.message_key, self.message);
+        self
+    }
+
+    pub fn with_card_brand(mut self, card_brand: CardBrand) -> Self {
+        self.card_brand = Some(card_brand);
+        self
+    }
+
+    // Echo what was charged (or refunded) so receipts are self-contained
+    pub fn with_payment_details(mut self, amount: i64, currency: &str, masked_card_number: &str) -> Self {
+        self.amount = Some(amount);
+        self.amount_display = Money::from_minor(amount, currency).ok().map(|money| money.to_string());
+        self.currency = Some(currency.to_string());
+        self.masked_card_number = Some(masked_card_number.to_string());
+        self
+    }
+
+    pub fn with_gateway_ref(mut self, gateway_ref: Option<String>) -> Self {
+        self.gateway_ref = gateway_ref;
+        self
+    }
+
+    pub fn with_operation(mut self, operation: PaymentOperation) -> Self {
+        self.operation = Some(operation);
+        self
+    }
+
+    pub fn with_conversion(mut self, conversion: Option<FxConversion>) -> Self {
+        self.conversion = conversion;
+        self
+    }
+
+    pub fn with_decline_reason(mut self, decline_reason: Option<DeclineReason>) -> Self {
+        self.decline_reason = decline_reason;
+        self
+    }
+}
+
+// What a PaymentResponse reports on: a void cancels an unsettled authorization (nothing is
+// charged), a refund returns money that was already captured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentOperation {
+    Charge,
+    Authorize,
+    Capture,
+    Refund,
+    Void,
+}
+
+// Lifecycle state of a transaction, stored as the `transaction_status` Postgres enum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "transaction_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Pending,
+    Success,
+    Failed,
+    Refunded,
+    PartiallyRefunded,
+    Voided, // Authorization cancelled before settlement; no funds moved
+    Authorized, // Funds held, awaiting capture
+}
+
+impl TransactionStatus {
+    // Same spelling as the database enum and JSON
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionStatus::Pending => "pending",
+            TransactionStatus::Success => "success",
+            TransactionStatus::Failed => "failed",
+            TransactionStatus::Refunded => "refunded",
+            TransactionStatus::PartiallyRefunded => "partially_refunded",
+            TransactionStatus::Voided => "voided",
+            TransactionStatus::Authorized => "authorized",
+        }
+    }
+}
+
+// What happened to a transaction, as recorded in the `transaction_events` audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "transaction_event_type", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionEventType {
+    Created,
+    Authorized,
+    Succeeded,
+    Failed,
+    Captured,
+    Refunded,
+    PartiallyRefunded,
+    Voided,
+}
+
+impl TransactionEventType {
+    // The event a charge outcome is logged as
+    pub(crate) fn for_charge(status: TransactionStatus) -> Self {
+        match status {
+            TransactionStatus::Success => TransactionEventType::Succeeded,
+            TransactionStatus::Authorized => TransactionEventType::Authorized,
+            _ => TransactionEventType::Failed,
+        }
+    }
+}
+
+// One audit log entry (GET /api/v1/payment/:uuid/events)
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TransactionEvent {
+    pub event_type: TransactionEventType,
+    pub status: TransactionStatus, // Status after the event
+    pub amount: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+// Card network, detected from the BIN prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "card_brand", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum CardBrand {
+    Visa,
+    Mastercard,
+    Amex,
+    Discover,
+    Unknown,
+}
+
+// Machine-readable cause of a gateway decline, stored as the `decline_reason` Postgres enum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "decline_reason", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DeclineReason {
+    InsufficientFunds,
+    CardExpired,
+    DoNotHonor, // Generic issuer refusal; the cardholder has to contact their bank
+    SuspectedFraud,
+    ProcessingError, // Issuer-side failure; retrying later may succeed
+}
+
+// TRANSACTION MODELS
+#[derive(Debug, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Transaction {
+    pub id: i32,
+    pub transaction_uuid: Uuid,
+    pub amount: i64,
+    pub currency: String,
+    pub status: TransactionStatus,
+    pub masked_card_number: String,
+    pub card_brand: CardBrand,
+    pub refunded_amount: i64,
+    pub authorized_amount: Option<i64>, // Set for authorize-then-capture payments
+    pub gateway: Option<String>, // Name of the gateway that processed the charge
+    pub gateway_ref: Option<String>, // The gateway's own ID for the charge, used for refunds
+    pub version: i32, // Incremented by every update
+    pub presentment_amount: Option<i64>, // Before FX conversion; NULL when charged as quoted
+    pub presentment_currency: Option<String>,
+    #[schema(value_type = Option<String>)]
+    pub fx_rate: Option<Decimal>,
+    pub fx_rounding: Option<RoundingMode>,
+    pub card_expiry_month: Option<i32>, // Only recorded when STORE_CARD_EXPIRY is enabled
+    pub card_expiry_year: Option<i32>,
+    pub decline_reason: Option<DeclineReason>, // Only on failed transactions the gateway declined
+    #[schema(value_type = Option<Object>)]
+    pub metadata: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Capture Request (optional body of POST /api/v1/payment/:uuid/capture)
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CaptureRequest {
+    pub amount: Option<i64>, // Defaults to the full authorized amount; may be less, never more
+}
+
+// Refund Request (optional body of POST /api/v1/payment/:uuid/refund)
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RefundRequest {
+    pub amount: Option<i64>, // Defaults to the remaining refundable amount
+}
+
+// Listing query parameters (GET /api/v1/payments)
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListParams {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>, // Fallback for clients that predate cursors; not combinable with one
+    pub cursor: Option<String>, // `next_cursor` from the previous page
+    pub status: Option<TransactionStatus>,
+    pub currency: Option<String>,
+    pub from: Option<String>, // RFC3339, inclusive
+    pub to: Option<String>,   // RFC3339, inclusive
+}
+
+// Paginated listing envelope
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TransactionList {
+    pub items: Vec<Transaction>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    pub next_cursor: Option<String>, // Absent on the last page
+}
+
+// Reporting query parameters (GET /api/v1/reports/summary)
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SummaryParams {
+    pub currency: Option<String>,
+    pub from: Option<String>, // RFC3339, inclusive
+    pub to: Option<String>,   // RFC3339, inclusive
+}
+
+// Aggregate totals over the matching transactions
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct PaymentSummary {
+    pub total_count: i64,
+    pub successful_count: i64,
+    pub declined_count: i64,
+    pub total_successful_amount: i64, // Includes charges that were later refunded
+    pub total_refunded_amount: i64,
+    pub decline_rate: f64,            // declined / (successful + declined)
+    // Formatted totals, only when the summary is restricted to one currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_successful_display: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_refunded_display: Option<String>,
+}
+
+// How often a subscription is billed, stored as the `billing_interval` Postgres enum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "billing_interval", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum BillingInterval {
+    Day,
+    Week,
+    Month, // Same day next month, clamped to its last day
+    Year,
+}
+
+impl BillingInterval {
+    // Start of the cycle after the one beginning at `from`
+    pub(crate) fn advance(self, from: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            BillingInterval::Day => from + chrono::Duration::days(1),
+            BillingInterval::Week => from + chrono::Duration::weeks(1),
+            BillingInterval::Month => from.checked_add_months(chrono::Months::new(1)).unwrap_or(DateTime::<Utc>::MAX_UTC),
+            BillingInterval::Year => from.checked_add_months(chrono::Months::new(12)).unwrap_or(DateTime::<Utc>::MAX_UTC),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "subscription_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    Active,
+    PastDue, // Retries exhausted; no longer billed
+}
+
+// Subscription Request (body of POST /api/v1/subscriptions)
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SubscriptionRequest {
+    pub amount: i64, // Minor units, charged every cycle
+    pub currency: String,
+    pub payment_token: String, // Gateway-issued; raw cards can't be stored for later cycles
+    pub interval: BillingInterval,
+    pub start_at: Option<String>, // RFC3339; the first cycle is billed then. Defaults to now.
+}
+
+// A recurring charge schedule. The payment token is write-only and never returned.
+#[derive(Debug, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Subscription {
+    pub subscription_uuid: Uuid,
+    pub amount: i64,
+    pub currency: String,
+    pub interval: BillingInterval,
+    pub status: SubscriptionStatus,
+    pub next_billing_at: DateTime<Utc>, // Start of the next unpaid cycle
+    pub next_attempt_at: DateTime<Utc>, // Later than next_billing_at while a failed cycle is retried
+    pub failed_attempts: i32, // Consecutive failures of the current cycle
+    pub created_at: DateTime<Utc>,
+}
+
+// --- MONEY AND CARD HELPERS (input validation lives in validation.rs) ---
+
+// ISO 4217 alpha code -> number of minor-unit digits
+pub(crate) const ISO_4217_CURRENCIES: &[(&str, u32)] = &[
+    ("AED", 2), ("ARS", 2), ("AUD", 2), ("BGN", 2), ("BHD", 3), ("BRL", 2),
+    ("CAD", 2), ("CHF", 2), ("CLP", 0), ("CNY", 2), ("COP", 2), ("CZK", 2),
+    ("DKK", 2), ("EGP", 2), ("EUR", 2), ("GBP", 2), ("HKD", 2), ("HUF", 2),
+    ("IDR", 2), ("ILS", 2), ("INR", 2), ("ISK", 0), ("JOD", 3), ("JPY", 0),
+    ("KRW", 0), ("KWD", 3), ("MAD", 2), ("MXN", 2), ("MYR", 2), ("NGN", 2),
+    ("NOK", 2), ("NZD", 2), ("OMR", 3), ("PEN", 2), ("PHP", 2), ("PKR", 2),
+    ("PLN", 2), ("QAR", 2), ("RON", 2), ("RUB", 2), ("SAR", 2), ("SEK", 2),
+    ("SGD", 2), ("THB", 2), ("TND", 3), ("TRY", 2), ("TWD", 2), ("UAH", 2),
+    ("USD", 2), ("VND", 0), ("ZAR", 2),
+];
+
+// A supported ISO 4217 currency together with its minor-unit scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Currency {
+    pub(crate) code: &'static str,
+    pub(crate) exponent: u32,
+}
+
+impl Currency {
+    // Callers normalize to uppercase first
+    pub(crate) fn from_code(code: &str) -> Result<Currency, AppError> {
+        ISO_4217_CURRENCIES
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(code, exponent)| Currency { code, exponent: *exponent })
+            .ok_or_else(|| AppError::BadRequest("UNSUPPORTED_CURRENCY", format!("Unsupported currency code: {}.", code)))
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    // Falls back to the ISO code for currencies without a common symbol
+    pub(crate) fn symbol(&self) -> Option<&'static str> {
+        match self.code {
+            "USD" | "AUD" | "CAD" | "NZD" | "HKD" | "SGD" | "MXN" => Some("$"),
+            "EUR" => Some("\u{20ac}"),
+            "GBP" => Some("\u{a3}"),
+            "JPY" | "CNY" => Some("\u{a5}"),
+            "INR" => Some("\u{20b9}"),
+            "KRW" => Some("\u{20a9}"),
+            _ => None,
+        }
+    }
+}
+
+// How a converted amount is brought back to whole minor units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "rounding_mode", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingMode {
+    HalfUp,   // 0.5 rounds up
+    HalfEven, // Banker's rounding: 0.5 rounds to the even neighbour, unbiased in aggregate
+    Floor,    // Always down; never charges more than the exact conversion
+}
+
+impl RoundingMode {
+    pub(crate) fn parse(value: &str) -> Option<RoundingMode> {
+        match value {
+            "half_up" => Some(RoundingMode::HalfUp),
+            "half_even" => Some(RoundingMode::HalfEven),
+            "floor" => Some(RoundingMode::Floor),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn strategy(self) -> RoundingStrategy {
+        match self {
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::Floor => RoundingStrategy::ToNegativeInfinity,
+        }
+    }
+}
+
+// An amount in minor units bound to its currency; arithmetic refuses to mix currencies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Money {
+    pub(crate) amount: i64,
+    pub(crate) currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: i64, currency: Currency) -> Money {
+        Money { amount, currency }
+    }
+
+    // Parses the currency code, so an unsupported currency never becomes Money
+    pub(crate) fn from_minor(amount: i64, currency_code: &str) -> Result<Money, AppError> {
+        Ok(Money::new(amount, Currency::from_code(currency_code)?))
+    }
+
+    pub fn amount(&self) -> i64 {
+        self.amount
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    // Converts at `rate` (units of `to` per unit of this currency), rounding to whole minor units
+    pub(crate) fn convert(self, to: Currency, rate: Decimal, rounding: RoundingMode) -> Result<Money, AppError> {
+        let overflow = || AppError::InternalServerError("Currency conversion overflowed.".to_string());
+        let minor = Decimal::new(self.amount, self.currency.exponent)
+            .checked_mul(rate)
+            .and_then(|major| major.checked_mul(Decimal::from(10i64.pow(to.exponent))))
+            .ok_or_else(overflow)?
+            .round_dp_with_strategy(0, rounding.strategy());
+
+        Ok(Money::new(minor.to_i64().ok_or_else(overflow)?, to))
+    }
+
+    // Major units ("10.50") to minor units, refusing more decimal places than the currency has
+    pub(crate) fn from_major(major: Decimal, currency: Currency) -> Result<Money, AppError> {
+        if major.scale() > currency.exponent {
+            return Err(AppError::BadRequest("AMOUNT_TOO_PRECISE", format!(
+                "{} amounts have at most {} decimal places.", currency.code, currency.exponent
+            )));
+        }
+        let minor = major
+            .checked_mul(Decimal::from(10i64.pow(currency.exponent)))
+            .and_then(|minor| minor.to_i64())
+            .ok_or_else(|| AppError::BadRequest("INVALID_AMOUNT", "Payment amount is out of range.".to_string()))?;
+
+        Ok(Money::new(minor, currency))
+    }
+
+    pub(crate) fn checked_sub(self, other: Money) -> Result<Money, AppError> {
+        if self.currency != other.currency {
+            return Err(AppError::InternalServerError(format!(
+                "Currency mismatch: {} vs {}.", self.currency.code, other.currency.code
+            )));
+        }
+        let amount = self.amount.checked_sub(other.amount)
+            .ok_or_else(|| AppError::InternalServerError("Money arithmetic overflowed.".to_string()))?;
+
+        Ok(Money::new(amount, self.currency))
+    }
+}
+
+// 1050 USD -> "$10.50", 1050 JPY -> "\u{a5}1050", 1050 SEK -> "10.50 SEK"
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.amount < 0 { "-" } else { "" };
+        let abs = self.amount.unsigned_abs();
+        let scale = 10u64.pow(self.currency.exponent);
+
+        let number = if self.currency.exponent == 0 {
+            abs.to_string()
+        } else {
+            format!("{}.{:0width$}", abs / scale, abs % scale, width = self.currency.exponent as usize)
+        };
+
+        match self.currency.symbol() {
+            Some(symbol) => write!(f, "{}{}{}", sign, symbol, number),
+            None => write!(f, "{}{} {}", sign, number, self.currency.code),
+        }
+    }
+}
+
+// Only the last four digits survive; separators are ignored. Expects a validated card number.
+pub(crate) fn mask_card_number(card_number: &str) -> String {
+    let digits: Vec<char> = card_number.chars().filter(|c| c.is_ascii_digit()).collect();
+    let last_four: String = digits[digits.len().saturating_sub(4)..].iter().collect();
+
+    format!("XXXX-XXXX-XXXX-{}", last_four)
+}
+
+// Stable per-card identifier for fraud correlation: HMAC-SHA256 of the PAN digits keyed by a
+// secret salt. One-way; without the salt, not even a brute force over card numbers can link it.
+pub(crate) fn card_fingerprint(salt: &[u8], card_number: &str) -> String {
+    let digits: String = card_number.chars().filter(|c| c.is_ascii_digit()).collect();
+    let mut mac = Hmac::<Sha256>::new_from_slice(salt).expect("HMAC accepts keys of any length");
+    mac.update(digits.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
@@ -0,0 +1,132 @@
+// --- ROUTE SETUP ---
+
+use axum::{
+    extract::{DefaultBodyLimit, Json},
+    http::{header, HeaderName, Method},
+    middleware,
+    response::Html,
+    routing::{get, post},
+    Router,
+};
+use tower_http::cors::CorsLayer;
+use utoipa::OpenApi;
+
+use crate::{config::*, db::*, errors::*, handlers::*, middleware::*, models::*};
+
+// OpenAPI spec generated from the handler and model annotations
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Rust Payment API"),
+    paths(
+        process_payment, process_payment_async, validate_payment, get_transaction, refund_payment, void_payment, capture_payment, transaction_events, transaction_status_stream,
+        process_payment_batch, list_transactions, export_transactions, payments_summary, create_payment_method, create_subscription, get_subscription, reload_gateway_key,
+    ),
+    components(schemas(
+        PaymentRequest, PaymentInstrument, CardDetails, PaymentMethod, PaymentResponse, AcceptedPayment, PaymentValidation, PaymentOperation, TransactionStatus, CardBrand, DeclineReason,
+        Transaction, RefundRequest, CaptureRequest, TransactionList, BatchPaymentResult, BatchPaymentResponse, FxConversion, RoundingMode, TransactionEvent, TransactionEventType, PaymentSummary,
+        SubscriptionRequest, Subscription, BillingInterval, GatewayKeyRotation, SubscriptionStatus, ErrorBody, ErrorDetail, FieldError,
+    )),
+    modifiers(&ApiKeySecurity)
+)]
+pub(crate) struct ApiDoc;
+
+// Documents the X-API-Key scheme enforced by `require_api_key`
+pub(crate) struct ApiKeySecurity;
+
+impl utoipa::Modify for ApiKeySecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+    }
+}
+
+pub(crate) async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// Swagger UI is loaded from its CDN bundle so the binary doesn't embed the assets
+pub(crate) const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>Rust Payment API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => { window.ui = SwaggerUIBundle({ url: "/api-docs/openapi.json", dom_id: "#swagger-ui" }); };
+  </script>
+</body>
+</html>
+"##;
+
+pub(crate) async fn swagger_ui() -> Html<&'static str> {
+    Html(SWAGGER_UI_HTML)
+}
+
+// All application routes (rate limited per client IP, API-key authenticated, then signature-checked
+// when REQUEST_SIGNING_SECRET is set; probes and API docs are exempt).
+// Shared by `main` and the tests so handlers can be exercised without binding a socket.
+pub fn build_router(state: AppState) -> Router {
+    // Outermost so preflight OPTIONS requests are answered before auth and rate limiting.
+    // Browsers send nothing the preflight doesn't allow, so every header the API reads is listed.
+    let api_headers = [API_KEY_HEADER, IDEMPOTENCY_KEY_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER, NONCE_HEADER]
+        .map(|name| HeaderName::from_bytes(name.as_bytes()).expect("header constants are valid names"));
+    let cors = CorsLayer::new()
+        .allow_origin(state.cors_allowed_origins.iter().cloned().collect::<Vec<_>>())
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION].into_iter().chain(api_headers).collect::<Vec<_>>());
+
+    let api = Router::new()
+        .route("/payment", post(process_payment))
+        .route("/payment/async", post(process_payment_async))
+        .route("/payment/validate", post(validate_payment))
+        .route("/payment/:uuid", get(get_transaction))
+        .route("/payment/:uuid/refund", post(refund_payment))
+        .route("/payment/:uuid/void", post(void_payment))
+        .route("/payment/:uuid/capture", post(capture_payment))
+        .route("/payment/:uuid/events", get(transaction_events))
+        .route("/payment/:uuid/stream", get(transaction_status_stream))
+        .route("/payments", get(list_transactions))
+        .route("/payments/batch", post(process_payment_batch))
+        .route("/payments/export.csv", get(export_transactions))
+        .route("/reports/summary", get(payments_summary))
+        .route("/tokens", post(create_payment_method))
+        .route("/subscriptions", post(create_subscription))
+        .route("/subscriptions/:uuid", get(get_subscription))
+        .route("/admin/gateway-key/reload", post(reload_gateway_key))
+        .route_layer(middleware::from_fn_with_state(state.clone(), verify_signature))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+        .route_layer(middleware::from_fn_with_state(state.clone(), check_ip_allowlist));
+
+    Router::new()
+        .nest("/api/v1", api.clone())
+        // Unversioned paths predate /v1 and are kept as deprecated aliases
+        .nest("/api", api.layer(middleware::from_fn(mark_deprecated)))
+        .route("/health", get(health_check))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/api-docs/openapi.json", get(openapi_json))
+        .route("/swagger", get(swagger_ui))
+        .layer(DefaultBodyLimit::max(state.max_request_body_bytes))
+        .layer(middleware::from_fn_with_state(state.clone(), log_http))
+        .layer(middleware::from_fn(response_time))
+        .layer(middleware::from_fn(negotiate_locale))
+        .layer(middleware::from_fn(request_id))
+        .layer(cors)
+        .with_state(state)
+}
+
+// Unauthenticated Prometheus scrape route, mounted on the main or a dedicated listener
+pub(crate) fn build_metrics_router(state: AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(state)
+}
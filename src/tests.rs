@@ -0,0 +1,2502 @@
+// --- INTEGRATION TESTS ---
+
+use axum::{
+    body::Bytes,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use sqlx::{PgPool, postgres::PgPoolOptions};
+use async_trait::async_trait;
+use reqwest::Client;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::info;
+use tracing_subscriber::{
+    fmt::format::JsonFields,
+    layer::SubscriberExt,
+};
+use opentelemetry::trace::TracerProvider as _;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use rust_decimal::Decimal;
+
+use super::*;
+use axum::body::Body;
+use http_body_util::BodyExt;
+use tower::ServiceExt;
+use chrono::{Datelike, SubsecRound};
+use std::sync::atomic::AtomicUsize;
+use crate::validation::{luhn_valid, validate_card};
+
+const TEST_MERCHANT_KEY: &str = "mk_test";
+const TEST_MERCHANT: MerchantId = MerchantId(1); // The "default" merchant seeded by the migration
+
+fn test_state(db: PgPool) -> AppState {
+    AppState {
+        db,
+        api_key: GatewayApiKey::new("sk_test"),
+        amount_limits: AmountLimits::new(AmountRange { min: 1, max: DEFAULT_MAX_PAYMENT_AMOUNT }),
+        ready: Arc::new(AtomicBool::new(true)),
+        rate_limiter: RateLimiter::new(1_000),
+        merchant_keys: Arc::new(vec![(Sha256::digest(TEST_MERCHANT_KEY.as_bytes()).into(), TEST_MERCHANT, Roles::ALL)]),
+        gateway_retry: RetryPolicy { max_attempts: 1, base_delay: Duration::from_millis(1) },
+        metrics: PrometheusBuilder::new().build_recorder().handle(),
+        gateways: GatewayRouter::new(vec![routed(MockGateway::default())]),
+        cors_allowed_origins: Arc::new(vec![HeaderValue::from_static("https://shop.example")]),
+        max_request_body_bytes: DEFAULT_MAX_REQUEST_BODY_BYTES,
+        http_logging: false,
+        webhooks: None,
+        request_signing_secret: None,
+        nonces: NonceCache::new(DEFAULT_REPLAY_WINDOW_SECS),
+        gateway_probe: None,
+        velocity: VelocityLimit { max_charges: 1_000, window: chrono::Duration::minutes(60) },
+        decline_rate: DeclineRate::new(Duration::from_secs(15 * 60)),
+        card_fingerprint_salt: b"salt_test".as_slice().into(),
+        store_card_expiry: false,
+        fx: None,
+        ip_allowlist: IpAllowlist { allowed: Arc::new([]), trusted_proxies: Arc::new([]) },
+        dunning: DunningPolicy { max_retries: 2, retry_delay: chrono::Duration::minutes(60) },
+        payment_queue: mpsc::channel(1).0, // No worker: async payments are refused
+        payment_slots: Arc::new(Semaphore::new(100)),
+        status_updates: broadcast::channel(16).0,
+    }
+}
+
+fn routed(gateway: impl PaymentGateway + 'static) -> RoutedGateway {
+    RoutedGateway { gateway: Arc::new(gateway), breaker: CircuitBreaker::new(5, Duration::from_secs(30)) }
+}
+
+// Always fails the way a gateway outage does
+struct UnavailableGateway;
+
+#[async_trait]
+impl PaymentGateway for UnavailableGateway {
+    fn name(&self) -> &'static str {
+        "unavailable"
+    }
+
+    async fn charge(&self, _: &PaymentRequest, _: &Uuid, _: &str) -> Result<GatewayCharge, AppError> {
+        Err(AppError::GatewayError("connection refused".to_string()))
+    }
+
+    async fn refund(&self, _: &Transaction, _: i64) -> Result<String, AppError> {
+        Err(AppError::GatewayError("connection refused".to_string()))
+    }
+
+    async fn void(&self, _: &Transaction) -> Result<String, AppError> {
+        Err(AppError::GatewayError("connection refused".to_string()))
+    }
+
+    async fn capture(&self, _: &Transaction, _: i64) -> Result<String, AppError> {
+        Err(AppError::GatewayError("connection refused".to_string()))
+    }
+
+    async fn lookup_charge(&self, _: &Transaction) -> Result<Option<GatewayCharge>, AppError> {
+        Err(AppError::GatewayError("connection refused".to_string()))
+    }
+
+    async fn tokenize(&self, _: &CardDetails) -> Result<String, AppError> {
+        Err(AppError::GatewayError("connection refused".to_string()))
+    }
+}
+
+// Refuses the first `failures` charges like an outage, then behaves like the mock.
+// Records the transaction UUID each attempt carried.
+struct FlakyGateway {
+    failures: usize,
+    seen: Arc<Mutex<Vec<Uuid>>>,
+}
+
+#[async_trait]
+impl PaymentGateway for FlakyGateway {
+    fn name(&self) -> &'static str {
+        "flaky"
+    }
+
+    async fn charge(&self, data: &PaymentRequest, transaction_uuid: &Uuid, request_id: &str) -> Result<GatewayCharge, AppError> {
+        let attempt = {
+            let mut seen = self.seen.lock().unwrap();
+            seen.push(*transaction_uuid);
+            seen.len()
+        };
+        if attempt <= self.failures {
+            return Err(AppError::GatewayError("connection refused".to_string()));
+        }
+        MockGateway::default().charge(data, transaction_uuid, request_id).await
+    }
+
+    async fn refund(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        MockGateway::default().refund(transaction, amount).await
+    }
+
+    async fn void(&self, transaction: &Transaction) -> Result<String, AppError> {
+        MockGateway::default().void(transaction).await
+    }
+
+    async fn capture(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        MockGateway::default().capture(transaction, amount).await
+    }
+
+    async fn lookup_charge(&self, transaction: &Transaction) -> Result<Option<GatewayCharge>, AppError> {
+        MockGateway::default().lookup_charge(transaction).await
+    }
+
+    async fn tokenize(&self, card: &CardDetails) -> Result<String, AppError> {
+        MockGateway::default().tokenize(card).await
+    }
+}
+
+// Approves charges, but first records the row's status as another client sees it, then
+// takes the database away so the outcome can't be persisted
+struct DatabaseLosingGateway {
+    db: PgPool,
+    status_during_call: Arc<Mutex<Option<TransactionStatus>>>,
+}
+
+#[async_trait]
+impl PaymentGateway for DatabaseLosingGateway {
+    fn name(&self) -> &'static str {
+        "database_losing"
+    }
+
+    async fn charge(&self, data: &PaymentRequest, transaction_uuid: &Uuid, request_id: &str) -> Result<GatewayCharge, AppError> {
+        let status = sqlx::query_scalar("SELECT status FROM transactions WHERE transaction_uuid = $1")
+            .bind(transaction_uuid)
+            .fetch_one(&self.db)
+            .await?;
+        *self.status_during_call.lock().unwrap() = Some(status);
+        self.db.close().await;
+        MockGateway::default().charge(data, transaction_uuid, request_id).await
+    }
+
+    async fn refund(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        MockGateway::default().refund(transaction, amount).await
+    }
+
+    async fn void(&self, transaction: &Transaction) -> Result<String, AppError> {
+        MockGateway::default().void(transaction).await
+    }
+
+    async fn capture(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        MockGateway::default().capture(transaction, amount).await
+    }
+
+    async fn lookup_charge(&self, transaction: &Transaction) -> Result<Option<GatewayCharge>, AppError> {
+        MockGateway::default().lookup_charge(transaction).await
+    }
+
+    async fn tokenize(&self, card: &CardDetails) -> Result<String, AppError> {
+        MockGateway::default().tokenize(card).await
+    }
+}
+
+// Approves charges, but only after the merchant has voided the pending row through the API
+struct VoidedInFlightGateway {
+    db: PgPool,
+}
+
+#[async_trait]
+impl PaymentGateway for VoidedInFlightGateway {
+    fn name(&self) -> &'static str {
+        "voided_in_flight"
+    }
+
+    async fn charge(&self, data: &PaymentRequest, transaction_uuid: &Uuid, request_id: &str) -> Result<GatewayCharge, AppError> {
+        let void = format!("/api/v1/payment/{}/void", transaction_uuid);
+        let response = send(&test_state(self.db.clone()), api_request("POST", &void, Some(TEST_MERCHANT_KEY)), Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        MockGateway::default().charge(data, transaction_uuid, request_id).await
+    }
+
+    async fn refund(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        MockGateway::default().refund(transaction, amount).await
+    }
+
+    async fn void(&self, transaction: &Transaction) -> Result<String, AppError> {
+        MockGateway::default().void(transaction).await
+    }
+
+    async fn capture(&self, transaction: &Transaction, amount: i64) -> Result<String, AppError> {
+        MockGateway::default().capture(transaction, amount).await
+    }
+
+    async fn lookup_charge(&self, transaction: &Transaction) -> Result<Option<GatewayCharge>, AppError> {
+        MockGateway::default().lookup_charge(transaction).await
+    }
+
+    async fn tokenize(&self, card: &CardDetails) -> Result<String, AppError> {
+        MockGateway::default().tokenize(card).await
+    }
+}
+
+// Answers every charge as if the deadline expired, counting the charges it was sent.
+// The charges did go through: a later lookup finds them approved.
+#[derive(Default)]
+struct TimingOutGateway {
+    charges: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl PaymentGateway for TimingOutGateway {
+    fn name(&self) -> &'static str {
+        "timing_out"
+    }
+
+    async fn charge(&self, _: &PaymentRequest, _: &Uuid, _: &str) -> Result<GatewayCharge, AppError> {
+        self.charges.fetch_add(1, Ordering::SeqCst);
+        Err(AppError::GatewayTimeout)
+    }
+
+    async fn refund(&self, _: &Transaction, _: i64) -> Result<String, AppError> {
+        Err(AppError::GatewayTimeout)
+    }
+
+    async fn void(&self, _: &Transaction) -> Result<String, AppError> {
+        Err(AppError::GatewayTimeout)
+    }
+
+    async fn capture(&self, _: &Transaction, _: i64) -> Result<String, AppError> {
+        Err(AppError::GatewayTimeout)
+    }
+
+    async fn lookup_charge(&self, transaction: &Transaction) -> Result<Option<GatewayCharge>, AppError> {
+        let charge = GatewayCharge::new(TransactionStatus::Success, "Charge found on lookup.")
+            .with_gateway_ref(format!("late_{}", transaction.transaction_uuid.simple()));
+        Ok(Some(charge))
+    }
+
+    async fn tokenize(&self, card: &CardDetails) -> Result<String, AppError> {
+        MockGateway::default().tokenize(card).await
+    }
+}
+
+fn payment_body(card_number: &str, amount: i64) -> serde_json::Value {
+    serde_json::json!({
+        "amount": amount,
+        "currency": "usd",
+        "card_number": card_number,
+        "expiry_month": 12,
+        "expiry_year": Utc::now().year() + 2,
+        "cvv": "123",
+    })
+}
+
+async fn post_payment(db: &PgPool, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+    let (status, bytes) = post_raw_payment(db, body.to_string()).await;
+
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+async fn post_raw_payment(db: &PgPool, body: String) -> (StatusCode, Bytes) {
+    post_raw_payment_with_state(test_state(db.clone()), body).await
+}
+
+async fn post_raw_payment_with_state(state: AppState, body: String) -> (StatusCode, Bytes) {
+    let response = send(&state, api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY)), body).await;
+    let status = response.status();
+
+    (status, response.into_body().collect().await.unwrap().to_bytes())
+}
+
+// An API request with a JSON content type and, when given, an API key
+fn api_request(method: &str, uri: &str, key: Option<&str>) -> axum::http::request::Builder {
+    let request = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    match key {
+        Some(key) => request.header(API_KEY_HEADER, key),
+        None => request,
+    }
+}
+
+// Runs a request through a fresh router, as if from a loopback client
+async fn send(state: &AppState, request: axum::http::request::Builder, body: impl Into<Body>) -> Response {
+    send_from(state, [127, 0, 0, 1], request, body).await
+}
+
+async fn send_from(
+    state: &AppState,
+    peer: impl Into<IpAddr>,
+    request: axum::http::request::Builder,
+    body: impl Into<Body>,
+) -> Response {
+    let mut request = request.body(body.into()).unwrap();
+    request.extensions_mut().insert(ConnectInfo(SocketAddr::new(peer.into(), 4000)));
+    build_router(state.clone()).oneshot(request).await.unwrap()
+}
+
+async fn json_body(response: Response) -> serde_json::Value {
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+async fn transaction_count(db: &PgPool) -> i64 {
+    sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM transactions"#)
+        .fetch_one(db)
+        .await
+        .unwrap()
+}
+
+#[sqlx::test]
+async fn successful_payment_is_persisted(db: PgPool) {
+    let (status, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["success"], true);
+    assert_eq!(body["amount"], 1050);
+    assert_eq!(body["currency"], "USD");
+    assert_eq!(body["masked_card_number"], "XXXX-XXXX-XXXX-4242");
+
+    let uuid: Uuid = body["transaction_id"].as_str().unwrap().parse().unwrap();
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, uuid).await.unwrap();
+    assert_eq!(transaction.status, TransactionStatus::Success);
+    assert_eq!(transaction.masked_card_number, "XXXX-XXXX-XXXX-4242");
+    assert_eq!(transaction.amount, 1050);
+    assert_eq!(transaction.currency, "USD");
+    assert_eq!(transaction.gateway_ref, Some(format!("mock_{}", uuid.simple())));
+    assert_eq!(body["gateway_ref"], format!("mock_{}", uuid.simple()));
+}
+
+#[sqlx::test]
+async fn configured_test_cards_decline_with_their_outcome(db: PgPool) {
+    let mut state = test_state(db.clone());
+    let mut rules = TestCards::default_rules();
+    rules.push(("4000000000000069".to_string(), SimulatedOutcome::Expired));
+    rules.push(("5105".to_string(), SimulatedOutcome::ProcessingError));
+    state.gateways = GatewayRouter::new(vec![routed(MockGateway { test_cards: TestCards::new(rules) })]);
+
+    for (card_number, message) in [
+        ("4000000000000002", Some("Card declined: Insufficient funds (Mock).")),
+        ("4000000000000069", Some("Card declined: Expired card (Mock).")), // Longest prefix wins
+        ("5105105105105100", Some("Card declined: Processing error (Mock).")),
+        ("4242424242424242", None),
+    ] {
+        let (status, bytes) = post_raw_payment_with_state(state.clone(), payment_body(card_number, 1050).to_string()).await;
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(status, StatusCode::OK, "{card_number}");
+        assert_eq!(body["success"], message.is_none(), "{card_number}");
+        if let Some(message) = message {
+            assert_eq!(body["message"], message, "{card_number}");
+        }
+    }
+}
+
+#[sqlx::test]
+async fn declines_carry_a_stored_reason_code(db: PgPool) {
+    let mut state = test_state(db.clone());
+    let mut rules = TestCards::default_rules();
+    rules.push(("5105".to_string(), SimulatedOutcome::SuspectedFraud));
+    state.gateways = GatewayRouter::new(vec![routed(MockGateway { test_cards: TestCards::new(rules) })]);
+
+    for (card_number, reason) in [
+        ("4000000000000002", Some(DeclineReason::InsufficientFunds)),
+        ("5105105105105100", Some(DeclineReason::SuspectedFraud)),
+        ("4242424242424242", None),
+    ] {
+        let (_, bytes) = post_raw_payment_with_state(state.clone(), payment_body(card_number, 1050).to_string()).await;
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body.get("decline_reason").cloned(), reason.map(|r| serde_json::to_value(r).unwrap()), "{card_number}");
+
+        let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+        assert_eq!(transaction.decline_reason, reason, "{card_number}");
+    }
+
+    let mut request = payment_body("4242424242424242", 1050);
+    request.as_object_mut().unwrap().retain(|field, _| field == "amount" || field == "currency");
+    request["payment_token"] = serde_json::json!("tok_decline_1");
+    let (_, body) = post_payment(&db, request).await;
+    assert_eq!(body["decline_reason"], "do_not_honor");
+}
+
+#[sqlx::test]
+async fn messages_follow_accept_language(db: PgPool) {
+    assert_eq!(Locale::negotiate("de-DE, tr;q=0.8, en;q=0.5"), Locale::Tr);
+    assert_eq!(Locale::negotiate("TR-tr"), Locale::Tr);
+    assert_eq!(Locale::negotiate("tr;q=0, en"), Locale::En);
+    assert_eq!(Locale::negotiate("fr, *;q=0.1"), Locale::En);
+
+    let state = test_state(db.clone());
+    let post = |language: &'static str, body: serde_json::Value| {
+        let request = api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY)).header(header::ACCEPT_LANGUAGE, language);
+        let state = state.clone();
+        async move {
+            let response = send(&state, request, body.to_string()).await;
+            let content_language = response.headers()[header::CONTENT_LANGUAGE].to_str().unwrap().to_string();
+            let bytes = response.into_body().collect().await.unwrap().to_bytes();
+            (content_language, serde_json::from_slice::<serde_json::Value>(&bytes).unwrap())
+        }
+    };
+
+    let (content_language, body) = post("tr-TR,tr;q=0.9", payment_body("4000000000000002", 1050)).await;
+    assert_eq!(content_language, "tr");
+    assert_eq!((body["message_key"].as_str(), body["message"].as_str()), (Some("PAYMENT_DECLINED"), Some("Ödeme reddedildi.")));
+
+    let mut invalid = payment_body("4242424242424241", 1050);
+    invalid["cvv"] = serde_json::json!("1");
+    let (_, body) = post("tr", invalid.clone()).await;
+    assert_eq!(body["error"]["code"], "VALIDATION_FAILED");
+    assert_eq!(body["error"]["message"], "İstek doğrulanamadı.");
+    assert_eq!(body["error"]["fields"][0]["code"], "INVALID_CARD");
+    assert_eq!(body["error"]["fields"][0]["message"], "Geçersiz kart numarası.");
+
+    // Unsupported languages get the detailed English text
+    let (content_language, body) = post("fr", invalid).await;
+    assert_eq!(content_language, "en");
+    assert_eq!(body["error"]["fields"][0]["message"], "Card number failed checksum validation.");
+    let (_, body) = post("fr", payment_body("4000000000000002", 1050)).await;
+    assert_eq!(body["message"], "Card declined: Insufficient funds (Mock).");
+}
+
+#[sqlx::test]
+async fn timestamps_serialize_as_rfc3339_utc(db: PgPool) {
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let (_, transaction) = get_json(&db, &format!("/api/v1/payment/{}", body["transaction_id"].as_str().unwrap())).await;
+
+    for timestamp in [&body["timestamp"], &transaction["created_at"]] {
+        let timestamp = timestamp.as_str().unwrap();
+        assert!(timestamp.ends_with('Z'), "{timestamp}");
+        assert_eq!(DateTime::parse_from_rfc3339(timestamp).unwrap().offset().local_minus_utc(), 0);
+    }
+}
+
+#[sqlx::test]
+async fn card_expiry_is_stored_only_when_enabled(db: PgPool) {
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!((transaction.card_expiry_month, transaction.card_expiry_year), (None, None));
+
+    let mut state = test_state(db.clone());
+    state.store_card_expiry = true;
+    let request = payment_body("4242424242424242", 1050);
+    let (_, bytes) = post_raw_payment_with_state(state, request.to_string()).await;
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.card_expiry_month, request["expiry_month"].as_i64().map(|m| m as i32));
+    assert_eq!(transaction.card_expiry_year, request["expiry_year"].as_i64().map(|y| y as i32));
+}
+
+#[sqlx::test]
+async fn responses_format_amounts_at_the_currency_scale(db: PgPool) {
+    for (currency, display) in [("usd", "$10.50"), ("jpy", "\u{a5}1050"), ("bhd", "1.050 BHD")] {
+        let mut request = payment_body("4242424242424242", 1050);
+        request["currency"] = serde_json::json!(currency);
+        let (status, body) = post_payment(&db, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["amount"], 1050);
+        assert_eq!(body["amount_display"], display, "{currency}");
+    }
+}
+
+#[sqlx::test]
+async fn decimal_amounts_are_converted_by_currency_scale(db: PgPool) {
+    let mut request = payment_body("4242424242424242", 0);
+    request.as_object_mut().unwrap().remove("amount");
+    request["amount_decimal"] = serde_json::json!("10.50");
+    let (status, body) = post_payment(&db, request.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["amount"], 1050);
+
+    request["currency"] = serde_json::json!("JPY");
+    request["amount_decimal"] = serde_json::json!("1050");
+    let (_, body) = post_payment(&db, request.clone()).await;
+    assert_eq!(body["amount"], 1050);
+
+    for (currency, amount_decimal, code) in [("JPY", "10.5", "AMOUNT_TOO_PRECISE"), ("USD", "10.505", "AMOUNT_TOO_PRECISE"), ("USD", "ten", "INVALID_AMOUNT")] {
+        request["currency"] = serde_json::json!(currency);
+        request["amount_decimal"] = serde_json::json!(amount_decimal);
+        let (status, body) = post_payment(&db, request.clone()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{amount_decimal}");
+        assert_eq!(body["error"]["fields"][0]["field"], "amount_decimal");
+        assert_eq!(body["error"]["fields"][0]["code"], code, "{amount_decimal}");
+    }
+
+    // Both forms at once are ambiguous
+    request["amount"] = serde_json::json!(1050);
+    request["amount_decimal"] = serde_json::json!("10.50");
+    let (status, _) = post_payment(&db, request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(transaction_count(&db).await, 2);
+}
+
+#[sqlx::test]
+async fn metadata_is_stored_and_returned(db: PgPool) {
+    let mut request = payment_body("4242424242424242", 1050);
+    request["metadata"] = serde_json::json!({ "order_id": "ord_42", "customer": { "ref": "c_7" } });
+    let (status, body) = post_payment(&db, request.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let uri = format!("/api/v1/payment/{}", body["transaction_id"].as_str().unwrap());
+    let (status, transaction) = get_json(&db, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(transaction["metadata"], request["metadata"]);
+
+    request["metadata"] = serde_json::json!(["ord_42"]);
+    let (status, body) = post_payment(&db, request.clone()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["fields"][0]["code"], "INVALID_METADATA");
+
+    request["metadata"] = serde_json::json!({ "notes": "x".repeat(MAX_METADATA_BYTES) });
+    let (status, body) = post_payment(&db, request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["fields"][0]["code"], "METADATA_TOO_LARGE");
+    assert_eq!(transaction_count(&db).await, 1);
+}
+
+#[sqlx::test]
+async fn batch_items_succeed_or_fail_independently_in_order(db: PgPool) {
+    let batch = serde_json::json!([
+        payment_body("4242424242424242", 1050),
+        payment_body("4000000000000002", 2000),
+        { "amount": 500 },
+        payment_body("4242424242424241", 700),
+    ]);
+    let (status, body) = post_json(&db, "/api/v1/payments/batch", &batch.to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let results = body["results"].as_array().unwrap();
+    let indexes: Vec<u64> = results.iter().map(|result| result["index"].as_u64().unwrap()).collect();
+    assert_eq!(indexes, [0, 1, 2, 3]);
+    assert_eq!(results[0]["payment"]["success"], true);
+    assert_eq!(results[1]["status"], 200);
+    assert_eq!(results[1]["payment"]["success"], false);
+    assert_eq!(results[2]["status"], 400);
+    assert_eq!(results[2]["error"]["code"], "INVALID_PAYMENT_REQUEST");
+    assert_eq!(results[3]["error"]["code"], "VALIDATION_FAILED");
+    assert_eq!(transaction_count(&db).await, 2);
+
+    let oversized = serde_json::Value::Array(vec![payment_body("4242424242424242", 100); MAX_BATCH_SIZE + 1]);
+    let (status, body) = post_json(&db, "/api/v1/payments/batch", &oversized.to_string()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["code"], "INVALID_BATCH");
+    assert_eq!(transaction_count(&db).await, 2);
+}
+
+#[sqlx::test]
+async fn validate_endpoint_checks_without_charging(db: PgPool) {
+    let (status, body) = post_json(&db, "/api/v1/payment/validate", &payment_body("4242424242424242", 1050).to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, serde_json::json!({ "valid": true }));
+
+    let mut invalid = payment_body("4242424242424241", 0);
+    invalid["cvv"] = serde_json::json!("1");
+    let (status, body) = post_json(&db, "/api/v1/payment/validate", &invalid.to_string()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let fields: Vec<&str> = body["error"]["fields"].as_array().unwrap().iter().map(|f| f["field"].as_str().unwrap()).collect();
+    assert_eq!(fields, ["amount", "card_number", "cvv"]);
+
+    assert_eq!(transaction_count(&db).await, 0);
+}
+
+#[sqlx::test]
+async fn declined_card_is_persisted_as_failed(db: PgPool) {
+    let (status, body) = post_payment(&db, payment_body("4000000000000002", 1050)).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["success"], false);
+
+    let uuid: Uuid = body["transaction_id"].as_str().unwrap().parse().unwrap();
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, uuid).await.unwrap();
+    assert_eq!(transaction.status, TransactionStatus::Failed);
+    assert_eq!(transaction.masked_card_number, "XXXX-XXXX-XXXX-0002");
+    assert_eq!(transaction.gateway_ref, None);
+    assert!(body.get("gateway_ref").is_none());
+}
+
+#[test]
+fn luhn_accepts_known_good_and_rejects_known_bad_numbers() {
+    for good in ["4242424242424242", "4242 4242 4242 4242", "4242-4242-4242-4242", "5555555555554444", "378282246310005"] {
+        assert!(luhn_valid(good), "{} should pass", good);
+    }
+    for bad in ["4242424242424241", "1234567812345678", "4242x42424242424", "", " - "] {
+        assert!(!luhn_valid(bad), "{:?} should fail", bad);
+    }
+}
+
+#[test]
+fn validation_checks_name_their_fields() {
+    let card = CardDetails {
+        card_number: "4242424242424242".to_string(),
+        expiry_month: 12,
+        expiry_year: Utc::now().year() + 1,
+        cvv: "123".to_string(),
+    };
+    assert_eq!(validate_card(&card).unwrap(), CardBrand::Visa);
+
+    let expired = CardDetails { expiry_year: 2001, cvv: "12".to_string(), ..card };
+    let errors = validate_card(&expired).unwrap_err();
+    let failed: Vec<(&str, &str)> = errors.iter().map(|e| (e.field, e.code)).collect();
+    assert_eq!(failed, [("expiry", "CARD_EXPIRED"), ("cvv", "INVALID_CVV")]);
+
+    let errors = validate_currency("settlement_currency", "XXX").unwrap_err();
+    assert_eq!((errors[0].field, errors[0].code), ("settlement_currency", "UNSUPPORTED_CURRENCY"));
+}
+
+#[sqlx::test]
+async fn amount_above_i32_range_is_accepted(db: PgPool) {
+    let amount = i32::MAX as i64 + 1;
+    let (status, _) = post_payment(&db, payment_body("4242424242424242", amount)).await;
+
+    assert_eq!(status, StatusCode::OK);
+    let stored = sqlx::query_scalar!("SELECT amount FROM transactions")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(stored, amount);
+}
+
+#[sqlx::test]
+async fn validation_failures_are_rejected_without_persisting(db: PgPool) {
+    let mut expired = payment_body("4242424242424242", 1050);
+    expired["expiry_year"] = serde_json::json!(2001);
+
+    let mut bad_cvv = payment_body("4242424242424242", 1050);
+    bad_cvv["cvv"] = serde_json::json!("12a");
+
+    let cases = [
+        payment_body("4242424242424241", 1050), // Luhn failure
+        payment_body("4242424242424242", 0),
+        expired,
+        bad_cvv,
+    ];
+
+    for body in cases {
+        let (status, response) = post_payment(&db, body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "unexpected response: {}", response);
+        assert!(response["error"]["code"].is_string());
+        assert!(response["error"]["request_id"].is_string());
+    }
+
+    assert_eq!(transaction_count(&db).await, 0);
+}
+
+#[sqlx::test]
+async fn amounts_past_i32_are_charged_up_to_the_maximum(db: PgPool) {
+    let amount = i64::from(i32::MAX) + 1;
+    let (status, body) = post_payment(&db, payment_body("4242424242424242", amount)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["success"], true);
+    assert_eq!(body["amount"], amount);
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.amount, amount);
+
+    let (status, body) = post_payment(&db, payment_body("4242424242424242", DEFAULT_MAX_PAYMENT_AMOUNT + 1)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["fields"][0]["field"], "amount");
+    assert_eq!(transaction_count(&db).await, 1);
+}
+
+#[sqlx::test]
+async fn per_currency_amount_limits_are_inclusive(db: PgPool) {
+    let mut state = test_state(db.clone());
+    state.amount_limits = state.amount_limits.with_currency("USD", AmountRange { min: 50, max: 10_000 });
+
+    for (amount, expected) in [(49, Some("AMOUNT_TOO_SMALL")), (50, None), (10_000, None), (10_001, Some("AMOUNT_TOO_LARGE"))] {
+        let (status, bytes) = post_raw_payment_with_state(state.clone(), payment_body("4242424242424242", amount).to_string()).await;
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        match expected {
+            Some(code) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{amount}");
+                assert_eq!(body["error"]["fields"][0]["code"], code, "{amount}");
+            }
+            None => assert_eq!(body["success"], true, "{amount}"),
+        }
+    }
+    let (_, bytes) = post_raw_payment_with_state(state.clone(), payment_body("4242424242424242", 49).to_string()).await;
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["error"]["fields"][0]["message"], "Payment amount is below the minimum of $0.50.");
+
+    // Currencies without an entry fall back to the global range
+    let mut body = payment_body("4242424242424242", 1);
+    body["currency"] = serde_json::json!("EUR");
+    let (status, _) = post_raw_payment_with_state(state, body.to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn every_invalid_field_is_reported_at_once(db: PgPool) {
+    let mut body = payment_body("4242424242424241", 0);
+    body["expiry_month"] = serde_json::json!(13);
+    body["cvv"] = serde_json::json!("1");
+
+    let (status, response) = post_payment(&db, body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(response["error"]["code"], "VALIDATION_FAILED");
+
+    let fields: Vec<&str> = response["error"]["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| field["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["amount", "card_number", "expiry", "cvv"]);
+}
+
+#[sqlx::test]
+async fn unicode_card_number_is_rejected_not_panicking(db: PgPool) {
+    // Arabic-Indic digits: multibyte UTF-8 that used to be sliced by byte index
+    let (status, body) = post_payment(&db, payment_body("٤٢٤٢٤٢٤٢٤٢٤٢٤٢٤٢", 1050)).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["fields"][0]["code"], "INVALID_CARD");
+    assert_eq!(body["error"]["fields"][0]["message"], "Card number must contain only ASCII digits.");
+
+    // Separators don't count towards the length
+    let (status, body) = post_payment(&db, payment_body("--------4242", 1050)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["fields"][0]["message"], "Invalid card number.");
+    assert_eq!(transaction_count(&db).await, 0);
+}
+
+#[sqlx::test]
+async fn token_payment_uses_gateway_masked_card(db: PgPool) {
+    let body = serde_json::json!({
+        "amount": 1050,
+        "currency": "USD",
+        "payment_token": "tok_visa_4242",
+    });
+    let (status, body) = post_payment(&db, body).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["success"], true);
+
+    let uuid: Uuid = body["transaction_id"].as_str().unwrap().parse().unwrap();
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, uuid).await.unwrap();
+    assert_eq!(transaction.masked_card_number, "XXXX-XXXX-XXXX-4242");
+    assert_eq!(transaction.card_brand, CardBrand::Visa);
+}
+
+#[sqlx::test]
+async fn cors_preflight_allows_only_configured_origins(db: PgPool) {
+    let state = test_state(db);
+    for (origin, allowed) in [("https://shop.example", true), ("https://evil.example", false)] {
+        let request = api_request("OPTIONS", "/api/v1/payment", None)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST");
+
+        let response = send(&state, request, Body::empty()).await;
+        let allow_origin = response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN);
+        assert_eq!(allow_origin.is_some(), allowed, "origin {}", origin);
+    }
+
+    // Authenticated, idempotent and signed requests all pass the preflight
+    let request = api_request("OPTIONS", "/api/v1/payment", None)
+        .header(header::ORIGIN, "https://shop.example")
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST");
+    let response = send(&state, request, Body::empty()).await;
+    let allowed = response.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().to_string();
+    for name in ["x-api-key", "idempotency-key", "x-signature", "x-timestamp", "x-nonce"] {
+        assert!(allowed.split(',').any(|h| h.trim() == name), "{} missing from {}", name, allowed);
+    }
+}
+
+#[sqlx::test]
+async fn request_body_over_limit_is_rejected(db: PgPool) {
+    // Pad an otherwise valid payment with whitespace to land just either side of the limit
+    let body = payment_body("4242424242424242", 1050).to_string();
+    let padded = |total: usize| format!("{}{}", body, " ".repeat(total - body.len()));
+
+    let (status, _) = post_raw_payment(&db, padded(DEFAULT_MAX_REQUEST_BODY_BYTES)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = post_raw_payment(&db, padded(DEFAULT_MAX_REQUEST_BODY_BYTES + 1)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+async fn get_json(db: &PgPool, uri: &str) -> (StatusCode, serde_json::Value) {
+    let response = send(&test_state(db.clone()), api_request("GET", uri, Some(TEST_MERCHANT_KEY)), Body::empty()).await;
+
+    (response.status(), json_body(response).await)
+}
+
+#[test]
+fn gateway_router_falls_back_to_default() {
+    let real = routed(RealGateway { client: Client::new(), api_key: GatewayApiKey::new("sk_test"), url: None, charge_timeout: Duration::from_secs(1), test_cards: TestCards::default(), signing: None });
+    let router = GatewayRouter::new(vec![routed(MockGateway::default())]).with_route("EUR", vec![real]);
+
+    assert_eq!(router.route("EUR")[0].gateway.name(), "real");
+    assert_eq!(router.route("USD")[0].gateway.name(), "mock");
+}
+
+// A stand-in for the external gateway, answering charges by amount: 1050 approves, 2000
+// declines, 3000 fails with a 500, 4000 approves but answers garbage and 4220 is refused with
+// a 422. Lookups by our reference find every charge it answered with a 2xx. Refunds, voids,
+// captures and tokenizations are accepted. Records every POST's path, headers and body.
+async fn spawn_fake_gateway(seen: Arc<Mutex<Vec<FakeGatewayRequest>>>) -> String {
+    let charges = seen.clone();
+    let record = move |uri: axum::http::Uri, headers: HeaderMap, body: serde_json::Value| {
+        seen.lock().unwrap().push((uri.path().to_string(), headers, body));
+    };
+    let lookup = move |axum::extract::Path(reference): axum::extract::Path<String>| async move {
+        let amount = charges.lock().unwrap().iter().find_map(|(path, _, body)| {
+            (path == "/charges" && body["reference"] == reference.as_str()).then(|| body["amount"].as_i64())
+        });
+        match amount {
+            Some(Some(1050 | 4000)) => axum::Json(serde_json::json!({
+                "status": "approved", "message": "Approved.", "gateway_ref": "gw_123", "captured": true,
+            }))
+            .into_response(),
+            Some(Some(2000)) => axum::Json(serde_json::json!({
+                "status": "declined", "message": "Insufficient funds.", "decline_reason": "insufficient_funds",
+            }))
+            .into_response(),
+            _ => StatusCode::NOT_FOUND.into_response(),
+        }
+    };
+    let app = axum::Router::new()
+        .route("/charges/:reference", axum::routing::get(lookup))
+        .route(
+            "/charges",
+            axum::routing::post({
+                let record = record.clone();
+                move |uri, headers: HeaderMap, axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    let amount = body["amount"].as_i64();
+                    record(uri, headers, body);
+                    match amount {
+                        Some(1050) => axum::Json(serde_json::json!({
+                            "status": "approved", "message": "Approved.", "gateway_ref": "gw_123",
+                        }))
+                        .into_response(),
+                        Some(2000) => axum::Json(serde_json::json!({
+                            "status": "declined", "message": "Insufficient funds.", "decline_reason": "insufficient_funds",
+                        }))
+                        .into_response(),
+                        Some(3000) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                        Some(4220) => StatusCode::UNPROCESSABLE_ENTITY.into_response(),
+                        _ => "<html>oops</html>".into_response(),
+                    }
+                }
+            }),
+        )
+        .route(
+            "/charges/:reference/:operation",
+            axum::routing::post({
+                let record = record.clone();
+                move |uri, axum::extract::Path((reference, operation)): axum::extract::Path<(String, String)>, headers: HeaderMap, axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    record(uri, headers, body);
+                    axum::Json(serde_json::json!({ "message": format!("{} of {} accepted.", operation, reference) }))
+                }
+            }),
+        )
+        .route(
+            "/tokens",
+            axum::routing::post(move |uri, headers: HeaderMap, axum::Json(body): axum::Json<serde_json::Value>| async move {
+                record(uri, headers, body);
+                axum::Json(serde_json::json!({ "token": "tok_gw_1" }))
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+type FakeGatewayRequest = (String, HeaderMap, serde_json::Value);
+
+#[tokio::test]
+async fn real_gateway_posts_charges_to_the_configured_url() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let gateway = RealGateway {
+        client: Client::new(),
+        api_key: GatewayApiKey::new("sk_test"),
+        url: Some(spawn_fake_gateway(seen.clone()).await),
+        charge_timeout: Duration::from_secs(5),
+        test_cards: TestCards::default(),
+        signing: Some(GatewaySigning {
+            algorithm: GatewaySigningAlgorithm::HmacSha256,
+            secret: b"gw_secret".to_vec().into(),
+            header: axum::http::HeaderName::from_static("x-gateway-signature"),
+        }),
+    };
+    let request = |amount: i64| -> PaymentRequest { serde_json::from_value(payment_body("4242424242424242", amount)).unwrap() };
+    let charge = async |amount: i64| gateway.charge(&request(amount), &Uuid::nil(), "req-1").await;
+
+    let approved = charge(1050).await.unwrap();
+    assert_eq!(approved.status, TransactionStatus::Success);
+    assert_eq!(approved.gateway_ref.as_deref(), Some("gw_123"));
+    let (path, headers, body) = seen.lock().unwrap().pop().unwrap();
+    assert_eq!(path, "/charges");
+    assert_eq!(headers["authorization"], "Bearer sk_test");
+    assert_eq!(headers["idempotency-key"], Uuid::nil().to_string());
+    assert_eq!(headers["x-request-id"], "req-1");
+    assert!(headers["x-gateway-signature"].to_str().unwrap().starts_with("sha256="));
+    assert_eq!(body["reference"], Uuid::nil().to_string());
+
+    let declined = charge(2000).await.unwrap();
+    assert_eq!(declined.status, TransactionStatus::Failed);
+    assert_eq!(declined.decline_reason, Some(DeclineReason::InsufficientFunds));
+
+    assert!(matches!(charge(3000).await, Err(AppError::GatewayError(msg)) if msg.contains("500")));
+    assert!(matches!(charge(4000).await, Err(AppError::GatewayAmbiguous(msg)) if msg.starts_with("Malformed")));
+}
+
+#[sqlx::test]
+async fn real_gateway_sends_follow_ups_to_the_configured_url(db: PgPool) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let gateway = RealGateway {
+        client: Client::new(),
+        api_key: GatewayApiKey::new("sk_test"),
+        url: Some(spawn_fake_gateway(seen.clone()).await),
+        charge_timeout: Duration::from_secs(5),
+        test_cards: TestCards::default(),
+        signing: None,
+    };
+    let mut state = test_state(db.clone());
+    state.gateways = GatewayRouter::new(vec![routed(gateway)]);
+    let call = async |uri: &str, body: String| json_body(send(&state, api_request("POST", uri, Some(TEST_MERCHANT_KEY)), body).await).await;
+    let last_request = || {
+        let (path, _, body) = seen.lock().unwrap().pop().unwrap();
+        (path, body)
+    };
+
+    let mut authorization = payment_body("4242424242424242", 1050);
+    authorization["capture"] = serde_json::json!(false);
+    let charged = call("/api/v1/payment", authorization.to_string()).await;
+    assert_eq!(charged["operation"], "authorize");
+    let transaction = format!("/api/v1/payment/{}", charged["transaction_id"].as_str().unwrap());
+
+    let response = call(&format!("{}/capture", transaction), r#"{"amount": 800}"#.to_string()).await;
+    assert_eq!(response["message"], "capture of gw_123 accepted.");
+    let (path, body) = last_request();
+    assert_eq!(path, "/charges/gw_123/capture");
+    assert_eq!(body, serde_json::json!({ "amount": 800, "currency": "USD" }));
+
+    let response = call(&format!("{}/refund", transaction), r#"{"amount": 300}"#.to_string()).await;
+    assert_eq!(response["message"], "refunds of gw_123 accepted.");
+    let (path, body) = last_request();
+    assert_eq!(path, "/charges/gw_123/refunds");
+    assert_eq!(body, serde_json::json!({ "amount": 300, "currency": "USD" }));
+
+    let charged = call("/api/v1/payment", authorization.to_string()).await;
+    let response = call(&format!("/api/v1/payment/{}/void", charged["transaction_id"].as_str().unwrap()), String::new()).await;
+    assert_eq!(response["message"], "void of gw_123 accepted.");
+    assert_eq!(last_request().0, "/charges/gw_123/void");
+
+    let mut card = payment_body("4111111111111111", 0);
+    for field in ["amount", "currency"] {
+        card.as_object_mut().unwrap().remove(field);
+    }
+    call("/api/v1/tokens", card.to_string()).await;
+    assert_eq!(last_request().0, "/tokens");
+    let stored: String = sqlx::query_scalar("SELECT payment_token FROM payment_methods").fetch_one(&db).await.unwrap();
+    assert_eq!(stored, "tok_gw_1");
+}
+
+#[sqlx::test]
+async fn refused_gateway_charge_is_neither_retried_nor_failed_over(db: PgPool) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let gateway = RealGateway {
+        client: Client::new(),
+        api_key: GatewayApiKey::new("sk_test"),
+        url: Some(spawn_fake_gateway(seen.clone()).await),
+        charge_timeout: Duration::from_secs(5),
+        test_cards: TestCards::default(),
+        signing: None,
+    };
+    let mut state = test_state(db.clone());
+    state.gateway_retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1) };
+    state.gateways = GatewayRouter::new(vec![routed(gateway), routed(MockGateway::default())]);
+
+    let (status, bytes) = post_raw_payment_with_state(state, payment_body("4242424242424242", 4220).to_string()).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["error"]["code"], "GATEWAY_REJECTED");
+    assert_eq!(seen.lock().unwrap().len(), 1);
+
+    // Nothing was charged anywhere, so the attempt is closed rather than left for reconciliation
+    let status: TransactionStatus = sqlx::query_scalar("SELECT status FROM transactions").fetch_one(&db).await.unwrap();
+    assert_eq!(status, TransactionStatus::Failed);
+}
+
+#[sqlx::test]
+async fn unreadable_gateway_approval_stays_pending_until_reconciled(db: PgPool) {
+    let charges = Arc::new(Mutex::new(Vec::new()));
+    let gateway = RealGateway {
+        client: Client::new(),
+        api_key: GatewayApiKey::new("sk_test"),
+        url: Some(spawn_fake_gateway(charges.clone()).await),
+        charge_timeout: Duration::from_secs(5),
+        test_cards: TestCards::default(),
+        signing: None,
+    };
+    let mut state = test_state(db.clone());
+    state.gateway_retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1) };
+    state.gateways = GatewayRouter::new(vec![routed(gateway), routed(MockGateway::default())]);
+
+    let (status, bytes) = post_raw_payment_with_state(state.clone(), payment_body("4242424242424242", 4000).to_string()).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["error"]["code"], "GATEWAY_OUTCOME_UNKNOWN");
+
+    // Not failed over to the mock, and not recorded as failed: reconciliation settles it
+    let (status, gateway) = sqlx::query_as::<_, (TransactionStatus, Option<String>)>(
+        "SELECT status, gateway FROM transactions",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(status, TransactionStatus::Pending);
+    assert_eq!(gateway.as_deref(), Some("real"));
+
+    // Beside it, a charge that crashed before it was sent, which the gateway has never seen
+    sqlx::query!(
+        r#"
+        INSERT INTO transactions (transaction_uuid, amount, currency, status, masked_card_number, card_brand, gateway, merchant_id)
+        VALUES ($1, 500, 'USD', 'pending', 'XXXX-XXXX-XXXX-4242', 'visa', 'real', 1)
+        "#,
+        Uuid::new_v4()
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query!("UPDATE transactions SET created_at = created_at - INTERVAL '2 hours'")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    assert_eq!(reconcile_pending_transactions(&state, chrono::Duration::minutes(30)).await.unwrap(), 2);
+    let rows = sqlx::query_as::<_, (TransactionStatus, Option<String>)>("SELECT status, gateway_ref FROM transactions ORDER BY id")
+        .fetch_all(&db)
+        .await
+        .unwrap();
+    assert_eq!(rows, [(TransactionStatus::Success, Some("gw_123".to_string())), (TransactionStatus::Failed, None)]);
+}
+
+#[tokio::test]
+async fn circuit_breaker_opens_probes_once_and_closes() {
+    let cooldown = Duration::from_millis(50);
+    let breaker = CircuitBreaker::new(2, cooldown);
+
+    breaker.record_failure();
+    assert_eq!(breaker.state_name(), "closed");
+    breaker.record_failure();
+    assert_eq!(breaker.state_name(), "open");
+    assert!(!breaker.try_acquire());
+
+    // After the cooldown exactly one probe goes through
+    tokio::time::sleep(cooldown).await;
+    assert!(breaker.try_acquire());
+    assert_eq!(breaker.state_name(), "half_open");
+    assert!(!breaker.try_acquire());
+
+    // A failed probe reopens straight away
+    breaker.record_failure();
+    assert_eq!(breaker.state_name(), "open");
+
+    // A probe that never reports back doesn't wedge the breaker half-open
+    tokio::time::sleep(cooldown).await;
+    assert!(breaker.try_acquire());
+    tokio::time::sleep(cooldown).await;
+    assert!(breaker.try_acquire());
+
+    breaker.record_success();
+    assert_eq!(breaker.state_name(), "closed");
+    assert!(breaker.try_acquire());
+}
+
+#[sqlx::test]
+async fn transient_gateway_failures_are_retried_with_the_same_uuid(db: PgPool) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut state = test_state(db.clone());
+    state.gateway_retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1) };
+    state.gateways = GatewayRouter::new(vec![routed(FlakyGateway { failures: 2, seen: seen.clone() })]);
+
+    let (status, bytes) = post_raw_payment_with_state(state.clone(), payment_body("4242424242424242", 1050).to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let transaction_id: Uuid = body["transaction_id"].as_str().unwrap().parse().unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![transaction_id; 3]);
+
+    // Out of attempts, the failure surfaces
+    seen.lock().unwrap().clear();
+    state.gateways = GatewayRouter::new(vec![routed(FlakyGateway { failures: 3, seen: seen.clone() })]);
+    let (status, _) = post_raw_payment_with_state(state, payment_body("4242424242424242", 1050).to_string()).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(seen.lock().unwrap().len(), 3);
+}
+
+#[sqlx::test]
+async fn timed_out_charge_stays_pending_without_failover(db: PgPool) {
+    let gateway = TimingOutGateway::default();
+    let charges = gateway.charges.clone();
+    let mut state = test_state(db.clone());
+    state.gateway_retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1) };
+    state.gateways = GatewayRouter::new(vec![routed(gateway), routed(MockGateway::default())]);
+
+    let (status, _) = post_raw_payment_with_state(state, payment_body("4242424242424242", 1050).to_string()).await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    // Neither retried nor failed over: the first charge may have landed
+    assert_eq!(charges.load(Ordering::SeqCst), 1);
+
+    let (status, gateway) = sqlx::query_as::<_, (TransactionStatus, Option<String>)>(
+        "SELECT status, gateway FROM transactions",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(status, TransactionStatus::Pending);
+    assert_eq!(gateway.as_deref(), Some("timing_out"));
+}
+
+#[sqlx::test]
+async fn pending_row_is_committed_before_the_gateway_call(db: PgPool) {
+    // One connection: if the handler held it across the call, the gateway's query would stall
+    let single = PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(Duration::from_secs(2))
+        .connect_with((*db.connect_options()).clone())
+        .await
+        .unwrap();
+    let status_during_call = Arc::new(Mutex::new(None));
+    let mut state = test_state(single.clone());
+    state.gateways = GatewayRouter::new(vec![routed(DatabaseLosingGateway {
+        db: single,
+        status_during_call: status_during_call.clone(),
+    })]);
+
+    let (status, _) = post_raw_payment_with_state(state, payment_body("4242424242424242", 1050).to_string()).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(*status_during_call.lock().unwrap(), Some(TransactionStatus::Pending));
+
+    // The outcome was lost with the database, but the attempt survives for reconciliation
+    let (status, events) = sqlx::query_as::<_, (TransactionStatus, i64)>(
+        "SELECT status, (SELECT COUNT(*) FROM transaction_events e WHERE e.transaction_id = t.id) FROM transactions t",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(status, TransactionStatus::Pending);
+    assert_eq!(events, 1);
+}
+
+#[sqlx::test]
+async fn charge_outcome_never_overwrites_a_void_made_while_in_flight(db: PgPool) {
+    let mut state = test_state(db.clone());
+    state.gateways = GatewayRouter::new(vec![routed(VoidedInFlightGateway { db: db.clone() })]);
+    let mut updates = state.status_updates.subscribe();
+
+    let (status, bytes) = post_raw_payment_with_state(state, payment_body("4242424242424242", 1050).to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["success"], false);
+    assert_eq!(body["message_key"], "AUTHORIZATION_VOIDED");
+
+    let (status, events) = sqlx::query_as::<_, (TransactionStatus, Vec<String>)>(
+        r#"
+        SELECT status, ARRAY(SELECT event_type::text FROM transaction_events e WHERE e.transaction_id = t.id ORDER BY e.id)
+        FROM transactions t
+        "#,
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(status, TransactionStatus::Voided);
+    assert_eq!(events, ["created", "voided"]);
+    // The approval that lost the race is never published
+    assert!(updates.try_recv().is_err());
+}
+
+#[sqlx::test]
+async fn stuck_pending_transactions_are_reconciled_with_the_gateway(db: PgPool) {
+    let mut state = test_state(db.clone());
+    let simulated = RealGateway { client: Client::new(), api_key: GatewayApiKey::new("sk_test"), url: None, charge_timeout: Duration::from_secs(1), test_cards: TestCards::default(), signing: None };
+    state.gateways = GatewayRouter::new(vec![routed(TimingOutGateway::default()), routed(MockGateway::default()), routed(simulated)]);
+    let charge = || post_raw_payment_with_state(state.clone(), payment_body("4242424242424242", 1050).to_string());
+
+    // Timed out, but the charge landed; then a crash that never reached the mock, and one
+    // on a gateway that can't look charges up
+    assert_eq!(charge().await.0, StatusCode::GATEWAY_TIMEOUT);
+    for gateway in ["mock", "real"] {
+        sqlx::query!(
+            r#"
+            INSERT INTO transactions (transaction_uuid, amount, currency, status, masked_card_number, card_brand, gateway, merchant_id)
+            VALUES ($1, 500, 'USD', 'pending', 'XXXX-XXXX-XXXX-4242', 'visa', $2, 1)
+            "#,
+            Uuid::new_v4(),
+            gateway
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+    sqlx::query!("UPDATE transactions SET created_at = created_at - INTERVAL '2 hours'")
+        .execute(&db)
+        .await
+        .unwrap();
+    // Too recent to touch: it may still be in flight
+    assert_eq!(charge().await.0, StatusCode::GATEWAY_TIMEOUT);
+
+    let pending_after = chrono::Duration::minutes(30);
+    assert_eq!(reconcile_pending_transactions(&state, pending_after).await.unwrap(), 2);
+    assert_eq!(reconcile_pending_transactions(&state, pending_after).await.unwrap(), 0);
+
+    let rows = sqlx::query_as::<_, (TransactionStatus, Option<String>, Option<String>, i64)>(
+        r#"
+        SELECT status, gateway, gateway_ref, (SELECT COUNT(*) FROM transaction_events e WHERE e.transaction_id = t.id)
+        FROM transactions t
+        ORDER BY id
+        "#,
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(rows[0].0, TransactionStatus::Success);
+    assert!(rows[0].2.as_deref().unwrap().starts_with("late_"));
+    assert_eq!(rows[0].3, 2);
+    assert_eq!((rows[1].0, rows[1].1.as_deref()), (TransactionStatus::Failed, Some("mock")));
+    // Not failed on a guess: it may have been charged
+    assert_eq!((rows[2].0, rows[2].1.as_deref()), (TransactionStatus::Pending, Some("real")));
+    assert_eq!(rows[3].0, TransactionStatus::Pending);
+}
+
+#[sqlx::test]
+async fn unavailable_gateway_fails_over_but_declines_do_not(db: PgPool) {
+    let mut state = test_state(db.clone());
+    state.gateways = GatewayRouter::new(vec![routed(UnavailableGateway), routed(MockGateway::default())]);
+
+    let body = payment_body("4242424242424242", 1050).to_string();
+    let (status, bytes) = post_raw_payment_with_state(state.clone(), body).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.status, TransactionStatus::Success);
+    assert_eq!(transaction.gateway.as_deref(), Some("mock"));
+
+    // A decline from the primary is final, even with a healthy secondary behind it
+    state.gateways = GatewayRouter::new(vec![
+        routed(MockGateway::default()),
+        routed(RealGateway { client: Client::new(), api_key: GatewayApiKey::new("sk_test"), url: None, charge_timeout: Duration::from_secs(1), test_cards: TestCards::default(), signing: None }),
+    ]);
+    let body = payment_body("4000000000000002", 1050).to_string();
+    let (_, bytes) = post_raw_payment_with_state(state, body).await;
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.status, TransactionStatus::Failed);
+    assert_eq!(transaction.gateway.as_deref(), Some("mock"));
+}
+
+#[test]
+fn webhook_signature_is_verifiable_hmac() {
+    let body = br#"{"status":"success"}"#;
+    let signature = webhook_signature(b"whsec_test", body);
+    let digest = hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"whsec_test").unwrap();
+    mac.update(body);
+    assert!(mac.verify_slice(&digest).is_ok());
+    assert_ne!(signature, webhook_signature(b"other_secret", body));
+}
+
+#[test]
+fn gateway_requests_are_signed_with_the_configured_algorithm_and_header() {
+    let body = br#"{"amount":1050}"#;
+    let mut signing = GatewaySigning {
+        algorithm: GatewaySigningAlgorithm::HmacSha256,
+        secret: b"gw_secret".to_vec().into(),
+        header: axum::http::HeaderName::from_static("x-acme-signature"),
+    };
+    let mut headers = reqwest::header::HeaderMap::new();
+    signing.attach(&mut headers, body);
+
+    let signature = headers.get("x-acme-signature").unwrap().to_str().unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"gw_secret").unwrap();
+    mac.update(body);
+    assert!(mac.verify_slice(&hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap()).is_ok());
+
+    signing.algorithm = GatewaySigningAlgorithm::HmacSha512;
+    let signature = signing.sign(body);
+    let mut mac = Hmac::<sha2::Sha512>::new_from_slice(b"gw_secret").unwrap();
+    mac.update(body);
+    assert!(mac.verify_slice(&hex::decode(signature.strip_prefix("sha512=").unwrap()).unwrap()).is_ok());
+    assert_eq!(GatewaySigningAlgorithm::parse("rsa-sha256"), None);
+}
+
+#[tokio::test]
+async fn tls_certificate_changes_are_detected_and_bad_pems_refused() {
+    let dir = std::env::temp_dir().join(format!("tls_{}", Uuid::new_v4().simple()));
+    std::fs::create_dir(&dir).unwrap();
+    let paths = TlsPaths { cert_path: dir.join("cert.pem"), key_path: dir.join("key.pem") };
+    assert_eq!(tls_files_modified(&paths), None);
+
+    std::fs::write(&paths.cert_path, "not a certificate").unwrap();
+    std::fs::write(&paths.key_path, "not a key").unwrap();
+    let loaded = tls_files_modified(&paths).unwrap();
+    assert!(matches!(load_tls_config(&paths).await, Err(AppError::EnvironmentError(msg)) if msg.contains("cert.pem")));
+
+    // A renewal rewrites the files, which the watcher notices by their modification time
+    let file = std::fs::File::options().write(true).open(&paths.key_path).unwrap();
+    file.set_modified(loaded + Duration::from_secs(60)).unwrap();
+    assert_eq!(tls_files_modified(&paths), Some(loaded + Duration::from_secs(60)));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn money_formats_with_currency_scale() {
+    assert_eq!(Money::from_minor(1050, "USD").unwrap().to_string(), "$10.50");
+    assert_eq!(Money::from_minor(1050, "JPY").unwrap().to_string(), "\u{a5}1050");
+    assert_eq!(Money::from_minor(1005, "KWD").unwrap().to_string(), "1.005 KWD");
+    assert_eq!(Money::from_minor(-5, "EUR").unwrap().to_string(), "-\u{20ac}0.05");
+    assert!(Money::from_minor(100, "XYZ").is_err());
+
+    let usd = Money::from_minor(100, "USD").unwrap();
+    assert!(usd.checked_sub(Money::from_minor(100, "EUR").unwrap()).is_err());
+}
+
+#[sqlx::test]
+async fn export_streams_filtered_transactions_as_csv(db: PgPool) {
+    post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    post_payment(&db, payment_body("4000000000000002", 2000)).await;
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 3000)).await;
+
+    let state = test_state(db.clone());
+    let response = send(&state, api_request("GET", "/api/v1/payments/export.csv?status=success", Some(TEST_MERCHANT_KEY)), Body::empty()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv; charset=utf-8");
+    assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"transactions.csv\"");
+
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    let mut reader = csv::Reader::from_reader(bytes.as_ref());
+    assert_eq!(reader.headers().unwrap(), EXPORT_COLUMNS.as_slice());
+    let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(&rows[0][0], body["transaction_id"].as_str().unwrap());
+    assert_eq!((&rows[0][1], &rows[0][2], &rows[0][3], &rows[0][4]), ("3000", "USD", "success", "XXXX-XXXX-XXXX-4242"));
+    assert_eq!(&rows[1][1], "1050");
+}
+
+#[sqlx::test]
+async fn list_filters_by_status_and_date(db: PgPool) {
+    post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    post_payment(&db, payment_body("4000000000000002", 2000)).await;
+
+    let (status, body) = get_json(&db, "/api/v1/payments?status=failed&currency=usd").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["total"], 1);
+    assert_eq!(body["items"][0]["amount"], 2000);
+
+    let (_, body) = get_json(&db, "/api/v1/payments?from=2000-01-01T00:00:00Z&to=2000-12-31T00:00:00Z").await;
+    assert_eq!(body["total"], 0);
+
+    let (status, _) = get_json(&db, "/api/v1/payments?from=yesterday").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test]
+async fn list_pages_by_cursor(db: PgPool) {
+    for amount in [1000, 2000, 3000] {
+        post_payment(&db, payment_body("4242424242424242", amount)).await;
+    }
+
+    let (status, body) = get_json(&db, "/api/v1/payments?limit=2").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["items"].as_array().unwrap().len(), 2);
+    assert_eq!((body["items"][0]["amount"].as_i64(), body["items"][1]["amount"].as_i64()), (Some(3000), Some(2000)));
+    let cursor = body["next_cursor"].as_str().unwrap().to_string();
+
+    // A row inserted after the first page is newer than the cursor and doesn't shift the next one
+    post_payment(&db, payment_body("4242424242424242", 4000)).await;
+    let (_, body) = get_json(&db, &format!("/api/v1/payments?limit=2&cursor={}", cursor)).await;
+    assert_eq!(body["items"].as_array().unwrap().len(), 1);
+    assert_eq!(body["items"][0]["amount"], 1000);
+    assert_eq!(body["total"], 4);
+    assert!(body["next_cursor"].is_null());
+
+    // Offset paging still works and reports a cursor too
+    let (_, body) = get_json(&db, "/api/v1/payments?limit=2&offset=1").await;
+    assert_eq!(body["items"][0]["amount"], 3000);
+    assert!(body["next_cursor"].is_string());
+
+    let (status, _) = get_json(&db, &format!("/api/v1/payments?cursor={}&offset=1", cursor)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = get_json(&db, "/api/v1/payments?cursor=nonsense").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["code"], "INVALID_CURSOR");
+}
+
+#[sqlx::test]
+async fn summary_aggregates_and_zero_fills(db: PgPool) {
+    let (_, body) = get_json(&db, "/api/v1/reports/summary?currency=USD").await;
+    assert_eq!(body["total_count"], 0);
+    assert_eq!(body["total_successful_amount"], 0);
+    assert_eq!(body["decline_rate"], 0.0);
+
+    post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    post_payment(&db, payment_body("4000000000000002", 2000)).await;
+
+    let (status, body) = get_json(&db, "/api/v1/reports/summary?currency=usd").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["total_count"], 2);
+    assert_eq!(body["total_successful_amount"], 1050);
+    assert_eq!(body["decline_rate"], 0.5);
+    assert_eq!(body["total_successful_display"], "$10.50");
+}
+
+#[sqlx::test]
+async fn signed_requests_are_verified_when_enabled(db: PgPool) {
+    let mut state = test_state(db.clone());
+    state.request_signing_secret = Some(b"sign_test".as_slice().into());
+    let body = payment_body("4242424242424242", 1050).to_string();
+    let now = Utc::now().timestamp().to_string();
+
+    let post = |signed: Option<(&str, &str, &[u8])>| {
+        let mut request = api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY));
+        if let Some((timestamp, nonce, signed_body)) = signed {
+            let signature = webhook_signature(b"sign_test", &signing_payload(timestamp, nonce, signed_body));
+            request = request
+                .header(SIGNATURE_HEADER, signature)
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(NONCE_HEADER, nonce);
+        }
+        send(&state, request, body.clone())
+    };
+
+    let unsigned = post(None).await;
+    assert_eq!(unsigned.status(), StatusCode::UNAUTHORIZED);
+
+    let tampered = post(Some((&now, "n1", b"{}"))).await;
+    assert_eq!(tampered.status(), StatusCode::UNAUTHORIZED);
+
+    let stale = (Utc::now().timestamp() - DEFAULT_REPLAY_WINDOW_SECS - 60).to_string();
+    let stale = post(Some((&stale, "n2", body.as_bytes()))).await;
+    assert_eq!(stale.status(), StatusCode::UNAUTHORIZED);
+
+    let signed = post(Some((&now, "n3", body.as_bytes()))).await;
+    assert_eq!(signed.status(), StatusCode::OK);
+
+    let replayed = post(Some((&now, "n3", body.as_bytes()))).await;
+    assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(transaction_count(&db).await, 1);
+}
+
+#[sqlx::test]
+async fn openapi_spec_is_served_without_auth(db: PgPool) {
+    let response = send(&test_state(db), api_request("GET", "/api-docs/openapi.json", None), Body::empty()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let spec = json_body(response).await;
+    assert!(spec["paths"]["/api/v1/payment"]["post"].is_object());
+    assert!(spec["components"]["schemas"]["PaymentRequest"].is_object());
+}
+
+async fn post_empty(db: &PgPool, uri: &str) -> (StatusCode, serde_json::Value) {
+    post_json(db, uri, "").await
+}
+
+async fn post_json(db: &PgPool, uri: &str, body: &str) -> (StatusCode, serde_json::Value) {
+    let request = api_request("POST", uri, Some(TEST_MERCHANT_KEY));
+    let response = send(&test_state(db.clone()), request, body.to_string()).await;
+
+    (response.status(), json_body(response).await)
+}
+
+#[sqlx::test]
+async fn void_cancels_unsettled_payment_but_not_refunded(db: PgPool) {
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let uuid = body["transaction_id"].as_str().unwrap().to_string();
+
+    let (status, body) = post_empty(&db, &format!("/api/v1/payment/{}/void", uuid)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["operation"], "void");
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, uuid.parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.status, TransactionStatus::Voided);
+
+    let (status, body) = post_empty(&db, &format!("/api/v1/payment/{}/void", uuid)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["error"]["code"], "ALREADY_VOIDED");
+
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let uuid = body["transaction_id"].as_str().unwrap().to_string();
+    let (_, body) = post_empty(&db, &format!("/api/v1/payment/{}/refund", uuid)).await;
+    assert_eq!(body["operation"], "refund");
+
+    let (status, body) = post_empty(&db, &format!("/api/v1/payment/{}/void", uuid)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["error"]["code"], "NOT_VOIDABLE");
+}
+
+#[sqlx::test]
+async fn authorization_can_be_captured_for_less(db: PgPool) {
+    let mut body = payment_body("4242424242424242", 1050);
+    body["capture"] = serde_json::json!(false);
+    let (status, body) = post_payment(&db, body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["operation"], "authorize");
+    let uuid = body["transaction_id"].as_str().unwrap().to_string();
+    assert_eq!(fetch_transaction(&db, TEST_MERCHANT, uuid.parse().unwrap()).await.unwrap().status, TransactionStatus::Authorized);
+
+    // Refunds need captured funds
+    let (status, _) = post_empty(&db, &format!("/api/v1/payment/{}/refund", uuid)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _) = post_json(&db, &format!("/api/v1/payment/{}/capture", uuid), r#"{"amount": 800}"#).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, uuid.parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.status, TransactionStatus::Success);
+    assert_eq!(transaction.amount, 800);
+    assert_eq!(transaction.authorized_amount, Some(1050));
+
+    let (status, body) = post_empty(&db, &format!("/api/v1/payment/{}/capture", uuid)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["error"]["code"], "NOT_CAPTURABLE");
+}
+
+#[sqlx::test]
+async fn concurrent_retry_returns_existing_transaction(db: PgPool) {
+    let body = payment_body("4242424242424242", 1050);
+    let mut request: PaymentRequest = serde_json::from_value(body.clone()).unwrap();
+    request.currency = "USD".to_string(); // fingerprinted after normalization
+    let request_hash = request_fingerprint(b"salt_test", &request);
+
+    // A first attempt still in flight: row inserted, response not stored yet
+    let existing = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO transactions (transaction_uuid, amount, currency, status, masked_card_number, card_brand, idempotency_key, request_hash, merchant_id)
+        VALUES ($1, 1050, 'USD', 'pending', '************4242', 'visa', 'retry-1', $2, 1)
+        "#,
+        existing,
+        request_hash
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let state = test_state(db.clone());
+    let post = |body: serde_json::Value| {
+        let request = api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY))
+            .header(IDEMPOTENCY_KEY_HEADER, "retry-1");
+        send(&state, request, body.to_string())
+    };
+
+    // Still pending: there is no outcome to replay yet
+    let response = post(body.clone()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(json_body(response).await["error"]["code"], PAYMENT_IN_PROGRESS);
+
+    // Once it has finished, the retry reports that outcome without charging again
+    sqlx::query!("UPDATE transactions SET status = 'success' WHERE transaction_uuid = $1", existing)
+        .execute(&db)
+        .await
+        .unwrap();
+    let response = post(body.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json = json_body(response).await;
+    assert_eq!(json["transaction_id"], existing.to_string());
+    assert_eq!(json["success"], true);
+    assert_eq!(transaction_count(&db).await, 1);
+
+    let response = post(payment_body("4242424242424242", 2000)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(transaction_count(&db).await, 1);
+}
+
+#[sqlx::test]
+async fn simultaneous_retries_charge_once(db: PgPool) {
+    let state = test_state(db.clone());
+    let post = || {
+        let request = api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY))
+            .header(IDEMPOTENCY_KEY_HEADER, "race-1");
+        send(&state, request, payment_body("4242424242424242", 1050).to_string())
+    };
+
+    // The loser either replays the outcome or, if it lands mid-charge, is told to wait
+    let (first, second) = tokio::join!(post(), post());
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort();
+    assert!(statuses == [StatusCode::OK, StatusCode::OK] || statuses == [StatusCode::OK, StatusCode::CONFLICT], "{:?}", statuses);
+    assert_eq!(post().await.status(), StatusCode::OK);
+    assert_eq!(transaction_count(&db).await, 1);
+}
+
+#[sqlx::test]
+async fn saturated_payment_slots_shed_with_retry_after(db: PgPool) {
+    async fn post(state: &AppState) -> Response {
+        let request = api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY));
+        send(state, request, payment_body("4242424242424242", 1050).to_string()).await
+    }
+
+    let mut state = test_state(db.clone());
+    state.payment_slots = Arc::new(Semaphore::new(1));
+
+    // The only slot is held by a payment still in flight
+    let in_flight = state.payment_slots.clone().try_acquire_owned().unwrap();
+    let response = post(&state).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::RETRY_AFTER], SERVICE_UNAVAILABLE_RETRY_AFTER_SECS.to_string().as_str());
+    assert_eq!(json_body(response).await["error"]["code"], "SERVICE_UNAVAILABLE");
+    assert_eq!(transaction_count(&db).await, 0);
+
+    drop(in_flight);
+    assert_eq!(post(&state).await.status(), StatusCode::OK);
+    assert_eq!(state.payment_slots.available_permits(), 1);
+}
+
+#[sqlx::test]
+async fn exhausted_db_pool_is_reported_as_unavailable(db: PgPool) {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(Duration::from_millis(100))
+        .connect_with((*db.connect_options()).clone())
+        .await
+        .unwrap();
+    let _held = pool.acquire().await.unwrap();
+
+    let request = api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY));
+    let response = send(&test_state(pool.clone()), request, payment_body("4242424242424242", 1050).to_string()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(json_body(response).await["error"]["code"], "SERVICE_UNAVAILABLE");
+
+    // A failing query is still a server error
+    let err = AppError::from(sqlx::query("SELECT * FROM missing_table").execute(&db).await.unwrap_err());
+    assert_eq!(err.into_response().status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[sqlx::test]
+async fn retention_anonymizes_or_deletes_only_expired_settled_transactions(db: PgPool) {
+    let mut uuids = Vec::new();
+    for _ in 0..3 {
+        let mut request = payment_body("4242424242424242", 1050);
+        request["metadata"] = serde_json::json!({ "order_id": "ord_1" });
+        let (_, body) = post_payment(&db, request).await;
+        uuids.push(body["transaction_id"].as_str().unwrap().parse::<Uuid>().unwrap());
+    }
+    let (expired, pending, recent) = (uuids[0], uuids[1], uuids[2]);
+    sqlx::query!("UPDATE transactions SET created_at = NOW() - INTERVAL '40 days' WHERE transaction_uuid = ANY($1)", &[expired, pending])
+        .execute(&db)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE transactions SET status = 'pending' WHERE transaction_uuid = $1", pending)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let anonymize = RetentionPolicy { after_days: 30, mode: RetentionMode::Anonymize };
+    assert_eq!(purge_expired_transactions(&db, anonymize).await.unwrap(), 1);
+    assert_eq!(purge_expired_transactions(&db, anonymize).await.unwrap(), 0); // Already anonymized
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, expired).await.unwrap();
+    assert_eq!(transaction.masked_card_number, ANONYMIZED_CARD_MASK);
+    assert_eq!(transaction.metadata, None);
+    assert_eq!(transaction.amount, 1050);
+    assert_eq!(fetch_transaction(&db, TEST_MERCHANT, recent).await.unwrap().masked_card_number, "XXXX-XXXX-XXXX-4242");
+
+    let delete = RetentionPolicy { after_days: 30, mode: RetentionMode::Delete };
+    assert_eq!(purge_expired_transactions(&db, delete).await.unwrap(), 1);
+    assert!(matches!(fetch_transaction(&db, TEST_MERCHANT, expired).await, Err(AppError::NotFound(_))));
+    fetch_transaction(&db, TEST_MERCHANT, pending).await.unwrap();
+    fetch_transaction(&db, TEST_MERCHANT, recent).await.unwrap();
+
+    // Outside the purge the audit trail is still append-only
+    assert!(sqlx::query!("DELETE FROM transaction_events").execute(&db).await.is_err());
+}
+
+#[sqlx::test]
+async fn async_payments_are_queued_then_charged_by_the_worker(db: PgPool) {
+    async fn post(state: &AppState) -> Response {
+        let request = api_request("POST", "/api/v1/payment/async", Some(TEST_MERCHANT_KEY));
+        send(state, request, payment_body("4242424242424242", 1050).to_string()).await
+    }
+
+    // Nobody draining a one-slot queue: the second payment is turned away unrecorded
+    let mut state = test_state(db.clone());
+    let (queue, _stalled) = mpsc::channel(1);
+    state.payment_queue = queue;
+    assert_eq!(post(&state).await.status(), StatusCode::ACCEPTED);
+    let response = post(&state).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(json_body(response).await["error"]["code"], "SERVICE_UNAVAILABLE");
+    assert_eq!(transaction_count(&db).await, 1);
+
+    let (queue, queued) = mpsc::channel(4);
+    state.payment_queue = queue;
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let worker = tokio::spawn(run_payment_worker(state.clone(), queued, 2, stopped));
+
+    let response = post(&state).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = json_body(response).await;
+    assert_eq!(body["status"], "pending");
+    let uuid: Uuid = body["transaction_id"].as_str().unwrap().parse().unwrap();
+
+    let mut status = TransactionStatus::Pending;
+    for _ in 0..100 {
+        status = fetch_transaction(&db, TEST_MERCHANT, uuid).await.unwrap().status;
+        if status != TransactionStatus::Pending {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(status, TransactionStatus::Success);
+
+    stop.send(true).unwrap();
+    worker.await.unwrap();
+}
+
+#[sqlx::test]
+async fn status_stream_follows_a_pending_payment_to_its_outcome(db: PgPool) {
+    let state = test_state(db.clone());
+    let transaction_uuid = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO transactions (transaction_uuid, amount, currency, status, masked_card_number, card_brand, merchant_id)
+        VALUES ($1, 1050, 'USD', 'pending', 'XXXX-XXXX-XXXX-4242', 'visa', 1)
+        "#,
+        transaction_uuid
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let worker = {
+        let state = state.clone();
+        async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sqlx::query!("UPDATE transactions SET status = 'success' WHERE transaction_uuid = $1", transaction_uuid)
+                .execute(&state.db)
+                .await
+                .unwrap();
+            publish_status(&state, Uuid::new_v4(), TransactionStatus::Failed); // Someone else's
+            publish_status(&state, transaction_uuid, TransactionStatus::Success);
+        }
+    };
+    let uri = format!("/api/v1/payment/{}/stream", transaction_uuid);
+    let (response, ()) = tokio::join!(send(&state, api_request("GET", &uri, Some(TEST_MERCHANT_KEY)), Body::empty()), worker);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+    // The stream ends by itself once the outcome is out
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    let statuses: Vec<serde_json::Value> = std::str::from_utf8(&bytes)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap()["status"].clone())
+        .collect();
+    assert_eq!(statuses, ["pending", "success"]);
+
+    let (status, _) = get_json(&db, &format!("/api/v1/payment/{}/stream", Uuid::new_v4())).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[sqlx::test]
+async fn readiness_follows_the_database_not_the_pool_size(db: PgPool) {
+    let state = test_state(db.clone());
+    let ready = || send(&state, api_request("GET", "/readyz", None), Body::empty());
+
+    assert_eq!(ready().await.status(), StatusCode::OK);
+
+    db.close().await;
+    let response = ready().await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(json_body(response).await["database_connected"], false);
+}
+
+#[sqlx::test]
+async fn deep_health_reports_gateway_without_failing(db: PgPool) {
+    let mut state = test_state(db.clone());
+    // Nothing listens on port 1, so the probe fails fast
+    state.gateway_probe = Some(GatewayHealthProbe::new(Client::new(), "http://127.0.0.1:1/health"));
+
+    let health = |uri: &'static str| send(&state, api_request("GET", uri, None), Body::empty());
+
+    let body = json_body(health("/health").await).await;
+    assert_eq!(body["status"], "ok");
+    assert!(body["gateway"].get("reachable").is_none());
+
+    let response = health("/health?deep=true").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    assert_eq!(body["status"], "degraded");
+    assert_eq!(body["database"]["reachable"], true);
+    assert_eq!(body["gateway"]["reachable"], false);
+}
+
+#[test]
+fn decline_rate_forgets_outcomes_past_its_window() {
+    let rate = DeclineRate::new(Duration::from_secs(600));
+    let start = Instant::now();
+    rate.record_at(start, true);
+    rate.record_at(start + Duration::from_secs(300), false);
+    rate.record_at(start + Duration::from_secs(300), true);
+
+    assert_eq!(rate.counts_at(start + Duration::from_secs(301)), (3, 2));
+    assert_eq!(rate.counts_at(start + Duration::from_secs(601)), (2, 1));
+    assert_eq!(DeclineRate::rate(2, 1), Some(0.5));
+    assert_eq!(rate.counts_at(start + Duration::from_secs(1_000)), (0, 0));
+    assert_eq!(DeclineRate::rate(0, 0), None);
+}
+
+#[sqlx::test]
+async fn deep_health_reports_the_decline_rate(db: PgPool) {
+    let state = test_state(db.clone());
+    for card in ["4242424242424242", "4000000000000002", "4000000000000002", "4242424242424242"] {
+        post_raw_payment_with_state(state.clone(), payment_body(card, 1050).to_string()).await;
+    }
+
+    let body = json_body(send(&state, api_request("GET", "/health?deep=true", None), Body::empty()).await).await;
+    assert_eq!(body["gateway"]["decline_rate"], serde_json::json!({ "window_minutes": 15, "payments": 4, "declines": 2, "rate": 0.5 }));
+}
+
+#[sqlx::test]
+async fn velocity_limit_counts_only_successful_charges(db: PgPool) {
+    let mut state = test_state(db.clone());
+    state.velocity.max_charges = 2;
+    let post = |body: serde_json::Value| post_raw_payment_with_state(state.clone(), body.to_string());
+
+    // Declines don't count toward the limit
+    for _ in 0..3 {
+        let (status, _) = post(payment_body("4000000000000002", 1050)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    for _ in 0..2 {
+        let (status, _) = post(payment_body("4242424242424242", 1050)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, bytes) = post(payment_body("4242424242424242", 1050)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["error"]["code"], "VELOCITY_LIMIT_EXCEEDED");
+    assert_eq!(transaction_count(&db).await, 5);
+
+    // Same last four digits, different card: counted separately
+    let (status, _) = post(payment_body("5555555555594242", 1050)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[test]
+fn card_fingerprint_is_stable_salted_and_pan_free() {
+    let fingerprint = card_fingerprint(b"salt_a", "4242 4242 4242 4242");
+    assert_eq!(fingerprint, card_fingerprint(b"salt_a", "4242424242424242"));
+    assert_ne!(fingerprint, card_fingerprint(b"salt_b", "4242424242424242"));
+    assert_ne!(fingerprint, card_fingerprint(b"salt_a", "5555555555554444"));
+    assert_eq!(fingerprint.len(), 64);
+    assert!(!fingerprint.contains("4242"));
+}
+
+#[test]
+fn request_hash_never_depends_on_the_raw_card_data() {
+    let request = |card_number: &str, cvv: &str| {
+        let mut body = payment_body(card_number, 1050);
+        body["cvv"] = serde_json::json!(cvv);
+        serde_json::from_value::<PaymentRequest>(body).unwrap()
+    };
+    let hash = request_fingerprint(b"salt_a", &request("4242424242424242", "123"));
+
+    // The CVV is left out entirely; the PAN only counts through the salted fingerprint
+    assert_eq!(hash, request_fingerprint(b"salt_a", &request("4242424242424242", "999")));
+    assert_ne!(hash, request_fingerprint(b"salt_a", &request("5555555555554444", "123")));
+    assert_ne!(hash, request_fingerprint(b"salt_b", &request("4242424242424242", "123")));
+}
+
+#[sqlx::test]
+async fn state_changes_are_recorded_as_append_only_events(db: PgPool) {
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let uuid = body["transaction_id"].as_str().unwrap().to_string();
+
+    let (status, _) = post_json(&db, &format!("/api/v1/payment/{}/refund", uuid), r#"{"amount": 50}"#).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, events) = get_json(&db, &format!("/api/v1/payment/{}/events", uuid)).await;
+    assert_eq!(status, StatusCode::OK);
+    let events: Vec<(&str, &str, i64)> = events
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["event_type"].as_str().unwrap(), e["status"].as_str().unwrap(), e["amount"].as_i64().unwrap()))
+        .collect();
+    assert_eq!(events, [
+        ("created", "pending", 1050),
+        ("succeeded", "success", 1050),
+        ("partially_refunded", "partially_refunded", 50),
+    ]);
+
+    let rewrite = sqlx::query!("UPDATE transaction_events SET amount = 0").execute(&db).await;
+    assert!(rewrite.is_err());
+}
+
+#[sqlx::test]
+async fn parallel_full_refunds_succeed_once(db: PgPool) {
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let uuid = body["transaction_id"].as_str().unwrap().to_string();
+
+    let refund = |db: PgPool, uri: String| tokio::spawn(async move { post_empty(&db, &uri).await.0 });
+    let uri = format!("/api/v1/payment/{}/refund", uuid);
+    let (first, second) = tokio::join!(refund(db.clone(), uri.clone()), refund(db.clone(), uri));
+    let mut statuses = [first.unwrap(), second.unwrap()];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, uuid.parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.refunded_amount, 1050);
+    assert_eq!(transaction.version, 2); // charged, then refunded once
+}
+
+#[sqlx::test]
+async fn retried_refund_with_the_same_key_refunds_once(db: PgPool) {
+    let (_, first) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let (_, second) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let state = test_state(db.clone());
+    let refund = |transaction: &serde_json::Value, body: &str| {
+        let uri = format!("/api/v1/payment/{}/refund", transaction["transaction_id"].as_str().unwrap());
+        let request = api_request("POST", &uri, Some(TEST_MERCHANT_KEY)).header(IDEMPOTENCY_KEY_HEADER, "refund-1");
+        send(&state, request, body.to_string())
+    };
+
+    let (original, retry) = tokio::join!(refund(&first, r#"{"amount": 300}"#), refund(&first, r#"{"amount": 300}"#));
+    assert_eq!((original.status(), retry.status()), (StatusCode::OK, StatusCode::OK));
+    let (original, retry) = (json_body(original).await, json_body(retry).await);
+    assert_eq!(original["timestamp"], retry["timestamp"]); // Replayed, not refunded again
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, first["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.refunded_amount, 300);
+
+    let response = refund(&first, r#"{"amount": 400}"#).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json_body(response).await["error"]["code"], "IDEMPOTENCY_KEY_REUSED");
+
+    // Keys are scoped to their transaction
+    let response = refund(&second, r#"{"amount": 400}"#).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, second["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!(transaction.refunded_amount, 400);
+}
+
+#[sqlx::test]
+async fn stale_version_is_refused(db: PgPool) {
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+
+    sqlx::query!("UPDATE transactions SET version = version + 1 WHERE id = $1", transaction.id)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let stale = sqlx::query!(
+        "UPDATE transactions SET status = 'voided' WHERE id = $1 AND version = $2",
+        transaction.id,
+        transaction.version
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    let err = ensure_unmodified(stale).unwrap_err();
+    assert!(matches!(err, AppError::Conflict("CONCURRENT_MODIFICATION", _)));
+}
+
+#[sqlx::test]
+async fn parallel_refunds_and_captures_apply_once(db: PgPool) {
+    let (_, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    let refund = format!("/api/v1/payment/{}/refund", body["transaction_id"].as_str().unwrap());
+    let mut auth_only = payment_body("4242424242424242", 1050);
+    auth_only["capture"] = serde_json::json!(false);
+    let (_, body) = post_payment(&db, auth_only).await;
+    let capture = format!("/api/v1/payment/{}/capture", body["transaction_id"].as_str().unwrap());
+
+    // The row lock serializes each pair: the loser sees the winner's write, not a stale read
+    let ((first, _), (second, _)) = tokio::join!(post_empty(&db, &refund), post_empty(&db, &refund));
+    let mut statuses = [first, second];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+
+    let ((first, _), (second, _)) = tokio::join!(post_empty(&db, &capture), post_empty(&db, &capture));
+    let mut statuses = [first, second];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+
+    let rows = sqlx::query_as::<_, (TransactionStatus, i64, i32)>(
+        "SELECT status, refunded_amount, version FROM transactions ORDER BY id",
+    )
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    // Charge outcome, then the one write that went through
+    assert_eq!(rows, [(TransactionStatus::Refunded, 1050, 2), (TransactionStatus::Success, 0, 2)]);
+}
+
+// Collects formatted log output for assertions
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LogBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[test]
+fn json_logs_lift_span_fields_to_top_level() {
+    let buffer = LogBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .fmt_fields(JsonFields::new())
+        .event_format(JsonLogFormat)
+        .with_writer(move || writer.clone())
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, || {
+        let request = tracing::info_span!("request", request_id = "req-1");
+        let _request = request.enter();
+        let payment = tracing::info_span!("payment", transaction_uuid = tracing::field::Empty);
+        let _payment = payment.enter();
+        payment.record("transaction_uuid", "3f1c");
+        info!(amount = 1050, "payment succeeded");
+    });
+
+    let output = buffer.contents();
+    let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+    assert_eq!(line["level"], "INFO");
+    assert_eq!(line["target"], "rust_payment_api::tests");
+    assert_eq!(line["request_id"], "req-1");
+    assert_eq!(line["transaction_uuid"], "3f1c");
+    assert_eq!(line["amount"], 1050);
+    assert_eq!(line["message"], "payment succeeded");
+    assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+}
+
+#[sqlx::test]
+async fn http_logging_redacts_card_data_and_credentials(db: PgPool) {
+    let buffer = LogBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let mut state = test_state(db);
+    state.http_logging = true;
+    let mut body = payment_body("4242424242424242", 1050);
+    body["metadata"] = serde_json::json!({ "note": "card 4111 1111 1111 1111" });
+    let response = send(&state, api_request("POST", "/api/v1/payment", Some(TEST_MERCHANT_KEY)), body.to_string()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let logs = buffer.contents();
+    assert!(logs.contains("http request"));
+    assert!(logs.contains("http response") && logs.contains("status=200"));
+    assert!(logs.contains(r#""card_number":"[REDACTED]""#));
+    assert!(logs.contains(r#""cvv":"[REDACTED]""#));
+    assert!(logs.contains("x-api-key: [REDACTED]"));
+    for secret in ["4242424242424242", "4111 1111 1111 1111", r#""cvv":"123""#, TEST_MERCHANT_KEY] {
+        assert!(!logs.contains(secret), "{} leaked into the logs", secret);
+    }
+}
+
+#[test]
+fn outbound_requests_carry_the_current_trace() {
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+    opentelemetry::global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("payment");
+        let _entered = span.enter();
+
+        let headers = trace_context_headers();
+        let traceparent = headers.get("traceparent").unwrap().to_str().unwrap();
+        // version-traceid-spanid-flags, with a real (non-zero) trace ID
+        let parts: Vec<&str> = traceparent.split('-').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "00");
+        assert_ne!(parts[1], "0".repeat(32));
+    });
+}
+
+// Serves fixed rates, or fails like a provider outage when empty
+struct FixedRates(HashMap<String, Decimal>);
+
+#[async_trait]
+impl FxRateProvider for FixedRates {
+    async fn rates(&self, _: &str) -> Result<HashMap<String, Decimal>, AppError> {
+        if self.0.is_empty() {
+            return Err(AppError::GatewayError("FX rate provider unavailable: connection refused".to_string()));
+        }
+        Ok(self.0.clone())
+    }
+}
+
+#[sqlx::test]
+async fn cross_currency_charge_is_converted_and_recorded(db: PgPool) {
+    let mut state = test_state(db.clone());
+    let rates = HashMap::from([("JPY".to_string(), Decimal::new(150_123, 3))]);
+    state.fx = Some(FxConverter::new(FixedRates(rates), Duration::from_secs(60), RoundingMode::HalfUp));
+
+    let mut body = payment_body("4242424242424242", 1050);
+    body["settlement_currency"] = serde_json::json!("jpy");
+    let (status, bytes) = post_raw_payment_with_state(state, body.to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+    // $10.50 * 150.123 = 1576.29 yen, rounded to whole yen
+    assert_eq!(body["amount"], 1576);
+    assert_eq!(body["currency"], "JPY");
+    assert_eq!(body["conversion"]["original_amount"], 1050);
+    assert_eq!(body["conversion"]["original_currency"], "USD");
+    assert_eq!(body["conversion"]["rate"], "150.123");
+
+    let transaction = fetch_transaction(&db, TEST_MERCHANT, body["transaction_id"].as_str().unwrap().parse().unwrap()).await.unwrap();
+    assert_eq!((transaction.amount, transaction.currency.as_str()), (1576, "JPY"));
+    assert_eq!(transaction.presentment_amount, Some(1050));
+    assert_eq!(transaction.presentment_currency.as_deref(), Some("USD"));
+    assert_eq!(transaction.fx_rate, Some(Decimal::new(150_123, 3)));
+    assert_eq!(transaction.fx_rounding, Some(RoundingMode::HalfUp));
+}
+
+#[test]
+fn conversion_rounding_modes() {
+    let usd = Currency::from_code("USD").unwrap();
+    let jpy = Currency::from_code("JPY").unwrap();
+    let convert = |cents, rate, mode| Money::new(cents, usd).convert(jpy, rate, mode).unwrap().amount();
+
+    // $1.25 at 2.0 = 2.5 yen; $1.75 at 2.0 = 3.5 yen
+    let rate = Decimal::new(2, 0);
+    assert_eq!(convert(125, rate, RoundingMode::HalfUp), 3);
+    assert_eq!(convert(125, rate, RoundingMode::HalfEven), 2);
+    assert_eq!(convert(175, rate, RoundingMode::HalfEven), 4);
+    assert_eq!(convert(175, rate, RoundingMode::Floor), 3);
+
+    // 100 yen at 0.0066 = $0.66 exactly: no mode changes an exact result
+    let back = |mode| Money::new(100, jpy).convert(usd, Decimal::new(66, 4), mode).unwrap().amount();
+    assert_eq!([back(RoundingMode::HalfUp), back(RoundingMode::HalfEven), back(RoundingMode::Floor)], [66, 66, 66]);
+}
+
+#[sqlx::test]
+async fn fx_provider_failure_rejects_without_charging(db: PgPool) {
+    let mut state = test_state(db.clone());
+    state.fx = Some(FxConverter::new(FixedRates(HashMap::new()), Duration::from_secs(60), RoundingMode::HalfUp));
+
+    let mut body = payment_body("4242424242424242", 1050);
+    body["settlement_currency"] = serde_json::json!("EUR");
+    let (status, _) = post_raw_payment_with_state(state, body.to_string()).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(transaction_count(&db).await, 0);
+
+    // Same currency is not a conversion, so it never needs the provider
+    let (status, _) = post_payment(&db, {
+        let mut body = payment_body("4242424242424242", 1050);
+        body["settlement_currency"] = serde_json::json!("USD");
+        body
+    }).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn saved_payment_methods_are_charged_by_token_id_on_their_gateway_for_their_merchant_only(db: PgPool) {
+    let other = register_merchants(&db, &[("other".to_string(), "mk_other".to_string(), Roles::ALL)]).await.unwrap();
+    let mut state = test_state(db.clone());
+    state.merchant_keys = Arc::new(vec![
+        (Sha256::digest(TEST_MERCHANT_KEY.as_bytes()).into(), TEST_MERCHANT, Roles::ALL),
+        other[0],
+    ]);
+    // Tokens are issued by the default gateway; USD charges would otherwise route elsewhere
+    state.gateways = GatewayRouter::new(vec![routed(MockGateway::default())])
+        .with_route("USD", vec![routed(UnavailableGateway)]);
+
+    let call = |uri: &str, key: &str, body: String| send(&state, api_request("POST", uri, Some(key)), body);
+
+    let mut card = payment_body("4111111111111111", 0);
+    for field in ["amount", "currency"] {
+        card.as_object_mut().unwrap().remove(field);
+    }
+    let response = call("/api/v1/tokens", TEST_MERCHANT_KEY, card.to_string()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let method = json_body(response).await;
+    assert_eq!(method["masked_card_number"], "XXXX-XXXX-XXXX-1111");
+    assert_eq!(method["card_brand"], "visa");
+    assert!(method.get("payment_token").is_none());
+
+    let stored: String = sqlx::query_scalar("SELECT payment_token FROM payment_methods").fetch_one(&db).await.unwrap();
+    assert!(stored.starts_with("tok_"));
+
+    let charge = serde_json::json!({ "amount": 1050, "currency": "usd", "token_id": method["token_id"] }).to_string();
+    let response = call("/api/v1/payment", "mk_other", charge.clone()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json_body(response).await["error"]["code"], "UNKNOWN_PAYMENT_METHOD");
+    assert_eq!(transaction_count(&db).await, 0);
+
+    let charged = json_body(call("/api/v1/payment", TEST_MERCHANT_KEY, charge).await).await;
+    assert_eq!(charged["success"], true);
+    let (masked, fingerprint, gateway): (String, Option<String>, Option<String>) =
+        sqlx::query_as("SELECT masked_card_number, card_fingerprint, gateway FROM transactions").fetch_one(&db).await.unwrap();
+    assert!(masked.starts_with("XXXX-XXXX-XXXX-"));
+    assert_eq!(fingerprint, None);
+    assert_eq!(gateway.as_deref(), Some("mock"));
+}
+
+#[sqlx::test]
+async fn gateway_key_is_rotated_from_its_secret_file(db: PgPool) {
+    let path = std::env::temp_dir().join(format!("gateway_key_{}", Uuid::new_v4().simple()));
+    std::fs::write(&path, "sk_rotated\n").unwrap();
+    let mut state = test_state(db.clone());
+    state.api_key = GatewayApiKey::new("sk_test").with_source(Some(&path));
+    let gateway_key = state.api_key.clone(); // As the RealGateway holds it
+    let reload = || send(&state, api_request("POST", "/api/v1/admin/gateway-key/reload", Some(TEST_MERCHANT_KEY)), Body::empty());
+
+    let response = reload().await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["changed"], true);
+    assert_eq!(&*gateway_key.current(), "sk_rotated");
+
+    // A bad secret is refused and the working key kept
+    std::fs::write(&path, "sk broken").unwrap();
+    let response = reload().await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(json_body(response).await["error"]["code"], "INVALID_GATEWAY_KEY");
+    assert_eq!(&*gateway_key.current(), "sk_rotated");
+    std::fs::remove_file(&path).unwrap();
+
+    let (status, body) = post_empty(&db, "/api/v1/admin/gateway-key/reload").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["code"], "GATEWAY_KEY_SOURCE_NOT_CONFIGURED");
+}
+
+#[sqlx::test]
+async fn merchants_only_see_their_own_transactions(db: PgPool) {
+    let other = register_merchants(&db, &[("other".to_string(), "mk_other".to_string(), Roles::ALL)]).await.unwrap();
+    let mut state = test_state(db.clone());
+    state.merchant_keys = Arc::new(vec![
+        (Sha256::digest(TEST_MERCHANT_KEY.as_bytes()).into(), TEST_MERCHANT, Roles::ALL),
+        other[0],
+    ]);
+    assert_ne!(other[0].1, TEST_MERCHANT);
+
+    let call = |method: &str, uri: &str, key: &str, body: String| send(&state, api_request(method, uri, Some(key)), body);
+
+    let body = payment_body("4242424242424242", 1050).to_string();
+    let charged = json_body(call("POST", "/api/v1/payment", TEST_MERCHANT_KEY, body).await).await;
+    let uuid = charged["transaction_id"].as_str().unwrap();
+
+    let response = call("GET", &format!("/api/v1/payment/{}", uuid), "mk_other", String::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = call("POST", &format!("/api/v1/payment/{}/refund", uuid), "mk_other", String::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let listed = json_body(call("GET", "/api/v1/payments", "mk_other", String::new()).await).await;
+    assert_eq!(listed["total"], 0);
+    let summary = json_body(call("GET", "/api/v1/reports/summary", "mk_other", String::new()).await).await;
+    assert_eq!(summary["total_count"], 0);
+
+    let listed = json_body(call("GET", "/api/v1/payments", TEST_MERCHANT_KEY, String::new()).await).await;
+    assert_eq!(listed["total"], 1);
+}
+
+#[test]
+fn api_keys_resolve_to_their_merchant() {
+    let keys = [
+        (Sha256::digest(b"mk_a").into(), MerchantId(1), Roles::ALL),
+        (Sha256::digest(b"mk_b").into(), MerchantId(2), Roles(Roles::READ)),
+    ];
+    assert_eq!(authenticate_merchant(&keys, "mk_a"), Some((MerchantId(1), Roles::ALL)));
+    assert_eq!(authenticate_merchant(&keys, "mk_b"), Some((MerchantId(2), Roles(Roles::READ))));
+    assert_eq!(authenticate_merchant(&keys, "mk_c"), None);
+}
+
+#[sqlx::test]
+async fn ip_allowlist_checks_the_forwarded_client_behind_trusted_proxies(db: PgPool) {
+    let allowlist = IpAllowlist {
+        allowed: vec!["203.0.113.0/24".parse().unwrap(), "2001:db8::/32".parse().unwrap()].into(),
+        trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()].into(),
+    };
+    let forwarded = |chain: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", HeaderValue::from_str(chain).unwrap());
+        headers
+    };
+    let proxy: IpAddr = "10.1.2.3".parse().unwrap();
+
+    // Spoofed entries to the left of the first untrusted hop are ignored
+    let client = allowlist.client_ip(proxy, &forwarded("198.51.100.7, 203.0.113.9, 10.0.0.2"));
+    assert_eq!(client, "203.0.113.9".parse::<IpAddr>().unwrap());
+    // An untrusted peer can't claim another address
+    let client = allowlist.client_ip("198.51.100.7".parse().unwrap(), &forwarded("203.0.113.9"));
+    assert_eq!(client, "198.51.100.7".parse::<IpAddr>().unwrap());
+    assert!(allowlist.allows(allowlist.client_ip(proxy, &forwarded("2001:db8::1"))));
+    assert!(allowlist.allows(allowlist.client_ip("::ffff:203.0.113.5".parse().unwrap(), &HeaderMap::new())));
+
+    let mut state = test_state(db);
+    state.ip_allowlist = allowlist;
+    let call = |peer: [u8; 4]| {
+        send_from(&state, peer, api_request("GET", "/api/v1/payments", Some(TEST_MERCHANT_KEY)), Body::empty())
+    };
+    assert_eq!(call([198, 51, 100, 7]).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(call([203, 0, 113, 7]).await.status(), StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn keys_without_the_route_role_are_forbidden(db: PgPool) {
+    let mut state = test_state(db);
+    state.merchant_keys = Arc::new(vec![
+        (Sha256::digest(b"mk_read").into(), TEST_MERCHANT, Roles::parse("read").unwrap()),
+    ]);
+
+    let call = |method: &str, uri: &str| {
+        send(&state, api_request(method, uri, Some("mk_read")), payment_body("4242424242424242", 1050).to_string())
+    };
+
+    assert_eq!(call("POST", "/api/v1/payment").await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(call("GET", "/api/v1/reports/summary").await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(call("GET", "/api/v1/payments").await.status(), StatusCode::OK);
+
+    assert_eq!(Roles::parse("charge+refund"), Ok(Roles(Roles::CHARGE | Roles::REFUND)));
+    assert!(Roles::parse("charge+owner").is_err());
+    assert!(Roles(Roles::ADMIN).allows(Roles::REFUND));
+}
+
+#[sqlx::test]
+async fn clients_over_the_rate_limit_get_429_with_retry_after(db: PgPool) {
+    let mut state = test_state(db);
+    state.rate_limiter = RateLimiter::new(2);
+    let list = |peer: [u8; 4]| {
+        send_from(&state, peer, api_request("GET", "/api/v1/payments", Some(TEST_MERCHANT_KEY)), Body::empty())
+    };
+
+    for _ in 0..2 {
+        assert_eq!(list([127, 0, 0, 1]).await.status(), StatusCode::OK);
+    }
+    let limited = list([127, 0, 0, 1]).await;
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = limited.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+    assert!((1..=60).contains(&retry_after));
+
+    // Buckets are per client
+    assert_eq!(list([127, 0, 0, 2]).await.status(), StatusCode::OK);
+
+    // Behind a trusted proxy, that is the forwarded client rather than the proxy
+    state.ip_allowlist.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()].into();
+    let via_proxy = |client: &str| {
+        let request = api_request("GET", "/api/v1/payments", Some(TEST_MERCHANT_KEY)).header("X-Forwarded-For", client);
+        send_from(&state, [10, 0, 0, 1], request, Body::empty())
+    };
+    for _ in 0..2 {
+        assert_eq!(via_proxy("203.0.113.1").await.status(), StatusCode::OK);
+    }
+    assert_eq!(via_proxy("203.0.113.1").await.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(via_proxy("203.0.113.2").await.status(), StatusCode::OK);
+}
+
+#[sqlx::test]
+async fn missing_api_key_is_unauthorized(db: PgPool) {
+    let response = send(&test_state(db), api_request("GET", "/api/v1/payments", None), Body::empty()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[sqlx::test]
+async fn legacy_paths_are_deprecated_aliases(db: PgPool) {
+    let request = api_request("GET", "/api/payments", Some(TEST_MERCHANT_KEY));
+    let response = send(&test_state(db), request, Body::empty()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Deprecation"], "true");
+    assert_eq!(response.headers()["Sunset"], LEGACY_API_SUNSET);
+    assert_eq!(response.headers()[header::LINK], "</api/v1/payments>; rel=\"successor-version\"");
+}
+
+#[sqlx::test]
+async fn responses_report_their_handling_time(db: PgPool) {
+    let state = test_state(db);
+    for key in [TEST_MERCHANT_KEY, "mk_wrong"] {
+        // Rejections from middleware are timed too
+        let response = send(&state, api_request("GET", "/api/v1/payments", Some(key)), Body::empty()).await;
+        let millis: f64 = response.headers()[RESPONSE_TIME_HEADER].to_str().unwrap().parse().unwrap();
+        assert!(millis >= 0.0);
+    }
+}
+
+#[sqlx::test]
+async fn subscriptions_bill_due_cycles_and_go_past_due_after_retries(db: PgPool) {
+    let state = test_state(db.clone());
+    let start_at = (Utc::now() - chrono::Duration::minutes(1)).trunc_subsecs(0);
+    let subscribe = |payment_token: &str| {
+        let body = serde_json::json!({
+            "amount": 999,
+            "currency": "usd",
+            "payment_token": payment_token,
+            "interval": "month",
+            "start_at": start_at.to_rfc3339(),
+        });
+        send(&state, api_request("POST", "/api/v1/subscriptions", Some(TEST_MERCHANT_KEY)), body.to_string())
+    };
+    let response = subscribe("tok_visa_4242").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let created = json_body(response).await;
+    assert_eq!(created["status"], "active");
+    assert!(created.get("payment_token").is_none());
+    assert_eq!(subscribe("4242424242424242").await.status(), StatusCode::BAD_REQUEST);
+
+    assert_eq!(bill_due_subscriptions(&state).await.unwrap(), 1);
+    let paid = sqlx::query!(
+        r#"
+        SELECT s.next_billing_at, s.failed_attempts, t.amount, t.status AS "status: TransactionStatus"
+        FROM subscriptions s JOIN transactions t ON t.subscription_id = s.id
+        "#
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(paid.next_billing_at, BillingInterval::Month.advance(start_at.with_timezone(&Utc)));
+    assert_eq!((paid.amount, paid.status, paid.failed_attempts), (999, TransactionStatus::Success, 0));
+
+    // A declining token is retried until max_retries (2) is exceeded
+    sqlx::query!("UPDATE subscriptions SET status = 'past_due'").execute(&db).await.unwrap();
+    subscribe("tok_decline_card").await;
+    for expected_attempts in 1..=3 {
+        assert_eq!(bill_due_subscriptions(&state).await.unwrap(), 1);
+        let row = sqlx::query!(
+            r#"SELECT failed_attempts, status AS "status: SubscriptionStatus", next_attempt_at FROM subscriptions WHERE status = 'active' OR failed_attempts > 0"#
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(row.failed_attempts, expected_attempts);
+        assert!(row.next_attempt_at > Utc::now());
+        let expected_status = if expected_attempts > 2 { SubscriptionStatus::PastDue } else { SubscriptionStatus::Active };
+        assert_eq!(row.status, expected_status);
+        sqlx::query!("UPDATE subscriptions SET next_attempt_at = NOW() - INTERVAL '1 minute'").execute(&db).await.unwrap();
+    }
+    assert_eq!(bill_due_subscriptions(&state).await.unwrap(), 0);
+    assert_eq!(transaction_count(&db).await, 4);
+
+    // Monthly cycles clamp to the end of shorter months
+    let jan_31 = DateTime::parse_from_rfc3339("2024-01-31T09:00:00Z").unwrap().with_timezone(&Utc);
+    assert_eq!(BillingInterval::Month.advance(jan_31).to_rfc3339(), "2024-02-29T09:00:00+00:00");
+}
+
+#[sqlx::test(migrations = false)]
+async fn startup_migrations_provision_a_fresh_database(db: PgPool) {
+    run_migrations(&db).await.unwrap();
+    // Already up to date: the second run applies nothing
+    run_migrations(&db).await.unwrap();
+
+    let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations WHERE success")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(applied as usize, MIGRATOR.iter().count());
+
+    let (status, body) = post_payment(&db, payment_body("4242424242424242", 1050)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+}
@@ -0,0 +1,315 @@
+// --- INPUT VALIDATION ---
+//
+// Pure checks on request fields: no database, no gateway, no clock beyond the current date.
+// Each check reports its failures as field errors, so POST /payment, POST /payment/validate and
+// the subscription endpoint aggregate every problem into the same 400.
+
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{Datelike, Utc};
+use rust_decimal::Decimal;
+
+use crate::{
+    mask_card_number, AppError, CardBrand, CardDetails, Currency, FieldError, Money, PaymentInstrument, PaymentRequest,
+    TOKEN_PENDING_MASK,
+};
+
+fn invalid<T>(field: &'static str, code: &'static str, message: impl Into<String>) -> Result<T, Vec<FieldError>> {
+    Err(vec![FieldError { field, code, message: message.into() }])
+}
+
+// Files an error from outside this module (e.g. a query parameter parser) under `field`
+pub fn field_error(field: &'static str, err: AppError) -> Vec<FieldError> {
+    match err {
+        AppError::BadRequest(code, message) => vec![FieldError { field, code, message }],
+        AppError::Validation(errors) => errors,
+        other => vec![FieldError { field, code: "INVALID", message: other.to_string() }],
+    }
+}
+
+// Collects failures across fields so a client sees every problem in one response
+#[derive(Default)]
+pub struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    // Records whatever a check reported; the value comes back only when it passed
+    pub fn check<T>(&mut self, result: Result<T, Vec<FieldError>>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(errors) => {
+                self.0.extend(errors);
+                None
+            }
+        }
+    }
+
+    // Ok(value) only when nothing failed; `value` is None exactly when a check did
+    pub fn finish<T>(self, value: Option<T>) -> Result<T, Vec<FieldError>> {
+        match value {
+            Some(value) if self.0.is_empty() => Ok(value),
+            _ => Err(self.0),
+        }
+    }
+}
+
+// Luhn (mod 10) checksum. Spaces and dashes are ignored, any other non-digit fails.
+pub fn luhn_valid(card_number: &str) -> bool {
+    let digits: Vec<u32> = match card_number
+        .chars()
+        .filter(|c| *c != ' ' && *c != '-')
+        .map(|c| c.to_digit(10))
+        .collect::<Option<Vec<u32>>>()
+    {
+        Some(digits) => digits,
+        None => return false,
+    };
+
+    if digits.is_empty() {
+        return false;
+    }
+
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| {
+            if i % 2 == 1 {
+                let doubled = d * 2;
+                if doubled > 9 { doubled - 9 } else { doubled }
+            } else {
+                d
+            }
+        })
+        .sum();
+
+    sum.is_multiple_of(10)
+}
+
+// Brand from standard BIN ranges; spaces and dashes are ignored
+pub fn detect_card_brand(card_number: &str) -> CardBrand {
+    let digits: String = card_number
+        .chars()
+        .filter(|c| *c != ' ' && *c != '-')
+        .collect();
+
+    let prefix = |len: usize| -> Option<u32> {
+        digits.get(..len).and_then(|p| p.parse().ok())
+    };
+
+    if digits.starts_with('4') {
+        return CardBrand::Visa;
+    }
+    if matches!(prefix(2), Some(34 | 37)) {
+        return CardBrand::Amex;
+    }
+    if matches!(prefix(2), Some(51..=55)) || matches!(prefix(4), Some(2221..=2720)) {
+        return CardBrand::Mastercard;
+    }
+    if matches!(prefix(4), Some(6011)) || matches!(prefix(2), Some(65)) {
+        return CardBrand::Discover;
+    }
+
+    CardBrand::Unknown
+}
+
+// A supported ISO 4217 code, normalized to uppercase by the caller
+pub fn validate_currency(field: &'static str, code: &str) -> Result<Currency, Vec<FieldError>> {
+    Currency::from_code(code).map_err(|err| field_error(field, err))
+}
+
+// Inclusive bounds for a single charge, in minor units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmountRange {
+    pub min: i64,
+    pub max: i64,
+}
+
+// Currency -> allowed amounts, with a default range for every currency without its own
+#[derive(Clone)]
+pub struct AmountLimits {
+    default: AmountRange,
+    by_currency: Arc<HashMap<&'static str, AmountRange>>,
+}
+
+impl AmountLimits {
+    pub fn new(default: AmountRange) -> Self {
+        AmountLimits { default, by_currency: Arc::new(HashMap::new()) }
+    }
+
+    pub fn with_currency(mut self, currency: &'static str, range: AmountRange) -> Self {
+        Arc::make_mut(&mut self.by_currency).insert(currency, range);
+        self
+    }
+
+    fn for_currency(&self, currency: Currency) -> AmountRange {
+        self.by_currency.get(currency.code()).copied().unwrap_or(self.default)
+    }
+}
+
+// Amount in minor units must be positive and within the currency's configured bounds
+pub fn validate_amount(money: Money, limits: &AmountLimits) -> Result<(), Vec<FieldError>> {
+    if money.amount() <= 0 {
+        return invalid("amount", "INVALID_AMOUNT", "Payment amount must be greater than zero.");
+    }
+
+    let range = limits.for_currency(money.currency());
+    if money.amount() < range.min {
+        return invalid("amount", "AMOUNT_TOO_SMALL", format!(
+            "Payment amount is below the minimum of {}.", Money::new(range.min, money.currency())
+        ));
+    }
+    if money.amount() > range.max {
+        return invalid("amount", "AMOUNT_TOO_LARGE", format!(
+            "Payment amount exceeds the maximum of {}.", Money::new(range.max, money.currency())
+        ));
+    }
+
+    Ok(())
+}
+
+// `amount_decimal` in the currency's major units; the integer `amount` must then be left out
+pub fn validate_amount_decimal(amount_decimal: &str, amount: i64, currency: Currency) -> Result<Money, Vec<FieldError>> {
+    if amount != 0 {
+        return invalid("amount_decimal", "INVALID_AMOUNT", "Send either amount or amount_decimal, not both.");
+    }
+    let major = match amount_decimal.trim().parse::<Decimal>() {
+        Ok(major) => major,
+        Err(_) => return invalid("amount_decimal", "INVALID_AMOUNT", "amount_decimal must be a decimal number like \"10.50\"."),
+    };
+
+    Money::from_major(major, currency).map_err(|err| field_error("amount_decimal", err))
+}
+
+// CVV must be all digits: 4 for Amex, 3 for every other brand
+pub fn validate_cvv(cvv: &str, card_brand: CardBrand) -> Result<(), Vec<FieldError>> {
+    let expected_len = if card_brand == CardBrand::Amex { 4 } else { 3 };
+
+    if cvv.len() != expected_len || !cvv.chars().all(|c| c.is_ascii_digit()) {
+        return invalid("cvv", "INVALID_CVV", "Invalid CVV for card type.");
+    }
+
+    Ok(())
+}
+
+// Full PAN checks; returns the detected brand for the caller
+pub fn validate_card(card: &CardDetails) -> Result<CardBrand, Vec<FieldError>> {
+    let mut errors = FieldErrors::default();
+
+    errors.check(validate_card_number(&card.card_number));
+    errors.check(validate_expiry(card.expiry_month, card.expiry_year));
+
+    let card_brand = detect_card_brand(&card.card_number);
+    errors.check(validate_cvv(&card.cvv, card_brand));
+
+    errors.finish(Some(card_brand))
+}
+
+pub fn validate_card_number(card_number: &str) -> Result<(), Vec<FieldError>> {
+    // Must run first: the checks below (and the masking) index by byte
+    if !card_number.chars().all(|c| c.is_ascii_digit() || c == ' ' || c == '-') {
+        return invalid("card_number", "INVALID_CARD", "Card number must contain only ASCII digits.");
+    }
+    // Bound the PAN itself, so separators can't pad a short number past the check
+    let digit_count = card_number.chars().filter(char::is_ascii_digit).count();
+    if !(12..=19).contains(&digit_count) {
+        return invalid("card_number", "INVALID_CARD", "Invalid card number.");
+    }
+    if !luhn_valid(card_number) {
+        return invalid("card_number", "INVALID_CARD", "Card number failed checksum validation.");
+    }
+
+    Ok(())
+}
+
+// Expiry check. Two-digit years are read as 20YY; a card is valid through the end of its expiry month.
+pub fn validate_expiry(expiry_month: i32, expiry_year: i32) -> Result<(), Vec<FieldError>> {
+    if !(1..=12).contains(&expiry_month) {
+        return invalid("expiry", "INVALID_EXPIRY", "Expiry month must be between 1 and 12.");
+    }
+
+    let year = match expiry_year {
+        0..=99 => 2000 + expiry_year,
+        1000..=9999 => expiry_year,
+        _ => return invalid("expiry", "INVALID_EXPIRY", "Invalid expiry year."),
+    };
+
+    let now = Utc::now();
+    if (year, expiry_month as u32) < (now.year(), now.month()) {
+        return invalid("expiry", "CARD_EXPIRED", "Card has expired.");
+    }
+
+    Ok(())
+}
+
+// Tokens are opaque, but must look like one so junk never reaches the gateway
+pub fn validate_payment_token(payment_token: &str) -> Result<(), Vec<FieldError>> {
+    let well_formed = payment_token.starts_with("tok_")
+        && payment_token.len() <= 255
+        && payment_token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !well_formed {
+        return invalid("payment_token", "INVALID_PAYMENT_TOKEN", "Invalid payment token.");
+    }
+
+    Ok(())
+}
+
+// Serialized size cap for `metadata`, so it can't be used as free bulk storage
+pub const MAX_METADATA_BYTES: usize = 4 * 1024;
+
+// Metadata is stored and returned as-is, but must be a JSON object of bounded size
+pub fn validate_metadata(metadata: &serde_json::Value) -> Result<(), Vec<FieldError>> {
+    if !metadata.is_object() {
+        return invalid("metadata", "INVALID_METADATA", "Metadata must be a JSON object.");
+    }
+    if metadata.to_string().len() > MAX_METADATA_BYTES {
+        return invalid("metadata", "METADATA_TOO_LARGE", format!(
+            "Metadata must not exceed {} bytes.", MAX_METADATA_BYTES
+        ));
+    }
+
+    Ok(())
+}
+
+// Every check POST /payment runs before touching the database, shared with POST /payment/validate.
+// Normalizes the currency codes and amount_decimal in place; returns the masked card and brand to record.
+pub fn validate_payment_request(
+    amount_limits: &AmountLimits,
+    payment_data: &mut PaymentRequest,
+) -> Result<(String, CardBrand), Vec<FieldError>> {
+    payment_data.currency = payment_data.currency.trim().to_uppercase();
+    payment_data.settlement_currency = payment_data.settlement_currency.take().map(|c| c.trim().to_uppercase());
+    let mut errors = FieldErrors::default();
+
+    // The amount's scale depends on the currency, so it is only checked against a supported one
+    if let Some(currency) = errors.check(validate_currency("currency", &payment_data.currency)) {
+        let money = match payment_data.amount_decimal.take() {
+            Some(amount_decimal) => errors.check(validate_amount_decimal(&amount_decimal, payment_data.amount, currency)),
+            None => Some(Money::new(payment_data.amount, currency)),
+        };
+        if let Some(money) = money {
+            payment_data.amount = money.amount();
+            errors.check(validate_amount(money, amount_limits));
+        }
+    }
+    if let Some(settlement_currency) = &payment_data.settlement_currency {
+        errors.check(validate_currency("settlement_currency", settlement_currency));
+    }
+    if let Some(metadata) = &payment_data.metadata {
+        errors.check(validate_metadata(metadata));
+    }
+
+    // Tokenized requests skip all PAN handling; the gateway reports the masked card later
+    let instrument = match &payment_data.instrument {
+        PaymentInstrument::Card(card) => errors
+            .check(validate_card(card))
+            .map(|card_brand| (mask_card_number(&card.card_number), card_brand)),
+        PaymentInstrument::Token { payment_token } => errors
+            .check(validate_payment_token(payment_token))
+            .map(|_| (TOKEN_PENDING_MASK.to_string(), CardBrand::Unknown)),
+        // Resolved to its stored card by `resolve_saved_payment_method`
+        PaymentInstrument::SavedMethod { .. } => Some((TOKEN_PENDING_MASK.to_string(), CardBrand::Unknown)),
+    };
+
+    errors.finish(instrument)
+}